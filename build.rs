@@ -0,0 +1,8 @@
+use std::env;
+
+// Triple this binary is built for, the default host of imported `rust-toolchain.toml` files
+fn main() {
+    let target = env::var("TARGET").expect("cargo sets TARGET for build scripts");
+    println!("cargo:rustc-env=HOST_TRIPLE={}", target);
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,143 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::{Compression, GzBuilder};
+use tar::{Builder, EntryType, Header};
+
+use crate::utils;
+
+// Fixed so two runs over the same inputs produce the same bytes, shared by every package format
+pub const COMPRESSION_LEVEL: u32 = 6;
+
+// Mtime stamped on every archive entry: `SOURCE_DATE_EPOCH` when set, the unix epoch otherwise
+pub fn archive_mtime() -> u64 {
+    if std::env::var_os("SOURCE_DATE_EPOCH").is_some() {
+        utils::source_date_epoch()
+    } else {
+        0
+    }
+}
+
+// A gzip stream with no timestamp, compressed at `COMPRESSION_LEVEL`
+pub fn reproducible_gzip<W: Write>(inner: W) -> GzEncoder<W> {
+    GzBuilder::new().mtime(0).write(inner, Compression::new(COMPRESSION_LEVEL))
+}
+
+// List every entry below `dir`, depth first, sorted by name inside each directory.
+// Paths are relative to `dir`.
+pub fn sorted_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    collect_sorted_entries(dir, Path::new(""), &mut entries)?;
+    Ok(entries)
+}
+
+fn collect_sorted_entries(root: &Path, rel_dir: &Path, entries: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut names: Vec<_> = fs::read_dir(root.join(rel_dir))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<_>>()?;
+    names.sort();
+
+    for name in names {
+        let rel_path = rel_dir.join(name);
+        let is_dir = fs::symlink_metadata(root.join(&rel_path))?.is_dir();
+        entries.push(rel_path.clone());
+        if is_dir {
+            collect_sorted_entries(root, &rel_path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+// Tar and gzip the content of `src_dir` into `dest` with sorted entries, root ownership,
+// a normalized mtime and permissions, and an empty gzip header.
+pub fn write_reproducible_tar_gz(src_dir: &Path, dest: &Path) -> Result<()> {
+    let file = File::create(dest).with_context(|| format!("failed to create archive {}", dest.display()))?;
+    let encoder = reproducible_gzip(file);
+    let mut builder = Builder::new(encoder);
+    append_reproducible_entries(&mut builder, src_dir, Path::new(""))?;
+
+    builder.into_inner()?.finish()?.sync_all()?;
+    Ok(())
+}
+
+// Append the content of `src_dir` below `prefix` with the same normalization as
+// `write_reproducible_tar_gz`, for callers that build their own archive.
+pub fn append_reproducible_entries<W: Write>(builder: &mut Builder<W>, src_dir: &Path, prefix: &Path) -> Result<()> {
+    let mtime = archive_mtime();
+
+    for rel_path in sorted_entries(src_dir)? {
+        let path = src_dir.join(&rel_path);
+        let archive_path = prefix.join(&rel_path);
+        let metadata = fs::symlink_metadata(&path)?;
+
+        let mut header = Header::new_gnu();
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(mtime);
+
+        if metadata.file_type().is_symlink() {
+            header.set_entry_type(EntryType::Symlink);
+            header.set_mode(0o777);
+            header.set_size(0);
+            builder.append_link(&mut header, &archive_path, fs::read_link(&path)?)?;
+        } else if metadata.is_dir() {
+            header.set_entry_type(EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            builder.append_data(&mut header, &archive_path, io::empty())?;
+        } else {
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(if is_executable(&metadata) { 0o755 } else { 0o644 });
+            header.set_size(metadata.len());
+            builder.append_data(&mut header, &archive_path, File::open(&path)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+pub fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reproducible_tar_gz() {
+        let root = std::env::temp_dir().join("rustup-distribution-test-archive");
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("bundle");
+        fs::create_dir_all(dir.join("b/nested")).unwrap();
+        fs::write(dir.join("components"), "rustc\n").unwrap();
+        fs::write(dir.join("b/nested/file"), "content").unwrap();
+        fs::write(dir.join("a"), "first").unwrap();
+
+        write_reproducible_tar_gz(&dir, &root.join("first.tar.gz")).unwrap();
+        // touching files must not change the archive
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        fs::write(dir.join("a"), "first").unwrap();
+        write_reproducible_tar_gz(&dir, &root.join("second.tar.gz")).unwrap();
+
+        let first = fs::read(root.join("first.tar.gz")).unwrap();
+        let second = fs::read(root.join("second.tar.gz")).unwrap();
+        assert_eq!(first, second);
+
+        let entries = sorted_entries(&dir).unwrap();
+        let entries: Vec<String> = entries.iter().map(|path| path.display().to_string().replace('\\', "/")).collect();
+        assert_eq!(entries, vec!["a", "b", "b/nested", "b/nested/file", "components"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
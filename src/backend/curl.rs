@@ -1,21 +1,25 @@
-use std::{cell::RefCell, time::Duration};
+use std::{cell::RefCell, fs, time::Duration};
 
 use url::Url;
-use curl::easy::Easy;
+use curl::easy::{Auth, Easy, List};
 
-use crate::Event;
+use crate::{utils, Credential, Event, NetworkConfig, NotFoundError};
 
-use anyhow::{Result, Context};
+use anyhow::{anyhow, Result, Context};
 
 const USER_AGENT: &str = concat!("rustup/", env!("CARGO_PKG_VERSION"));
 
 pub fn download(
     url: &Url, 
+    network: &NetworkConfig,
+    credential: Option<&Credential>,
     callback: &dyn Fn(Event<'_>) -> Result<()>,
 ) -> Result<()> {
     thread_local! {static EASY: RefCell<Easy> = RefCell::new(Easy::new())};
     EASY.with(|handle| {
         let mut handle = handle.borrow_mut();
+        // The handle is reused between downloads, drop options left by the previous one
+        handle.reset();
 
         handle.url(url.as_ref())?;
         handle.follow_location(true)?;
@@ -25,11 +29,30 @@ pub fn download(
 
         // Take the most 30s to connect
         handle.connect_timeout(Duration::new(30, 0))?;
-        
+
+        configure_network(&mut handle, url, network)?;
+        configure_auth(&mut handle, credential)?;
+
         {
             let cberr = RefCell::new(None);
             let mut transfer = handle.transfer();
 
+            // Listen for headers and parse out a `Content-Length` so we can report progress
+            transfer.header_function(|header| {
+                if let Ok(data) = std::str::from_utf8(header) {
+                    let prefix = "content-length: ";
+                    if data.to_ascii_lowercase().starts_with(prefix) {
+                        if let Ok(len) = data[prefix.len()..].trim().parse::<u64>() {
+                            if let Err(e) = callback(Event::DownloadContentLengthReceived(len)) {
+                                *cberr.borrow_mut() = Some(e);
+                                return false;
+                            }
+                        }
+                    }
+                }
+                true
+            })?;
+
             // Data callback for libcurl which is called with data that's
             // downloaded. We just feed it into our hasher and also write it out
             // to disk.
@@ -66,11 +89,63 @@ pub fn download(
         let code = handle.response_code()?;
         match code {
             0 | 200..=299 => {}
+            404 => return Err(NotFoundError { url: utils::redact_url(url) }.into()),
             _ => {
-                panic!("Failed to download file, http error code : {}", code);
+                return Err(anyhow!("failed to download file, http error code : {}", code));
             }
         }
 
         Ok(())
     })
-}
\ No newline at end of file
+}
+
+fn configure_network(handle: &mut Easy, url: &Url, network: &NetworkConfig) -> Result<()> {
+    match network.proxy_for(url) {
+        Some(proxy) => handle.proxy(proxy).context("invalid proxy setting")?,
+        // An empty proxy stops libcurl from reading the proxy environment variables itself
+        None => handle.proxy("")?,
+    }
+
+    if !network.ca_bundles.is_empty() {
+        // Setting a CA blob replaces the default bundle, so keep the system roots in front
+        let mut bundle = match openssl_probe::probe().cert_file {
+            Some(system_bundle) => fs::read(system_bundle).unwrap_or_default(),
+            None => Vec::new(),
+        };
+        bundle.push(b'\n');
+        bundle.extend(network.read_ca_bundles()?);
+        handle.ssl_cainfo_blob(&bundle).context("failed to load CA bundles")?;
+    }
+
+    if let Some(cert) = &network.client_cert {
+        handle.ssl_cert(cert).context("failed to load client certificate")?;
+        handle.ssl_cert_type("PEM")?;
+    }
+    if let Some(key) = &network.client_key {
+        handle.ssl_key(key).context("failed to load client key")?;
+        handle.ssl_key_type("PEM")?;
+    }
+
+    Ok(())
+}
+
+// libcurl only forwards these to the host of the original url, not across redirects
+fn configure_auth(handle: &mut Easy, credential: Option<&Credential>) -> Result<()> {
+    match credential {
+        Some(Credential::Basic { username, password }) => {
+            handle.username(username)?;
+            handle.password(password)?;
+            let mut auth = Auth::new();
+            auth.basic(true);
+            handle.http_auth(&auth)?;
+        }
+        Some(Credential::Bearer(token)) => {
+            let mut headers = List::new();
+            headers.append(&format!("Authorization: Bearer {}", token))?;
+            handle.http_headers(headers)?;
+        }
+        None => {}
+    }
+
+    Ok(())
+}
@@ -1,5 +1,122 @@
-use anyhow::Result;
+use std::{fs, io::Read, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::{Certificate, Identity, Proxy};
+use url::Url;
+
+use crate::{utils, Credential, Event, NetworkConfig, NotFoundError, TlsBackend};
+
+const USER_AGENT: &str = concat!("rustup/", env!("CARGO_PKG_VERSION"));
+
+pub fn download(
+    url: &Url,
+    network: &NetworkConfig,
+    credential: Option<&Credential>,
+    tls_backend: &TlsBackend,
+    callback: &dyn Fn(Event<'_>) -> Result<()>,
+) -> Result<()> {
+    let client = client(url, network, tls_backend)?;
+
+    // reqwest drops the authorization header when a redirect leaves the original host
+    let request = match credential {
+        Some(Credential::Basic { username, password }) => client.get(url.as_str()).basic_auth(username, Some(password)),
+        Some(Credential::Bearer(token)) => client.get(url.as_str()).bearer_auth(token),
+        None => client.get(url.as_str()),
+    };
+
+    let mut res = request
+        .send()
+        .context("error during download")?;
+
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(NotFoundError { url: utils::redact_url(url) }.into());
+    }
+    if !res.status().is_success() {
+        return Err(anyhow!(
+            "failed to download file, http error code : {}",
+            res.status().as_u16()
+        ));
+    }
+
+    if let Some(len) = res.content_length() {
+        callback(Event::DownloadContentLengthReceived(len))?;
+    }
+
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let bytes_read = res.read(&mut buffer).context("error reading response body")?;
+        if bytes_read == 0 {
+            break;
+        }
+        callback(Event::DownloadDataReceived(&buffer[..bytes_read]))?;
+    }
 
-pub fn download() -> Result<()> {
     Ok(())
-}
\ No newline at end of file
+}
+
+fn client(url: &Url, network: &NetworkConfig, tls_backend: &TlsBackend) -> Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(Duration::new(30, 0));
+
+    builder = select_tls(builder, tls_backend)?;
+
+    // Proxies are resolved by `NetworkConfig`, never by reqwest's own environment lookup
+    builder = match network.proxy_for(url) {
+        Some(proxy) => builder.proxy(Proxy::all(proxy).context("invalid proxy setting")?),
+        None => builder.no_proxy(),
+    };
+
+    if !network.ca_bundles.is_empty() {
+        let bundle = network.read_ca_bundles()?;
+        for cert in Certificate::from_pem_bundle(&bundle).context("failed to load CA bundles")? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if let Some(cert_path) = &network.client_cert {
+        let cert = fs::read(cert_path)
+            .with_context(|| format!("failed to read client certificate: {}", cert_path.display()))?;
+        let key = match &network.client_key {
+            Some(key_path) => fs::read(key_path)
+                .with_context(|| format!("failed to read client key: {}", key_path.display()))?,
+            // The key may be bundled with the certificate in a single PEM file
+            None => cert.clone(),
+        };
+        builder = builder.identity(identity(&cert, &key, tls_backend)?);
+    }
+
+    builder.build().context("failed to build http client")
+}
+
+fn select_tls(builder: ClientBuilder, tls_backend: &TlsBackend) -> Result<ClientBuilder> {
+    match tls_backend {
+        #[cfg(feature = "request-rustls-tls")]
+        TlsBackend::Rustls => Ok(builder.use_rustls_tls()),
+        #[cfg(not(feature = "request-rustls-tls"))]
+        TlsBackend::Rustls => Err(anyhow!("rustls backend is not compiled in, enable the `request-rustls-tls` feature")),
+        #[cfg(feature = "request-default-tls")]
+        TlsBackend::Default => Ok(builder),
+        #[cfg(not(feature = "request-default-tls"))]
+        TlsBackend::Default => Err(anyhow!("default tls backend is not compiled in, enable the `request-default-tls` feature")),
+    }
+}
+
+fn identity(cert: &[u8], key: &[u8], tls_backend: &TlsBackend) -> Result<Identity> {
+    let identity = match tls_backend {
+        #[cfg(feature = "request-rustls-tls")]
+        TlsBackend::Rustls => {
+            let mut pem = key.to_vec();
+            pem.push(b'\n');
+            pem.extend_from_slice(cert);
+            Identity::from_pem(&pem)
+        }
+        #[cfg(feature = "request-default-tls")]
+        TlsBackend::Default => Identity::from_pkcs8_pem(cert, key),
+        #[allow(unreachable_patterns)]
+        _ => return Err(anyhow!("client certificates are not supported by the selected tls backend")),
+    };
+
+    identity.context("failed to load client certificate")
+}
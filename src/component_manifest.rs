@@ -0,0 +1,47 @@
+use std::path::{Component, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+// One line of a component's `manifest.in`, the rust-installer list of what the component installs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ManifestEntry {
+    File(PathBuf),
+    Dir(PathBuf),
+}
+
+// Every entry of a `manifest.in`, blank lines skipped
+pub fn parse_component_manifest(content: &str) -> Result<Vec<ManifestEntry>> {
+    content.lines().map(str::trim).filter(|line| !line.is_empty()).map(parse_manifest_entry).collect()
+}
+
+// `file:bin/grcov` or `dir:share/doc/rust`. Paths must stay inside the prefix.
+pub fn parse_manifest_entry(line: &str) -> Result<ManifestEntry> {
+    let (kind, path) = line.split_once(':').with_context(|| format!("invalid manifest.in entry: {}", line))?;
+    let path = PathBuf::from(path);
+    if path.as_os_str().is_empty() || !path.components().all(|component| matches!(component, Component::Normal(_))) {
+        bail!("manifest.in entry escapes the prefix: {}", line);
+    }
+    match kind {
+        "file" => Ok(ManifestEntry::File(path)),
+        "dir" => Ok(ManifestEntry::Dir(path)),
+        _ => bail!("invalid manifest.in entry: {}", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest_entry() {
+        assert!(parse_manifest_entry("file:/etc/passwd").is_err());
+        assert!(parse_manifest_entry("file:../escape").is_err());
+        assert!(parse_manifest_entry("dir:").is_err());
+        assert!(parse_manifest_entry("link:bin/rustc").is_err());
+        assert_eq!(parse_manifest_entry("dir:share/doc/rust").unwrap(), ManifestEntry::Dir(PathBuf::from("share/doc/rust")));
+        assert_eq!(
+            parse_component_manifest("file:bin/rustc\n\ndir:share/doc/rust\n").unwrap(),
+            vec![ManifestEntry::File(PathBuf::from("bin/rustc")), ManifestEntry::Dir(PathBuf::from("share/doc/rust"))]
+        );
+    }
+}
@@ -1,44 +1,142 @@
-use std::{env, path::PathBuf, fs};
+use std::{collections::BTreeMap, env, fmt, path::{Path, PathBuf}, fs};
 
-use serde::Deserialize;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{utils, CommandSpec, ConfigOverrides, Credentials};
 
 // Deprecated
 pub static DEFAULT_RUSTUP_DIST_SERVER: &str = "https://static.rust-lang.org";
 pub static DEFAULT_RUSTUP_UPDATE_ROOT: &str = "https://static.rust-lang.org/rustup";
 
+// `date` value asking for the newest nightly providing every requested component
+pub static LATEST_AVAILABLE: &str = "latest-available";
+
 // Fully-resolved toolchain descriptors. These always have full target
 // triples attached to them and are used for canonical identification,
 // such as naming their installation directory.
 //
 // as strings they look like stable-x86_64-pc-windows-msvc or
 /// 1.55-x86_64-pc-windows-msvc
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, Debug)]
 pub struct TargetSelection {
     pub target: String,
     pub channel: String,
+    // YYYY-MM-DD, or `latest-available` for the newest date where every requested component exists
     pub date: Option<String>,
     // [possible values: minimal, default, complete]
     pub profile: Option<String>,
+    // components installed on top of the profile, eg: miri, rust-analyzer
+    #[serde(default)]
+    pub components: Vec<String>,
+    // additional targets to install the standard library for, eg: wasm32-unknown-unknown
+    #[serde(default)]
+    pub targets: Vec<String>,
+    // take the toolchain from the staged release candidates under `dist/staging`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub staging: bool,
+    // make it rustup's default toolchain on install
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub default: bool,
+    // date `toolchain.lock` resolved the selection to, downloads come from it while the
+    // toolchain keeps its configured name
+    #[serde(skip)]
+    pub locked_date: Option<String>,
 }
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct ExtendTool {
     pub name: String,
     pub version: Option<String>,
 }
 
-#[derive(Clone, Deserialize, Debug)]
+// Proxy, CA and client certificate settings shared by every download backend.
+#[derive(Clone, Default, Debug)]
+pub struct NetworkConfig {
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    // comma separated list of hosts that bypass the proxy, eg: localhost,.example.com
+    pub no_proxy: Option<String>,
+    // extra PEM bundles trusted on top of the system roots
+    pub ca_bundles: Vec<PathBuf>,
+    // PEM client certificate and private key used for mutual TLS
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    // per-host authentication for private mirrors, and the file it was read from
+    pub credentials: Credentials,
+    pub credentials_file: Option<PathBuf>,
+    // download with curl, as `RUSTUP_USE_CURL` does
+    pub use_curl: bool,
+}
+
+// RUSTUP_HOME and CARGO_HOME toolchains and tools are installed into, rustup's and cargo's
+// own defaults when unset
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct InstallHomes {
+    pub rustup_home: Option<PathBuf>,
+    pub cargo_home: Option<PathBuf>,
+}
+
+#[derive(Clone, Default, Deserialize, Debug)]
 pub struct YamlConfig {
+    // configuration files this one is laid over, relative to it
+    #[serde(rename = "INCLUDE", alias = "include", default)]
+    pub include: Vec<PathBuf>,
+    // values for `${VAR}` when the environment does not set VAR
+    #[serde(rename = "VARS", alias = "vars", default)]
+    pub vars: BTreeMap<String, String>,
     #[serde(rename = "RUSTUP_DIST_SERVER")]
     pub rustup_dist_server: Option<String>,
     #[serde(rename = "RUSTUP_UPDATE_ROOT")]
     pub rustup_update_root: Option<String>,
     // rust toolchains
-    #[serde(rename = "TARGETS")]
+    #[serde(rename = "TARGETS", default)]
     pub targets: Vec<TargetSelection>,
+    // rust-toolchain.toml files to import, relative to the configuration file
+    #[serde(rename = "RUST_TOOLCHAIN_FILES", default)]
+    pub rust_toolchain_files: Vec<PathBuf>,
+    // host triples the imported toolchains are packaged for, defaults to the current host
+    #[serde(rename = "HOSTS", default)]
+    pub hosts: Vec<String>,
     // extra rust tools
     #[serde(rename = "EXTEND_TOOLS")]
     pub extra_tools: Vec<ExtendTool>,
+    // toolchain rustup uses in each project directory, relative to the configuration file
+    #[serde(rename = "OVERRIDES", alias = "overrides", default)]
+    pub overrides: BTreeMap<PathBuf, String>,
+    // homes to install into instead of the user's, relative to the configuration file
+    #[serde(rename = "RUSTUP_HOME")]
+    pub rustup_home: Option<PathBuf>,
+    #[serde(rename = "CARGO_HOME")]
+    pub cargo_home: Option<PathBuf>,
+    // network settings
+    #[serde(rename = "HTTP_PROXY")]
+    pub http_proxy: Option<String>,
+    #[serde(rename = "HTTPS_PROXY")]
+    pub https_proxy: Option<String>,
+    #[serde(rename = "NO_PROXY")]
+    pub no_proxy: Option<String>,
+    #[serde(rename = "CA_BUNDLES", default)]
+    pub ca_bundles: Vec<PathBuf>,
+    #[serde(rename = "CLIENT_CERT")]
+    pub client_cert: Option<PathBuf>,
+    #[serde(rename = "CLIENT_KEY")]
+    pub client_key: Option<PathBuf>,
+    // yaml file with per-host tokens or username/password pairs
+    #[serde(rename = "CREDENTIALS_FILE")]
+    pub credentials_file: Option<PathBuf>,
+    // file each key was last set by, once includes are merged
+    #[serde(skip)]
+    pub sources: BTreeMap<String, PathBuf>,
+}
+
+// Where a resolved configuration value comes from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValueSource {
+    Default,
+    Env(String),
+    File(PathBuf),
+    Cli(String),
 }
 
 #[derive(Default, Clone, Debug)]
@@ -48,52 +146,716 @@ pub struct Config {
 
     pub target_selections: Vec<TargetSelection>,
 
+    // imported rust-toolchain.toml files and the hosts their toolchains are selected for
+    pub rust_toolchain_files: Vec<PathBuf>,
+    pub hosts: Vec<String>,
+
     pub extra_tools: Vec<ExtendTool>,
+
+    // project directory -> toolchain, set as rustup directory overrides on install
+    pub directory_overrides: BTreeMap<PathBuf, String>,
+
+    pub homes: InstallHomes,
+
+    pub network: NetworkConfig,
+
+    // source of each key, `TARGETS` and `EXTEND_TOOLS` entries being keyed by toolchain and tool name
+    pub sources: BTreeMap<String, ValueSource>,
+}
+
+impl InstallHomes {
+    pub fn is_isolated(&self) -> bool {
+        self.rustup_home.is_some() || self.cargo_home.is_some()
+    }
+
+    pub fn rustup_home(&self) -> Option<PathBuf> {
+        self.rustup_home.clone().or_else(utils::rustup_home)
+    }
+
+    pub fn cargo_home(&self) -> Option<PathBuf> {
+        self.cargo_home.clone().or_else(utils::cargo_home)
+    }
+
+    // Run `command` against these homes rather than the ones of this process
+    pub fn apply(&self, mut command: CommandSpec) -> CommandSpec {
+        if let Some(rustup_home) = &self.rustup_home {
+            command = command.env("RUSTUP_HOME", &rustup_home.to_string_lossy());
+        }
+        if let Some(cargo_home) = &self.cargo_home {
+            command = command.env("CARGO_HOME", &cargo_home.to_string_lossy());
+        }
+        command
+    }
 }
 
 impl Config {
-    pub fn parse(path: &PathBuf) -> Self {
+    pub fn parse(path: &Path) -> anyhow::Result<Self> {
+        Config::resolve(Some(path), &ConfigOverrides::default())
+    }
+
+    // Every value comes from, in order of precedence: the command line, the environment,
+    // the configuration file and its includes, and the defaults, proxies excepted. Without a configuration
+    // file, relative paths are resolved against the current directory.
+    pub fn resolve(path: Option<&Path>, overrides: &ConfigOverrides) -> anyhow::Result<Self> {
         let mut config = Config::default();
 
-        let yaml_config = YamlConfig::parse_yaml(&path);
+        let yaml_config = match path {
+            Some(path) => YamlConfig::load(path)?,
+            None => YamlConfig::default(),
+        };
+        let file_source = |key: &str| match yaml_config.sources.get(key) {
+            Some(file) => ValueSource::File(file.clone()),
+            None => ValueSource::Default,
+        };
+
+        let (rustup_dist_server, source) = first_set(
+            overrides.dist_server.clone().map(|value| (value, "--dist-server")),
+            &["RUSTUP_DIST_SERVER"],
+            yaml_config.rustup_dist_server.clone().map(|value| (value, file_source("RUSTUP_DIST_SERVER"))),
+        )
+        .unwrap_or_else(|| (String::from(DEFAULT_RUSTUP_DIST_SERVER), ValueSource::Default));
+        config.rustup_dist_server = rustup_dist_server;
+        config.sources.insert("RUSTUP_DIST_SERVER".to_string(), source);
+
+        let (rustup_update_root, source) = first_set(
+            overrides.update_root.clone().map(|value| (value, "--update-root")),
+            &["RUSTUP_UPDATE_ROOT"],
+            yaml_config.rustup_update_root.clone().map(|value| (value, file_source("RUSTUP_UPDATE_ROOT"))),
+        )
+        .unwrap_or_else(|| (String::from(DEFAULT_RUSTUP_UPDATE_ROOT), ValueSource::Default));
+        config.rustup_update_root = rustup_update_root;
+        config.sources.insert("RUSTUP_UPDATE_ROOT".to_string(), source);
+
+        // A dist server can be a mirrored dist tree on disk. Those from the configuration file
+        // are already relative to it, those from the command line or environment are relative
+        // to the current directory.
+        let current_dir = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        config.rustup_dist_server = utils::normalize_dist_server(&config.rustup_dist_server, &current_dir);
+        config.rustup_update_root = utils::normalize_dist_server(&config.rustup_update_root, &current_dir);
+
+        config.target_selections = yaml_config.targets.clone();
+        let hosts = if yaml_config.hosts.is_empty() { vec![host_triple().to_string()] } else { yaml_config.hosts.clone() };
+        for file in &yaml_config.rust_toolchain_files {
+            let toolchain = RustToolchainFile::parse(file).with_context(|| format!("failed to import {}", file.display()))?;
+            for selection in toolchain.target_selections(&hosts) {
+                config.sources.entry(target_key(&selection)).or_insert_with(|| ValueSource::File(file.clone()));
+                merge_target_selection(&mut config.target_selections, selection);
+            }
+        }
+        config.rust_toolchain_files = yaml_config.rust_toolchain_files.clone();
+        config.hosts = hosts;
+        for key in ["RUST_TOOLCHAIN_FILES", "HOSTS"] {
+            config.sources.insert(key.to_string(), file_source(key));
+        }
+        config.extra_tools = yaml_config.extra_tools.clone();
+        for (key, file) in &yaml_config.sources {
+            if key.starts_with("TARGETS ") || key.starts_with("EXTEND_TOOLS ") {
+                config.sources.insert(key.clone(), ValueSource::File(file.clone()));
+            }
+        }
+        config.apply_target_overrides(overrides);
+        config.apply_tool_overrides(overrides);
+        config.directory_overrides = yaml_config.overrides.clone();
+        for directory in config.directory_overrides.keys() {
+            config.sources.insert(override_key(directory), file_source(&override_key(directory)));
+        }
+
+        // Only the command line and the configuration file isolate an install, an ambient
+        // RUSTUP_HOME or CARGO_HOME is where rustup and cargo install by default anyway. Like
+        // dist servers, homes from the command line are relative to the current directory.
+        let mut home = |key: &str, flag: &str, cli: Option<&Path>, file: Option<&Path>| {
+            let resolved = first_set(
+                cli.map(|path| (path.to_string_lossy().into_owned(), flag)),
+                &[],
+                file.map(|path| (path.to_string_lossy().into_owned(), file_source(key))),
+            );
+            let (home, source) = match resolved {
+                Some((home, source)) => (Some(current_dir.join(home)), source),
+                None => (None, ValueSource::Default),
+            };
+            config.sources.insert(key.to_string(), source);
+            home
+        };
+        let rustup_home = home("RUSTUP_HOME", "--rustup-home", overrides.rustup_home.as_deref(), yaml_config.rustup_home.as_deref());
+        let cargo_home = home("CARGO_HOME", "--cargo-home", overrides.cargo_home.as_deref(), yaml_config.cargo_home.as_deref());
+        config.homes = InstallHomes { rustup_home, cargo_home };
+
+        // Proxy settings written in the configuration file win over the ambient environment, which
+        // only fills in the unset ones. Like curl, both upper and lower case variable names are
+        // accepted, and `ALL_PROXY` is the fallback for either scheme.
+        let mut setting = |key: &str, env_names: &[&str], file_value: Option<String>| {
+            let file = file_value.map(|value| (value, file_source(key)));
+            let (value, source) = match file.or_else(|| first_set(None, env_names, None)) {
+                Some((value, source)) => (Some(value), source),
+                None => (None, ValueSource::Default),
+            };
+            config.sources.insert(key.to_string(), source);
+            value
+        };
+        let http_proxy = setting("HTTP_PROXY", &["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"], yaml_config.http_proxy.clone());
+        let https_proxy = setting("HTTPS_PROXY", &["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"], yaml_config.https_proxy.clone());
+        let no_proxy = setting("NO_PROXY", &["NO_PROXY", "no_proxy"], yaml_config.no_proxy.clone());
+        for key in ["CA_BUNDLES", "CLIENT_CERT", "CLIENT_KEY", "CREDENTIALS_FILE"] {
+            config.sources.insert(key.to_string(), file_source(key));
+        }
+        config.network = NetworkConfig {
+            http_proxy,
+            https_proxy,
+            no_proxy,
+            ca_bundles: yaml_config.ca_bundles,
+            client_cert: yaml_config.client_cert,
+            client_key: yaml_config.client_key,
+            credentials: Default::default(),
+            credentials_file: yaml_config.credentials_file.clone(),
+            use_curl: false,
+        };
+
+        // A relative credentials file was resolved next to the configuration file that set it
+        config.network.credentials = Credentials::load(yaml_config.credentials_file.as_deref(), &config.rustup_dist_server)
+            .context("failed to load mirror credentials")?;
+
+        Ok(config)
+    }
+
+    // `--target`, `--channel` and `--date` replace the triple, channel and date of every
+    // configured toolchain, `--staging` takes every one of them from the staged release. Without any configured toolchain, they describe a single one,
+    // on the current host and the stable channel unless given.
+    fn apply_target_overrides(&mut self, overrides: &ConfigOverrides) {
+        let triples = cli_or_env_list(&overrides.targets, "--target", "RUSTUP_DISTRIBUTION_TARGETS");
+        let channel = first_set(overrides.channel.clone().map(|value| (value, "--channel")), &["RUSTUP_DISTRIBUTION_CHANNEL"], None);
+        let date = first_set(overrides.date.clone().map(|value| (value, "--date")), &["RUSTUP_DISTRIBUTION_DATE"], None);
+        let staging = first_set(overrides.staging.then(|| ("true".to_string(), "--staging")), &["RUSTUP_DISTRIBUTION_STAGING"], None)
+            .filter(|(value, _)| value != "0" && value != "false");
+        let Some(source) = [triples.as_ref().map(|(_, source)| source)]
+            .into_iter()
+            .chain([&channel, &date, &staging].map(|value| value.as_ref().map(|(_, source)| source)))
+            .flatten()
+            .next()
+            .cloned()
+        else {
+            return;
+        };
+
+        if self.target_selections.is_empty() {
+            self.target_selections.push(TargetSelection {
+                target: host_triple().to_string(),
+                channel: "stable".to_string(),
+                ..Default::default()
+            });
+        }
+
+        let mut selections = Vec::new();
+        for selection in std::mem::take(&mut self.target_selections) {
+            self.sources.remove(&target_key(&selection));
+            let targets = match &triples {
+                Some((triples, _)) => triples.clone(),
+                None => vec![selection.target.clone()],
+            };
+            for target in targets {
+                let mut selection = TargetSelection { target, ..selection.clone() };
+                if let Some((channel, _)) = &channel {
+                    selection.channel = channel.clone();
+                }
+                if let Some((date, _)) = &date {
+                    selection.date = Some(date.clone());
+                }
+                selection.staging |= staging.is_some();
+                self.sources.insert(target_key(&selection), source.clone());
+                merge_target_selection(&mut selections, selection);
+            }
+        }
+        self.target_selections = selections;
+    }
+
+    // `--tool name@version` replaces the configured version of `name`, or adds the tool
+    fn apply_tool_overrides(&mut self, overrides: &ConfigOverrides) {
+        let Some((tools, source)) = cli_or_env_list(&overrides.tools, "--tool", "RUSTUP_DISTRIBUTION_TOOLS") else {
+            return;
+        };
+        for tool in tools {
+            let tool = match tool.split_once('@') {
+                Some((name, version)) => ExtendTool { name: name.to_string(), version: Some(version.to_string()) },
+                None => ExtendTool { name: tool, version: None },
+            };
+            self.sources.insert(tool_key(&tool), source.clone());
+            match self.extra_tools.iter_mut().find(|existing| existing.name == tool.name) {
+                Some(existing) => *existing = tool,
+                None => self.extra_tools.push(tool),
+            }
+        }
+    }
+}
+
+// The value from the command line, else from the first non-empty variable of `env_names`,
+// else from the configuration file
+fn first_set(
+    cli: Option<(String, &str)>,
+    env_names: &[&str],
+    file: Option<(String, ValueSource)>,
+) -> Option<(String, ValueSource)> {
+    if let Some((value, flag)) = cli {
+        return Some((value, ValueSource::Cli(flag.to_string())));
+    }
+    env_names
+        .iter()
+        .filter_map(|name| env::var(name).ok().map(|value| (value, ValueSource::Env(name.to_string()))))
+        .find(|(value, _)| !value.is_empty())
+        .or(file)
+}
 
-        if let Some(rustup_dist_server) = yaml_config.rustup_dist_server {
-            config.rustup_dist_server = env::var("RUSTUP_DIST_SERVER").unwrap_or(rustup_dist_server);
+// A repeated flag, or else a comma separated environment variable
+fn cli_or_env_list(cli: &[String], flag: &str, env_name: &str) -> Option<(Vec<String>, ValueSource)> {
+    if !cli.is_empty() {
+        return Some((cli.to_vec(), ValueSource::Cli(flag.to_string())));
+    }
+    let (value, source) = first_set(None, &[env_name], None)?;
+    let items = value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect();
+    Some((items, source))
+}
+
+// Key of a target selection in `Config::sources`
+pub fn target_key(selection: &TargetSelection) -> String {
+    format!("TARGETS {}", selection.toolchain_name())
+}
+
+// Key of a directory override in `Config::sources`
+pub fn override_key(directory: &Path) -> String {
+    format!("OVERRIDES {}", directory.display())
+}
+
+// Key of an extra tool in `Config::sources`
+pub fn tool_key(tool: &ExtendTool) -> String {
+    format!("EXTEND_TOOLS {}", tool.name)
+}
+
+impl fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueSource::Default => write!(f, "default"),
+            ValueSource::Env(name) => write!(f, "env {}", name),
+            ValueSource::File(path) => write!(f, "{}", path.display()),
+            ValueSource::Cli(flag) => write!(f, "{}", flag),
+        }
+    }
+}
+
+impl NetworkConfig {
+    // Pick the proxy matching the scheme of `url`, unless the host is listed in `no_proxy`.
+    pub fn proxy_for(&self, url: &url::Url) -> Option<&str> {
+        let proxy = match url.scheme() {
+            "https" => self.https_proxy.as_deref(),
+            "http" => self.http_proxy.as_deref(),
+            _ => None,
+        }?;
+
+        let host = url.host_str()?;
+        let bypass = self.no_proxy.as_deref().unwrap_or_default().split(',').map(str::trim).any(|pattern| {
+            if pattern == "*" {
+                return true;
+            }
+            let domain = pattern.trim_start_matches('*').trim_start_matches('.');
+            !domain.is_empty() && (host == domain || host.ends_with(&format!(".{}", domain)))
+        });
+
+        if bypass {
+            None
         } else {
-            config.rustup_dist_server = String::from(DEFAULT_RUSTUP_DIST_SERVER);
+            Some(proxy)
+        }
+    }
+
+    // Concatenate every configured CA bundle into a single PEM blob.
+    pub fn read_ca_bundles(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bundle = Vec::new();
+        for path in &self.ca_bundles {
+            let pem = fs::read(path)
+                .with_context(|| format!("failed to read CA bundle: {}", path.display()))?;
+            bundle.extend_from_slice(&pem);
+            bundle.push(b'\n');
+        }
+        Ok(bundle)
+    }
+}
+
+impl YamlConfig {
+    // Load a configuration file by extension: `.toml` is TOML with the same keys as the yaml
+    // format, and a `rust-toolchain.toml` is imported for the current host. Included files are
+    // merged first and overlaid by the including file, `${VAR}` is read from the environment
+    // or the merged `VARS`, and relative paths are resolved against the file they appear in.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::load_nested(path, &mut Vec::new())
+    }
+
+    fn load_nested(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<Self> {
+        let path = utils::canonicalize_path(path).unwrap_or_else(|| path.to_path_buf());
+        if stack.contains(&path) {
+            anyhow::bail!("include cycle through {}", path.display());
+        }
+        if RustToolchainFile::is_toolchain_file(&path) {
+            let mut config = YamlConfig::from_rust_toolchain_files(vec![path.clone()]);
+            config.record_sources(&path);
+            return Ok(config);
         }
+        let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+
+        let content = fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        let mut value = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml_to_yaml(toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))?),
+            _ => serde_yaml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))?,
+        };
+        if value.is_null() {
+            value = serde_yaml::Value::Mapping(Default::default());
+        }
+
+        let own_vars: BTreeMap<String, String> = match value.get("VARS").or_else(|| value.get("vars")) {
+            Some(vars) => serde_yaml::from_value(vars.clone()).context("`VARS` must map names to strings")?,
+            None => BTreeMap::new(),
+        };
+        let includes: Vec<String> = match value.get("INCLUDE").or_else(|| value.get("include")) {
+            Some(include) => serde_yaml::from_value(include.clone()).context("`INCLUDE` must be a list of paths")?,
+            None => Vec::new(),
+        };
+
+        stack.push(path.clone());
+        let mut merged = YamlConfig::default();
+        for include in includes {
+            let include = interpolate(&include, &own_vars)?;
+            let included = Self::load_nested(&dir.join(include), stack)
+                .with_context(|| format!("included from {}", path.display()))?;
+            merged.overlay(included);
+        }
+        stack.pop();
+
+        let mut vars = merged.vars.clone();
+        vars.extend(own_vars);
+        interpolate_value(&mut value, &vars).with_context(|| format!("in {}", path.display()))?;
+        let mut config: YamlConfig =
+            serde_yaml::from_value(value).with_context(|| format!("failed to parse {}", path.display()))?;
+        config.resolve_paths(&dir);
+        config.record_sources(&path);
+        merged.overlay(config);
+
+        Ok(merged)
+    }
+
+    // A configuration made only of imported `rust-toolchain.toml` files, with the default
+    // servers and no extra tools
+    pub fn from_rust_toolchain_files(files: Vec<PathBuf>) -> Self {
+        YamlConfig { rust_toolchain_files: files, ..Default::default() }
+    }
+
+    // Lay `other` over this configuration: its settings win, its `TARGETS` replace the entries
+    // with the same target, channel, date and staging, its `EXTEND_TOOLS` the entries with the same name,
+    // and everything else it lists is appended.
+    pub fn overlay(&mut self, other: YamlConfig) {
+        self.rustup_dist_server = other.rustup_dist_server.or(self.rustup_dist_server.take());
+        self.rustup_update_root = other.rustup_update_root.or(self.rustup_update_root.take());
+        self.http_proxy = other.http_proxy.or(self.http_proxy.take());
+        self.https_proxy = other.https_proxy.or(self.https_proxy.take());
+        self.no_proxy = other.no_proxy.or(self.no_proxy.take());
+        self.client_cert = other.client_cert.or(self.client_cert.take());
+        self.client_key = other.client_key.or(self.client_key.take());
+        self.credentials_file = other.credentials_file.or(self.credentials_file.take());
+        self.rustup_home = other.rustup_home.or(self.rustup_home.take());
+        self.cargo_home = other.cargo_home.or(self.cargo_home.take());
+
+        for target in other.targets {
+            let existing = self
+                .targets
+                .iter_mut()
+                .find(|existing| {
+                    existing.target == target.target
+                        && existing.channel == target.channel
+                        && existing.date == target.date
+                        && existing.staging == target.staging
+                });
+            match existing {
+                Some(existing) => {
+                    self.sources.remove(&target_key(existing));
+                    *existing = target;
+                }
+                None => self.targets.push(target),
+            }
+        }
+        for tool in other.extra_tools {
+            match self.extra_tools.iter_mut().find(|existing| existing.name == tool.name) {
+                Some(existing) => *existing = tool,
+                None => self.extra_tools.push(tool),
+            }
+        }
+        for file in other.rust_toolchain_files {
+            if !self.rust_toolchain_files.contains(&file) {
+                self.rust_toolchain_files.push(file);
+            }
+        }
+        for bundle in other.ca_bundles {
+            if !self.ca_bundles.contains(&bundle) {
+                self.ca_bundles.push(bundle);
+            }
+        }
+        if !other.hosts.is_empty() {
+            self.hosts = other.hosts;
+        }
+        self.overrides.extend(other.overrides);
+
+        self.vars.extend(other.vars);
+        self.sources.extend(other.sources);
+    }
+
+    // Paths written relative to a configuration file are relative to its directory
+    fn resolve_paths(&mut self, dir: &Path) {
+        if let Some(server) = self.rustup_dist_server.as_mut() {
+            *server = utils::normalize_dist_server(server, dir);
+        }
+        if let Some(root) = self.rustup_update_root.as_mut() {
+            *root = utils::normalize_dist_server(root, dir);
+        }
+        if let Some(file) = self.credentials_file.as_mut() {
+            *file = dir.join(&*file);
+        }
+        for home in [self.rustup_home.as_mut(), self.cargo_home.as_mut()].into_iter().flatten() {
+            *home = dir.join(&*home);
+        }
+        for file in self.rust_toolchain_files.iter_mut() {
+            *file = dir.join(&*file);
+        }
+        // rustup matches overrides against canonical paths
+        self.overrides = std::mem::take(&mut self.overrides)
+            .into_iter()
+            .map(|(directory, toolchain)| {
+                let directory = dir.join(directory);
+                (utils::canonicalize_path(&directory).unwrap_or(directory), toolchain)
+            })
+            .collect();
+    }
 
-        if let Some(rustup_update_root) = yaml_config.rustup_update_root {
-            config.rustup_update_root = env::var("RUSTUP_UPDATE_ROOT").unwrap_or(rustup_update_root);
+    fn record_sources(&mut self, path: &Path) {
+        let set = [
+            ("RUSTUP_DIST_SERVER", self.rustup_dist_server.is_some()),
+            ("RUSTUP_UPDATE_ROOT", self.rustup_update_root.is_some()),
+            ("RUST_TOOLCHAIN_FILES", !self.rust_toolchain_files.is_empty()),
+            ("HOSTS", !self.hosts.is_empty()),
+            ("HTTP_PROXY", self.http_proxy.is_some()),
+            ("HTTPS_PROXY", self.https_proxy.is_some()),
+            ("NO_PROXY", self.no_proxy.is_some()),
+            ("CA_BUNDLES", !self.ca_bundles.is_empty()),
+            ("CLIENT_CERT", self.client_cert.is_some()),
+            ("CLIENT_KEY", self.client_key.is_some()),
+            ("CREDENTIALS_FILE", self.credentials_file.is_some()),
+            ("RUSTUP_HOME", self.rustup_home.is_some()),
+            ("CARGO_HOME", self.cargo_home.is_some()),
+        ];
+        for (key, _) in set.iter().filter(|(_, is_set)| *is_set) {
+            self.sources.insert(key.to_string(), path.to_path_buf());
+        }
+        for target in &self.targets {
+            self.sources.insert(target_key(target), path.to_path_buf());
+        }
+        for tool in &self.extra_tools {
+            self.sources.insert(tool_key(tool), path.to_path_buf());
+        }
+        for directory in self.overrides.keys() {
+            self.sources.insert(override_key(directory), path.to_path_buf());
+        }
+    }
+}
+
+// Replace every `${VAR}` of `text` by the environment variable VAR, or else `vars[VAR]`.
+// `$${` is a literal `${`.
+pub fn interpolate(text: &str, vars: &BTreeMap<String, String>) -> anyhow::Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = rest.strip_prefix("${") {
+            let end = reference
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("unterminated variable reference in {:?}", text))?;
+            let name = &reference[..end];
+            let value = env::var(name)
+                .ok()
+                .or_else(|| vars.get(name).cloned())
+                .ok_or_else(|| anyhow::anyhow!("undefined variable `{}`, set it in the environment or in VARS", name))?;
+            result.push_str(&value);
+            rest = &reference[end + 1..];
         } else {
-            config.rustup_update_root = String::from(DEFAULT_RUSTUP_UPDATE_ROOT);
+            result.push('$');
+            rest = &rest[1..];
         }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
 
-        config.target_selections = yaml_config.targets;
-        config.extra_tools = yaml_config.extra_tools;
+fn interpolate_value(value: &mut serde_yaml::Value, vars: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    match value {
+        serde_yaml::Value::String(text) => *text = interpolate(text, vars)?,
+        serde_yaml::Value::Sequence(items) => {
+            for item in items {
+                interpolate_value(item, vars)?;
+            }
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for (_, item) in mapping.iter_mut() {
+                interpolate_value(item, vars)?;
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => interpolate_value(&mut tagged.value, vars)?,
+        _ => {}
+    }
+    Ok(())
+}
 
-        config
+// TOML dates such as `date = 2024-01-01` are kept as the string they are written as
+fn toml_to_yaml(value: toml::Value) -> serde_yaml::Value {
+    match value {
+        toml::Value::String(text) => serde_yaml::Value::String(text),
+        toml::Value::Integer(number) => serde_yaml::Value::Number(number.into()),
+        toml::Value::Float(number) => serde_yaml::Value::Number(number.into()),
+        toml::Value::Boolean(flag) => serde_yaml::Value::Bool(flag),
+        toml::Value::Datetime(datetime) => serde_yaml::Value::String(datetime.to_string()),
+        toml::Value::Array(items) => serde_yaml::Value::Sequence(items.into_iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(table) => serde_yaml::Value::Mapping(
+            table
+                .into_iter()
+                .map(|(key, item)| (serde_yaml::Value::String(key), toml_to_yaml(item)))
+                .collect(),
+        ),
     }
 }
 
-impl YamlConfig {
-    // Deserialize yaml file data
-    pub fn parse_yaml(path: &PathBuf) -> Self {
-        let file_content = fs::read_to_string(&path).expect("Failed to read file content");
-        let yaml_config = serde_yaml::from_str(&file_content).expect("failed to parse yaml config");
-        
-        yaml_config
+// Triple this binary was built for
+pub fn host_triple() -> &'static str {
+    env!("HOST_TRIPLE")
+}
+
+// The `[toolchain]` table of a `rust-toolchain.toml`, or the channel of a legacy `rust-toolchain`
+#[derive(Clone, Default, Deserialize, Debug)]
+pub struct RustToolchainFile {
+    pub toolchain: RustToolchainSection,
+}
+
+#[derive(Clone, Default, Deserialize, Debug)]
+pub struct RustToolchainSection {
+    pub channel: Option<String>,
+    pub path: Option<PathBuf>,
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub components: Vec<String>,
+    #[serde(default)]
+    pub targets: Vec<String>,
+}
+
+impl RustToolchainFile {
+    pub fn is_toolchain_file(path: &Path) -> bool {
+        matches!(path.file_name().and_then(|name| name.to_str()), Some("rust-toolchain.toml" | "rust-toolchain"))
+    }
+
+    pub fn parse(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse_str(&content).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    // Like rustup, a legacy `rust-toolchain` file holding only a channel name is accepted too
+    pub fn parse_str(content: &str) -> anyhow::Result<Self> {
+        let trimmed = content.trim();
+        let file = if !trimmed.contains('\n') && !trimmed.contains('=') && !trimmed.is_empty() {
+            RustToolchainFile {
+                toolchain: RustToolchainSection { channel: Some(trimmed.to_string()), ..Default::default() },
+            }
+        } else {
+            toml::from_str(content)?
+        };
+
+        if file.toolchain.path.is_some() {
+            anyhow::bail!("custom toolchains set with `path` cannot be packaged");
+        }
+        if file.toolchain.channel.is_none() {
+            anyhow::bail!("missing `channel` in [toolchain]");
+        }
+        Ok(file)
+    }
+
+    // One selection per host, `nightly-2024-01-01` being split into its channel and date
+    pub fn target_selections(&self, hosts: &[String]) -> Vec<TargetSelection> {
+        let channel = self.toolchain.channel.as_deref().unwrap_or("stable");
+        let (channel, date) = split_channel_date(channel);
+        hosts
+            .iter()
+            .map(|host| TargetSelection {
+                target: host.clone(),
+                channel: channel.clone(),
+                date: date.clone(),
+                profile: self.toolchain.profile.clone(),
+                components: self.toolchain.components.clone(),
+                targets: self.toolchain.targets.clone(),
+                staging: false,
+                default: false,
+                locked_date: None,
+            })
+            .collect()
+    }
+}
+
+fn split_channel_date(channel: &str) -> (String, Option<String>) {
+    for name in ["nightly", "beta", "stable"] {
+        if let Some(date) = channel.strip_prefix(name).and_then(|rest| rest.strip_prefix('-')) {
+            if date.len() == 10 && date.chars().all(|c| c.is_ascii_digit() || c == '-') {
+                return (name.to_string(), Some(date.to_string()));
+            }
+        }
+    }
+    (channel.to_string(), None)
+}
+
+// Repositories pinning the same toolchain share a selection, with the union of their
+// components and targets
+fn merge_target_selection(selections: &mut Vec<TargetSelection>, selection: TargetSelection) {
+    let existing = selections.iter_mut().find(|existing| {
+        existing.target == selection.target
+            && existing.channel == selection.channel
+            && existing.date == selection.date
+            && existing.staging == selection.staging
+    });
+    let Some(existing) = existing else {
+        selections.push(selection);
+        return;
+    };
+    if existing.profile.is_none() {
+        existing.profile = selection.profile;
+    }
+    for component in selection.components {
+        if !existing.components.contains(&component) {
+            existing.components.push(component);
+        }
+    }
+    for target in selection.targets {
+        if !existing.targets.contains(&target) {
+            existing.targets.push(target);
+        }
     }
 }
 
 impl TargetSelection {
+    // Staged release candidates live in a dist tree of their own under `dist/staging`,
+    // laid out like the regular one so rustup can install from it too.
+    pub fn dist_server(&self, dist_server: &str) -> String {
+        if self.staging {
+            format!("{}/dist/staging", dist_server)
+        } else {
+            dist_server.to_string()
+        }
+    }
+
     pub fn mainifest_v1_url(&self, dist_root: &str) -> String {
-        let do_mainifest_staging = env::var("RUSTUP_STAGED_MANIFEST").is_ok();
-        match (self.date.as_ref(), do_mainifest_staging) {
-            (None, false) => format!("{}/channel-rust-{}", dist_root, self.channel),  
-            (Some(date), false) => format!("{}/{}/channel-rust-{}", dist_root, date, self.channel),
-            (Some(_), true) => format!("{}/staging/channel-rust-{}", dist_root, self.channel),
-            (None, true) => panic!("not a real-world case"),
+        let dist_root = if self.staging { format!("{}/staging/dist", dist_root) } else { dist_root.to_string() };
+        match self.dist_date() {
+            None => format!("{}/channel-rust-{}", dist_root, self.channel),
+            Some(date) => format!("{}/{}/channel-rust-{}", dist_root, date, self.channel),
         }
     }
 
@@ -101,17 +863,66 @@ impl TargetSelection {
         format!("{}.toml", self.mainifest_v1_url(dist_root))
     }
 
+    pub fn is_latest_available(&self) -> bool {
+        self.date.as_deref() == Some(LATEST_AVAILABLE)
+    }
+
+    // The dated directory downloads come from: the locked date, or else the configured one
+    pub fn dist_date(&self) -> Option<&String> {
+        self.locked_date.as_ref().or(self.date.as_ref())
+    }
+
+    // The configured name, `latest-available` standing for the date it was locked to and
+    // staged toolchains told apart from released ones, eg: stable-staging-x86_64-unknown-linux-gnu
     pub fn toolchain_name(&self) -> String {
-        match self.date.as_ref() {
+        match self.staging {
+            true => self.rustup_toolchain_name().replacen(&self.channel, &format!("{}-staging", self.channel), 1),
+            false => self.rustup_toolchain_name(),
+        }
+    }
+
+    // The name rustup installs the toolchain under, the same for a staged toolchain as for
+    // the released one
+    pub fn rustup_toolchain_name(&self) -> String {
+        let date = match self.is_latest_available() {
+            true => self.locked_date.as_ref().or(self.date.as_ref()),
+            false => self.date.as_ref(),
+        };
+        match date {
             Some(date) => format!("{}-{}-{}", self.channel, date, self.target),
             None => format!("{}-{}", self.channel, self.target),
         }
     }
 
-    pub fn package_dir(&self, dist_root: &String) -> String {
-        match self.date {
-            None => dist_root.to_string(),
-            Some(ref date) => format!("{dist_root}/dist/{date}"),
+    // Name of the system package carrying this toolchain, eg: rust-nightly-2024-01-01-x86-64-unknown-linux-gnu
+    pub fn package_name(&self) -> String {
+        let name: String = self
+            .toolchain_name()
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '+' { c } else { '-' })
+            .collect();
+        format!("rust-{}", name)
+    }
+
+    // Version of the system package carrying this toolchain, from its channel and date:
+    // 1.75.0, 1.75.0+20240101, or 0~nightly.20240101 sorting by date for named channels
+    pub fn package_version(&self) -> String {
+        let date = self.dist_date().map(|date| date.replace('-', ""));
+        let channel = self.channel.replace('-', "~");
+        match (self.channel.starts_with(|c: char| c.is_ascii_digit()), date) {
+            (true, Some(date)) => format!("{}+{}", channel, date),
+            (true, None) => channel,
+            (false, Some(date)) => format!("0~{}.{}", channel, date),
+            (false, None) => format!("0~{}", channel),
+        }
+    }
+
+    pub fn package_dir(&self, dist_root: &str) -> String {
+        let dist_root = self.dist_server(dist_root);
+        match self.dist_date() {
+            None => format!("{dist_root}/dist"),
+            Some(date) => format!("{dist_root}/dist/{date}"),
         }
     }
 }
@@ -120,7 +931,329 @@ impl ExtendTool {
     pub fn crate_name(&self) -> String {
         match self.version.as_ref() {
             Some(version) => format!("{}@{}", self.name, version),
-            None => format!("{}", self.name),
+            None => self.name.to_string(),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_for_scheme() {
+        let network = NetworkConfig {
+            http_proxy: Some("http://proxy:3128".to_string()),
+            https_proxy: Some("http://secure-proxy:3128".to_string()),
+            ..Default::default()
+        };
+        let https = url::Url::parse("https://static.rust-lang.org/dist").unwrap();
+        let http = url::Url::parse("http://static.rust-lang.org/dist").unwrap();
+        let file = url::Url::parse("file:///mirror/dist").unwrap();
+
+        assert_eq!(network.proxy_for(&https), Some("http://secure-proxy:3128"));
+        assert_eq!(network.proxy_for(&http), Some("http://proxy:3128"));
+        assert_eq!(network.proxy_for(&file), None);
+    }
+
+    #[test]
+    fn test_proxy_for_no_proxy() {
+        let network = NetworkConfig {
+            https_proxy: Some("http://proxy:3128".to_string()),
+            no_proxy: Some("localhost, .corp.example.com".to_string()),
+            ..Default::default()
+        };
+        let internal = url::Url::parse("https://mirror.corp.example.com/rustup").unwrap();
+        let local = url::Url::parse("https://localhost/rustup").unwrap();
+        let external = url::Url::parse("https://static.rust-lang.org").unwrap();
+
+        assert_eq!(network.proxy_for(&internal), None);
+        assert_eq!(network.proxy_for(&local), None);
+        assert_eq!(network.proxy_for(&external), Some("http://proxy:3128"));
+    }
+
+    #[test]
+    fn test_parse_rust_toolchain_file() {
+        let file = RustToolchainFile::parse_str(
+            "[toolchain]\nchannel = \"nightly-2024-01-01\"\ncomponents = [\"miri\"]\ntargets = [\"wasm32-unknown-unknown\"]\nprofile = \"minimal\"\n",
+        )
+        .unwrap();
+        let selections = file.target_selections(&["x86_64-unknown-linux-gnu".to_string(), "aarch64-apple-darwin".to_string()]);
+        assert_eq!(selections.len(), 2);
+        assert_eq!(selections[1].target, "aarch64-apple-darwin");
+        assert_eq!(selections[0].channel, "nightly");
+        assert_eq!(selections[0].date.as_deref(), Some("2024-01-01"));
+        assert_eq!(selections[0].profile.as_deref(), Some("minimal"));
+        assert_eq!(selections[0].components, ["miri"]);
+        assert_eq!(selections[0].targets, ["wasm32-unknown-unknown"]);
+
+        let legacy = RustToolchainFile::parse_str("1.75.0\n").unwrap();
+        let selections = legacy.target_selections(&["x86_64-pc-windows-msvc".to_string()]);
+        assert_eq!(selections[0].channel, "1.75.0");
+        assert_eq!(selections[0].date, None);
+
+        assert!(RustToolchainFile::parse_str("[toolchain]\npath = \"/opt/rust\"\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_toml_config() {
+        let dir = env::temp_dir().join("rustup-distribution-test-toml-config");
+        fs::create_dir_all(dir.join("repo-a")).unwrap();
+        fs::create_dir_all(dir.join("repo-b")).unwrap();
+        fs::write(
+            dir.join("repo-a/rust-toolchain.toml"),
+            "[toolchain]\nchannel = \"nightly-2024-01-01\"\ncomponents = [\"miri\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("repo-b/rust-toolchain.toml"),
+            "[toolchain]\nchannel = \"nightly-2024-01-01\"\ncomponents = [\"rust-analyzer\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("toolchain.toml"),
+            r#"
+RUSTUP_DIST_SERVER = "https://mirror.example.com"
+RUST_TOOLCHAIN_FILES = ["repo-a/rust-toolchain.toml", "repo-b/rust-toolchain.toml"]
+HOSTS = ["x86_64-unknown-linux-gnu"]
+
+[[TARGETS]]
+target = "x86_64-pc-windows-msvc"
+channel = "stable"
+
+[[EXTEND_TOOLS]]
+name = "grcov"
+version = "0.8.18"
+"#,
+        )
+        .unwrap();
+
+        let config = Config::parse(&dir.join("toolchain.toml")).unwrap();
+        assert_eq!(config.extra_tools[0].crate_name(), "grcov@0.8.18");
+        assert_eq!(config.target_selections.len(), 2);
+        assert_eq!(config.target_selections[0].toolchain_name(), "stable-x86_64-pc-windows-msvc");
+        assert_eq!(config.target_selections[1].toolchain_name(), "nightly-2024-01-01-x86_64-unknown-linux-gnu");
+        assert_eq!(config.target_selections[1].components, ["miri", "rust-analyzer"]);
+        assert_eq!(config.target_selections[0].package_name(), "rust-stable-x86-64-pc-windows-msvc");
+        assert_eq!(config.target_selections[0].package_version(), "0~stable");
+        assert_eq!(config.target_selections[1].package_version(), "0~nightly.20240101");
+
+        let config = Config::parse(&dir.join("repo-a/rust-toolchain.toml")).unwrap();
+        assert_eq!(config.rustup_dist_server, DEFAULT_RUSTUP_DIST_SERVER);
+        assert_eq!(config.target_selections.len(), 1);
+        assert_eq!(config.target_selections[0].target, host_triple());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let vars = BTreeMap::from([("MIRROR".to_string(), "https://mirror.example.com".to_string())]);
+        assert_eq!(interpolate("${MIRROR}/rustup", &vars).unwrap(), "https://mirror.example.com/rustup");
+        assert_eq!(interpolate("$${MIRROR} costs $5", &vars).unwrap(), "${MIRROR} costs $5");
+        assert!(interpolate("${RUSTUP_DISTRIBUTION_TEST_UNDEFINED}", &vars).is_err());
+        assert!(interpolate("${MIRROR", &vars).is_err());
+    }
+
+    #[test]
+    fn test_load_includes() {
+        let dir = env::temp_dir().join("rustup-distribution-test-includes");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("base")).unwrap();
+        fs::create_dir_all(dir.join("team")).unwrap();
+        fs::write(
+            dir.join("base/toolchain.yaml"),
+            r#"
+VARS:
+  MIRROR: https://mirror.example.com
+  CHANNEL: stable
+RUSTUP_DIST_SERVER: ${MIRROR}
+RUSTUP_UPDATE_ROOT: ${MIRROR}/rustup
+TARGETS:
+  - target: x86_64-unknown-linux-gnu
+    channel: ${CHANNEL}
+  - target: x86_64-unknown-linux-gnu
+    channel: nightly
+    date: 2024-01-01
+EXTEND_TOOLS:
+  - name: grcov
+    version: 0.8.18
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("team/toolchain.toml"),
+            r#"
+INCLUDE = ["../base/toolchain.yaml"]
+RUSTUP_UPDATE_ROOT = "${MIRROR}/team-rustup"
+
+[VARS]
+CHANNEL = "beta"
+
+[[TARGETS]]
+target = "x86_64-unknown-linux-gnu"
+channel = "nightly"
+date = 2024-02-01
+components = ["miri"]
+
+[[EXTEND_TOOLS]]
+name = "grcov"
+version = "0.8.19"
+
+[[EXTEND_TOOLS]]
+name = "cargo-nextest"
+"#,
+        )
+        .unwrap();
+
+        let base = dir.join("base/toolchain.yaml").canonicalize().unwrap();
+        let team = dir.join("team/toolchain.toml").canonicalize().unwrap();
+        let config = Config::parse(&team).unwrap();
+        assert_eq!(config.rustup_dist_server, "https://mirror.example.com");
+        assert_eq!(config.rustup_update_root, "https://mirror.example.com/team-rustup");
+        // variables are interpolated per file, the including file's VARS do not leak into includes
+        let names: Vec<String> = config.target_selections.iter().map(TargetSelection::toolchain_name).collect();
+        assert_eq!(
+            names,
+            [
+                "stable-x86_64-unknown-linux-gnu",
+                "nightly-2024-01-01-x86_64-unknown-linux-gnu",
+                "nightly-2024-02-01-x86_64-unknown-linux-gnu"
+            ]
+        );
+        assert_eq!(config.target_selections[2].components, ["miri"]);
+        let tools: Vec<String> = config.extra_tools.iter().map(ExtendTool::crate_name).collect();
+        assert_eq!(tools, ["grcov@0.8.19", "cargo-nextest"]);
+
+        assert_eq!(config.sources["RUSTUP_DIST_SERVER"], ValueSource::File(base.clone()));
+        assert_eq!(config.sources["RUSTUP_UPDATE_ROOT"], ValueSource::File(team.clone()));
+        assert_eq!(config.sources["TARGETS stable-x86_64-unknown-linux-gnu"], ValueSource::File(base.clone()));
+        assert_eq!(config.sources["TARGETS nightly-2024-01-01-x86_64-unknown-linux-gnu"], ValueSource::File(base));
+        assert_eq!(config.sources["TARGETS nightly-2024-02-01-x86_64-unknown-linux-gnu"], ValueSource::File(team.clone()));
+        assert_eq!(config.sources["EXTEND_TOOLS grcov"], ValueSource::File(team));
+        assert_eq!(config.sources["CLIENT_CERT"], ValueSource::Default);
+
+        fs::write(dir.join("base/toolchain.yaml"), "INCLUDE: [../team/toolchain.toml]\n").unwrap();
+        let err = YamlConfig::load(&dir.join("team/toolchain.toml")).unwrap_err();
+        assert!(format!("{:?}", err).contains("include cycle"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_first_set_precedence() {
+        let name = "RUSTUP_DISTRIBUTION_TEST_FIRST_SET";
+        let file = Some(("file".to_string(), ValueSource::File(PathBuf::from("/toolchain.yaml"))));
+        env::set_var(name, "env");
+        assert_eq!(first_set(Some(("cli".to_string(), "--flag")), &[name], file.clone()).unwrap().1, ValueSource::Cli("--flag".to_string()));
+        assert_eq!(first_set(None, &[name], file.clone()).unwrap(), ("env".to_string(), ValueSource::Env(name.to_string())));
+        env::set_var(name, "");
+        assert_eq!(first_set(None, &[name], file.clone()).unwrap().0, "file");
+        env::remove_var(name);
+        assert_eq!(first_set(None, &[name], None), None);
+    }
+
+    #[test]
+    fn test_resolve_overrides() {
+        let dir = env::temp_dir().join("rustup-distribution-test-overrides");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("toolchain.yaml"),
+            r#"
+RUSTUP_DIST_SERVER: https://mirror.example.com
+TARGETS:
+  - target: x86_64-unknown-linux-gnu
+    channel: nightly
+    profile: minimal
+EXTEND_TOOLS:
+  - name: grcov
+    version: 0.8.18
+CARGO_HOME: sandbox/cargo
+"#,
+        )
+        .unwrap();
+        let yaml_config = YamlConfig::load(&dir.join("toolchain.yaml")).unwrap();
+        assert_eq!(yaml_config.cargo_home, Some(dir.join("sandbox/cargo")));
+
+        let overrides = ConfigOverrides {
+            dist_server: Some("https://cli.example.com".to_string()),
+            rustup_home: Some(PathBuf::from("/opt/sandbox/rustup")),
+            targets: vec!["x86_64-unknown-linux-gnu".to_string(), "aarch64-unknown-linux-gnu".to_string()],
+            date: Some("2024-01-01".to_string()),
+            tools: vec!["grcov@0.8.19".to_string(), "cargo-nextest".to_string()],
+            ..Default::default()
+        };
+        let config = Config::resolve(Some(&dir.join("toolchain.yaml")), &overrides).unwrap();
+        assert_eq!(config.rustup_dist_server, "https://cli.example.com");
+        assert_eq!(config.sources["RUSTUP_DIST_SERVER"], ValueSource::Cli("--dist-server".to_string()));
+        let names: Vec<String> = config.target_selections.iter().map(TargetSelection::toolchain_name).collect();
+        assert_eq!(names, ["nightly-2024-01-01-x86_64-unknown-linux-gnu", "nightly-2024-01-01-aarch64-unknown-linux-gnu"]);
+        assert_eq!(config.target_selections[1].profile.as_deref(), Some("minimal"));
+        assert_eq!(config.sources["TARGETS nightly-2024-01-01-aarch64-unknown-linux-gnu"], ValueSource::Cli("--target".to_string()));
+        let tools: Vec<String> = config.extra_tools.iter().map(ExtendTool::crate_name).collect();
+        assert_eq!(tools, ["grcov@0.8.19", "cargo-nextest"]);
+        assert_eq!(config.homes.rustup_home, Some(PathBuf::from("/opt/sandbox/rustup")));
+        assert_eq!(config.sources["RUSTUP_HOME"], ValueSource::Cli("--rustup-home".to_string()));
+        // the environment does not isolate an install, the configuration file does
+        assert_eq!(config.homes.cargo_home, Some(dir.join("sandbox/cargo")));
+
+        // without a configuration file
+        let overrides = ConfigOverrides { channel: Some("1.75.0".to_string()), ..Default::default() };
+        let config = Config::resolve(None, &overrides).unwrap();
+        assert_eq!(config.target_selections.len(), 1);
+        assert_eq!(config.target_selections[0].toolchain_name(), format!("1.75.0-{}", host_triple()));
+        assert_eq!(config.target_selections[0].package_version(), "1.75.0");
+        assert!(config.extra_tools.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_staging_urls() {
+        let mut selection = TargetSelection {
+            target: "x86_64-unknown-linux-gnu".to_string(),
+            channel: "stable".to_string(),
+            staging: true,
+            ..Default::default()
+        };
+        let server = "https://static.rust-lang.org".to_string();
+        assert_eq!(
+            selection.mainifest_v2_url(&format!("{}/dist", server)),
+            "https://static.rust-lang.org/dist/staging/dist/channel-rust-stable.toml"
+        );
+        assert_eq!(selection.dist_server(&server), "https://static.rust-lang.org/dist/staging");
+        assert_eq!(selection.toolchain_name(), "stable-staging-x86_64-unknown-linux-gnu");
+        assert_eq!(selection.rustup_toolchain_name(), "stable-x86_64-unknown-linux-gnu");
+        assert_eq!(selection.package_name(), "rust-stable-staging-x86-64-unknown-linux-gnu");
+
+        selection.date = Some("2024-03-21".to_string());
+        assert_eq!(
+            selection.mainifest_v2_url(&format!("{}/dist", server)),
+            "https://static.rust-lang.org/dist/staging/dist/2024-03-21/channel-rust-stable.toml"
+        );
+        assert_eq!(selection.package_dir(&server), "https://static.rust-lang.org/dist/staging/dist/2024-03-21");
+        assert_eq!(selection.toolchain_name(), "stable-staging-2024-03-21-x86_64-unknown-linux-gnu");
+        let staged_key = target_key(&selection);
+
+        selection.staging = false;
+        assert_eq!(selection.package_dir(&server), "https://static.rust-lang.org/dist/2024-03-21");
+        assert_eq!(selection.toolchain_name(), "stable-2024-03-21-x86_64-unknown-linux-gnu");
+        assert_ne!(target_key(&selection), staged_key);
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let dir = env::temp_dir().join("rustup-distribution-test-resolve-errors");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("toolchain.yaml"), "EXTEND_TOOLS: []\nRUST_TOOLCHAIN_FILES: [rust-toolchain.toml]\n").unwrap();
+        fs::write(dir.join("rust-toolchain.toml"), "[toolchain]\npath = \"/opt/rust\"\n").unwrap();
+        let err = Config::parse(&dir.join("toolchain.yaml")).unwrap_err();
+        assert!(format!("{:?}", err).contains("cannot be packaged"));
+
+        fs::write(dir.join("broken.yaml"), "targets: {").unwrap();
+        assert!(Config::parse(&dir.join("broken.yaml")).is_err());
+        assert!(Config::parse(&dir.join("missing.yaml")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use md5::{Digest, Md5};
+use tar::{Builder, EntryType, Header};
+
+use crate::{archive, installer, utils, BundleMetadata};
+
+// Used for the Maintainer field when DEBEMAIL is not set
+const DEFAULT_MAINTAINER_EMAIL: &str = concat!(env!("CARGO_PKG_NAME"), "@localhost");
+
+// Debian architecture of a target triple, eg: x86_64-unknown-linux-gnu -> amd64
+pub fn debian_architecture(target: &str) -> Option<&'static str> {
+    installer::package_architectures(target).map(|(debian, _)| debian)
+}
+
+// Write the staged bundle `bundle_dir` as a Debian package installing it into
+// /opt/rust/<toolchain>, without dpkg-deb
+pub fn write_deb(bundle_dir: &Path, dest: &Path, metadata: &BundleMetadata) -> Result<()> {
+    let target = &metadata.config.target;
+    let Some(architecture) = debian_architecture(&target.target) else {
+        bail!("no Debian architecture for target {}", target.target);
+    };
+
+    let data = dest.with_extension("data.tar.gz");
+    let root = installer::stage_package_root(bundle_dir, dest, metadata)?;
+
+    let mut md5sums = String::new();
+    let mut installed_size = 0;
+    for rel_path in archive::sorted_entries(&root)? {
+        let path = root.join(&rel_path);
+        let file_metadata = fs::symlink_metadata(&path)?;
+        if file_metadata.is_file() {
+            let mut hasher = Md5::new();
+            io::copy(&mut File::open(&path)?, &mut hasher)?;
+            md5sums.push_str(&format!("{}  {}\n", utils::to_hex(&hasher.finalize()), rel_path.display()));
+            installed_size += file_metadata.len();
+        }
+    }
+
+    let file = File::create(&data).with_context(|| format!("failed to create {}", data.display()))?;
+    let mut builder = Builder::new(archive::reproducible_gzip(file));
+    archive::append_reproducible_entries(&mut builder, &root, Path::new("."))?;
+    builder.into_inner()?.finish()?.sync_all()?;
+    fs::remove_dir_all(&root)?;
+
+    let maintainer = maintainer(env::var("DEBFULLNAME").ok(), env::var("DEBEMAIL").ok());
+    let control = control_file(metadata, architecture, &maintainer, installed_size.div_ceil(1024));
+    let control_tar = control_tar_gz(&[
+        ("control", 0o644, control.as_bytes()),
+        ("md5sums", 0o644, md5sums.as_bytes()),
+        ("postinst", 0o755, installer::rustup_link_script(metadata).as_bytes()),
+        ("prerm", 0o755, installer::rustup_unlink_script(metadata).as_bytes()),
+    ])?;
+
+    let mut ar = ArWriter::new(File::create(dest).with_context(|| format!("failed to create {}", dest.display()))?)?;
+    ar.append("debian-binary", 4, &mut "2.0\n".as_bytes())?;
+    ar.append("control.tar.gz", control_tar.len() as u64, &mut control_tar.as_slice())?;
+    ar.append("data.tar.gz", fs::metadata(&data)?.len(), &mut File::open(&data)?)?;
+    ar.into_inner().sync_all()?;
+    fs::remove_file(&data)?;
+
+    Ok(())
+}
+
+// The `Name <email>` Debian requires, from DEBFULLNAME and DEBEMAIL as the Debian tools take
+// them. DEBEMAIL may hold both, eg: Jane Doe <jane@example.org>
+fn maintainer(name: Option<String>, email: Option<String>) -> String {
+    let email = email.unwrap_or_default();
+    let (email_name, address) = match email.split_once('<') {
+        Some((email_name, address)) => (email_name.trim(), address.trim().trim_end_matches('>').trim()),
+        None => ("", email.trim()),
+    };
+    let name = name.as_deref().map(str::trim).filter(|name| !name.is_empty());
+    let name = name.or(Some(email_name).filter(|name| !name.is_empty())).unwrap_or(env!("CARGO_PKG_NAME"));
+    let address = if address.is_empty() { DEFAULT_MAINTAINER_EMAIL } else { address };
+    format!("{} <{}>", name, address)
+}
+
+fn control_file(metadata: &BundleMetadata, architecture: &str, maintainer: &str, installed_size: u64) -> String {
+    let target = &metadata.config.target;
+    let mut description = format!("Rust {} toolchain\n", metadata.toolchain);
+    description.push_str(&format!(" Installs {} into {}", metadata.components.join(", "), installer::package_prefix(metadata).display()));
+    description.push_str(&format!(" and registers it with rustup when available. Packaged by {}.\n", metadata.generator));
+    format!(
+        "Package: {}\nVersion: {}\nArchitecture: {}\nMaintainer: {}\nInstalled-Size: {}\nSection: devel\nPriority: optional\nDescription: {}",
+        target.package_name(),
+        target.package_version(),
+        architecture,
+        maintainer,
+        installed_size,
+        description
+    )
+}
+
+fn control_tar_gz(files: &[(&str, u32, &[u8])]) -> Result<Vec<u8>> {
+    let encoder = archive::reproducible_gzip(Vec::new());
+    let mut builder = Builder::new(encoder);
+    for (name, mode, content) in files {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(archive::archive_mtime());
+        header.set_mode(*mode);
+        header.set_size(content.len() as u64);
+        builder.append_data(&mut header, Path::new(".").join(name), *content)?;
+    }
+    Ok(builder.into_inner()?.finish()?)
+}
+
+// The common `ar` format a .deb is made of, with normalized member headers
+struct ArWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ArWriter<W> {
+    fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(b"!<arch>\n")?;
+        Ok(ArWriter { inner })
+    }
+
+    fn append(&mut self, name: &str, size: u64, content: &mut dyn Read) -> io::Result<()> {
+        let header = format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, archive::archive_mtime(), 0, 0, "100644", size);
+        self.inner.write_all(header.as_bytes())?;
+        let copied = io::copy(&mut content.take(size), &mut self.inner)?;
+        if copied != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} is shorter than {} bytes", name, size)));
+        }
+        // members start on even offsets
+        if size % 2 == 1 {
+            self.inner.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use crate::test_utils::{bundle_metadata, linux_target, temp_dir, write_files};
+
+    // Name and content of each member of an `ar` archive
+    fn ar_members(content: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut members = Vec::new();
+        let mut offset = 8;
+        while offset < content.len() {
+            let header = std::str::from_utf8(&content[offset..offset + 60]).unwrap();
+            let size: usize = header[48..58].trim().parse().unwrap();
+            let start = offset + 60;
+            members.push((header[..16].trim().to_string(), content[start..start + size].to_vec()));
+            offset = start + size + size % 2;
+        }
+        members
+    }
+
+    // Path -> mode and content of each regular file of a `.tar.gz`
+    fn tar_files(content: &[u8]) -> BTreeMap<String, (u32, Vec<u8>)> {
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(content));
+        let mut files = BTreeMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.header().entry_type().is_file() {
+                let path = entry.path().unwrap().display().to_string();
+                let mode = entry.header().mode().unwrap();
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).unwrap();
+                files.insert(path, (mode, content));
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn test_write_deb() {
+        let root = temp_dir("rustup-distribution-test-deb");
+        let dir = root.join("rust-nightly-x86_64-unknown-linux-gnu");
+        write_files(
+            &dir,
+            &[
+                ("components", "rustc\n"),
+                ("rustc/manifest.in", "file:bin/rustc\ndir:share/doc/rust\n"),
+                ("rustc/bin/rustc", "rustc"),
+                ("rustc/share/doc/rust/README.md", "readme"),
+            ],
+        );
+        let metadata = bundle_metadata(&dir, linux_target("nightly", Some("2024-01-01")), &[]);
+
+        let deb = root.join("rust-nightly-x86_64-unknown-linux-gnu.deb");
+        write_deb(&dir, &deb, &metadata).unwrap();
+        let content = fs::read(&deb).unwrap();
+        assert!(content.starts_with(b"!<arch>\ndebian-binary   "));
+        assert!(!root.join("rust-nightly-x86_64-unknown-linux-gnu.root").exists());
+        assert!(!root.join("rust-nightly-x86_64-unknown-linux-gnu.data.tar.gz").exists());
+
+        let members = ar_members(&content);
+        assert_eq!(members.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["debian-binary", "control.tar.gz", "data.tar.gz"]);
+        let control_tar = tar_files(&members[1].1);
+        assert_eq!(control_tar.keys().collect::<Vec<_>>(), ["control", "md5sums", "postinst", "prerm"]);
+        assert_eq!(control_tar["postinst"].0, 0o755);
+        let control = String::from_utf8(control_tar["control"].1.clone()).unwrap();
+        assert!(control.starts_with("Package: rust-nightly-2024-01-01-x86-64-unknown-linux-gnu\nVersion: 0~nightly.20240101\nArchitecture: amd64\n"));
+        assert!(control.contains("\nDescription: Rust nightly-2024-01-01-x86_64-unknown-linux-gnu toolchain\n Installs rustc into "));
+        // every installed file is listed with its md5, relative to the root
+        let data_tar = tar_files(&members[2].1);
+        let md5sums = String::from_utf8(control_tar["md5sums"].1.clone()).unwrap();
+        let prefix = "opt/rust/nightly-2024-01-01-x86_64-unknown-linux-gnu";
+        assert!(md5sums.contains(&format!("{}  {}/bin/rustc\n", utils::to_hex(&Md5::digest(b"rustc")), prefix)));
+        assert!(md5sums.contains(&format!("  {}/lib/rustlib/manifest-rustc\n", prefix)));
+        for line in md5sums.lines() {
+            let (md5, path) = line.split_once("  ").unwrap();
+            assert_eq!(utils::to_hex(&Md5::digest(&data_tar[path].1)), md5, "{}", path);
+        }
+        assert_eq!(md5sums.lines().count(), data_tar.len());
+
+        // and dpkg reads it the same way, when installed
+        if utils::find_program("dpkg-deb").is_some() {
+            let output = std::process::Command::new("dpkg-deb").arg("--info").arg(&deb).output().unwrap();
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            let info = String::from_utf8(output.stdout).unwrap();
+            assert!(info.contains(" Package: rust-nightly-2024-01-01-x86-64-unknown-linux-gnu\n"));
+            assert!(info.contains(" Version: 0~nightly.20240101\n"));
+            let extracted = root.join("extracted");
+            assert!(std::process::Command::new("dpkg-deb").arg("-x").arg(&deb).arg(&extracted).status().unwrap().success());
+            assert_eq!(fs::read_to_string(extracted.join(prefix).join("bin/rustc")).unwrap(), "rustc");
+        }
+
+        assert_eq!(maintainer(None, None), format!("{} <{}>", env!("CARGO_PKG_NAME"), DEFAULT_MAINTAINER_EMAIL));
+        assert_eq!(maintainer(Some("Jane Doe".to_string()), Some("jane@example.org".to_string())), "Jane Doe <jane@example.org>");
+        assert_eq!(maintainer(None, Some("Jane Doe <jane@example.org>".to_string())), "Jane Doe <jane@example.org>");
+        assert_eq!(maintainer(Some("J. Doe".to_string()), Some("Jane Doe <jane@example.org>".to_string())), "J. Doe <jane@example.org>");
+
+        assert_eq!(debian_architecture("aarch64-unknown-linux-gnu"), Some("arm64"));
+        assert_eq!(debian_architecture("armv7-unknown-linux-gnueabihf"), Some("armhf"));
+        assert_eq!(debian_architecture("arm-unknown-linux-gnueabi"), Some("armel"));
+        assert_eq!(debian_architecture("i586-unknown-linux-gnu"), Some("i386"));
+        assert_eq!(debian_architecture("x86_64-pc-windows-msvc"), None);
+        assert_eq!(debian_architecture("wasm32-unknown-unknown"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,19 +1,47 @@
-use std::{path::{PathBuf, Path}, ops, fs::{self, remove_file, OpenOptions}, env, cell::RefCell, io::Write};
+use std::{path::{PathBuf, Path}, ops, fs::{self, remove_file, OpenOptions}, env, cell::{Cell, RefCell}, io::Write};
 
-use anyhow::{Result, Context};
+use anyhow::{bail, Result, Context};
+use tracing::{debug, info};
 
-use crate::{utils, Backend, TlsBackend, curl, CommandRunner, Runner};
+use crate::{report, utils, Backend, TlsBackend, curl, reqwest_be, CommandSpec, Credential, Message, NetworkConfig, Runner};
 
 use url::Url;
 
+// Bytes between two `download-progress` messages
+const PROGRESS_INTERVAL: u64 = 4 * 1024 * 1024;
+
 #[derive(Clone)]
 pub struct DownloadCfg {
     pub dist_root: String,
     pub download_dir: PathBuf,
+    pub network: NetworkConfig,
+}
+
+// The dist server has no file at the requested url: an HTTP 404, or a local mirror lacking it
+#[derive(Debug)]
+pub struct NotFoundError {
+    pub url: String,
+}
+
+impl std::fmt::Display for NotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} does not exist", self.url)
+    }
+}
+
+impl std::error::Error for NotFoundError {}
+
+impl NotFoundError {
+    // Whether `err` comes from a missing file rather than a network or server failure
+    pub fn is_cause_of(err: &anyhow::Error) -> bool {
+        err.chain().any(|cause| cause.is::<NotFoundError>())
+    }
 }
 
 pub struct File {
     pub path: PathBuf,
+    // sha256 of the downloaded archive, kept after the archive is extracted
+    pub sha256: String,
 }
 
 impl ops::Deref for File {
@@ -37,55 +65,97 @@ impl DownloadCfg {
     pub fn download(&self, target_file_name: &String) -> Result<File> {
         utils::ensure_dir_exists(&"Download Directory".to_string(), &self.download_dir)?;
 
-        let target_file = self.download_dir.join(Path::new(target_file_name));
+        let target_file = self.target_path(target_file_name);
         if target_file.exists() {
             fs::remove_file(&target_file).context("cleaning up previous download")?;
         }
         let url = utils::parse_url(&self.dist_root)?;
 
-        if let Err(err) = download_file(&url, &target_file) {
-            panic!("failed to download file {:?} from url: {}, \n cause: {:?}", target_file_name, url, err);
-        }
+        info!(url = %utils::redact_url(&url), "Downloading {}", target_file_name);
+        report::emit(Message::DownloadStarted { url: utils::redact_url(&url), destination: target_file.clone() });
+        download_file(&url, &target_file, &self.network).with_context(|| {
+            format!("failed to download file {:?} from url: {}", target_file_name, utils::redact_url(&url))
+        })?;
+
+        let sha256 = utils::sha256_file(&target_file).context("hashing downloaded file")?;
+        debug!(path = %target_file.display(), sha256 = %sha256, "Downloaded {}", target_file_name);
+        report::emit(Message::DownloadFinished {
+            url: utils::redact_url(&url),
+            destination: target_file.clone(),
+            size: fs::metadata(&target_file)?.len(),
+            sha256: sha256.clone(),
+        });
+
+        Ok(File { path: target_file, sha256 })
+    }
 
-        Ok(File { path: target_file })
+    // The local path `download` writes `target_file_name` to
+    pub fn target_path(&self, target_file_name: &str) -> PathBuf {
+        self.download_dir.join(target_file_name)
     }
 
-    pub fn extract_after_download(&self, target_file_name: &String) -> Result<File> {
-        match self.download(target_file_name) {
-            Ok(target_file) => {
-                let target_folder = utils::strip_extensions(&utils::strip_extensions(&target_file.to_path_buf()));
-                utils::ensure_dir_exists(&"Rust package".to_string(), &target_folder)?;
-                let mut args = Vec::new();
-                args.push("zxvf".to_string());
-                args.push(target_file.to_owned().to_string_lossy().to_string());
-                args.push("-C".to_string());
-                args.push(target_folder.parent().expect("Wrong target folder").to_owned().to_string_lossy().to_string());
-                if let Err(err) = CommandRunner::Tar.run_command(&args) {
-                    panic!("Error running command: {:?}", err);
-                };
-                
-                fs::remove_file(&*target_file).context("cleaning up downloaded package")?;
-
-                Ok(File { path: target_folder })
-            },
-            Err(_) => {
-                panic!("Failed to extract target folder");
-            },
+    // Download and unpack `target_file_name`, refusing an archive whose sha256 is not `expected_sha256`
+    pub fn extract_after_download(&self, target_file_name: &String, expected_sha256: Option<&str>, runner: &dyn Runner) -> Result<File> {
+        let target_file = self.download(target_file_name)?;
+        if let Some(expected) = expected_sha256.filter(|expected| *expected != target_file.sha256) {
+            fs::remove_file(&*target_file).context("cleaning up downloaded package")?;
+            bail!("checksum mismatch for {}: expected sha256 {}, found {}", target_file_name, expected, target_file.sha256);
         }
+
+        let target_folder = utils::strip_extensions(&utils::strip_extensions(&target_file));
+        utils::ensure_dir_exists(&"Rust package".to_string(), &target_folder)?;
+        runner.run(&CommandSpec::tar(extract_args(&target_file)))?;
+
+        fs::remove_file(&*target_file).context("cleaning up downloaded package")?;
+
+        Ok(File { path: target_folder, sha256: target_file.sha256.clone() })
     }
 }
 
+// Arguments `tar` is run with to unpack `archive` next to itself
+pub fn extract_args(archive: &Path) -> Vec<String> {
+    let target_folder = utils::strip_extensions(&utils::strip_extensions(archive));
+    vec![
+        "zxvf".to_string(),
+        archive.to_string_lossy().to_string(),
+        "-C".to_string(),
+        target_folder.parent().expect("Wrong target folder").to_string_lossy().to_string(),
+    ]
+}
+
 pub fn download_v1_manifest() {
     todo!()
 }
 
-fn download_file(url: &Url, path: &PathBuf) -> Result<()> {
+fn download_file(url: &Url, path: &Path, network: &NetworkConfig) -> Result<()> {
+    // Local mirrors are read straight from disk
+    if url.scheme() == "file" {
+        return copy_local_file(url, path);
+    }
+
     // Download the file
+    let backend = select_backend(network);
+
+    // Credentials travel as headers, never as part of the requested url
+    let credential = network.credentials.for_url(url);
+    let url = utils::strip_url_credentials(url);
+
+    download_to_path_with_backend(&backend, &url, path, network, credential.as_ref())
+}
+
+// Size of a file of a local mirror, without reading it. `None` for remote urls, which are
+// never queried.
+pub fn local_file_size(url: &str) -> Option<u64> {
+    let url = Url::parse(url).ok().filter(|url| url.scheme() == "file")?;
+    fs::metadata(url.to_file_path().ok()?).ok().map(|metadata| metadata.len())
+}
+
+fn select_backend(network: &NetworkConfig) -> Backend {
     // Keep the curl env var around for a bit
-    let use_curl_backend = env::var_os("RUSTUP_USE_CURL").is_some();
+    let use_curl_backend = network.use_curl || env::var_os("RUSTUP_USE_CURL").is_some();
     let use_rustls = env::var_os("RUSTUP_USE_RUSTLS").is_some();
 
-    let backend = if use_curl_backend {
+    if use_curl_backend {
         Backend::Curl
     } else {
         let tls_backend = if use_rustls {
@@ -101,39 +171,73 @@ fn download_file(url: &Url, path: &PathBuf) -> Result<()> {
             }
         };
         Backend::Reqwest(tls_backend)
-    };
+    }
+}
 
-    let res = download_to_path_with_backend(&backend, url, path);
+fn copy_local_file(url: &Url, path: &Path) -> Result<()> {
+    let source = url
+        .to_file_path()
+        .map_err(|_| anyhow::anyhow!("invalid local file url: {}", url))?;
+    if !source.exists() {
+        return Err(NotFoundError { url: url.to_string() }.into());
+    }
+    fs::copy(&source, path).with_context(|| format!("failed to read file: {}", source.display()))?;
 
-    res
+    Ok(())
 }
 
 fn download_with_backend(
     backend: &Backend, 
     url: &Url,
+    network: &NetworkConfig,
+    credential: Option<&Credential>,
     callback: &dyn Fn(Event<'_>) -> Result<()>,
 ) -> Result<()> {
     match backend {
-        Backend::Curl => curl::download(url, callback),
-        Backend::Reqwest(_) => todo!(),
+        Backend::Curl => curl::download(url, network, credential, callback),
+        Backend::Reqwest(tls_backend) => reqwest_be::download(url, network, credential, tls_backend, callback),
     }
 }
 
-fn download_to_path_with_backend(backend: &Backend, url: &Url, path: &Path) -> Result<()> {
+fn download_to_path_with_backend(
+    backend: &Backend,
+    url: &Url,
+    path: &Path,
+    network: &NetworkConfig,
+    credential: Option<&Credential>,
+) -> Result<()> {
     || -> Result<()> {
         let file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(path)
             .context("error creating file for download")?;
 
         let file = RefCell::new(file);
+        let total = Cell::new(None);
+        let downloaded = Cell::new(0u64);
 
-        download_with_backend(backend, url, &|event| {
-            if let Event::DownloadDataReceived(data) = event {
-                file.borrow_mut()
-                .write_all(data)
-                .context("unable to write downloaded to disk")?;
+        download_with_backend(backend, url, network, credential, &|event| {
+            match event {
+                Event::DownloadContentLengthReceived(len) => total.set(Some(len)),
+                Event::DownloadDataReceived(data) => {
+                    file.borrow_mut()
+                    .write_all(data)
+                    .context("unable to write downloaded to disk")?;
+
+                    let before = downloaded.get();
+                    downloaded.set(before + data.len() as u64);
+                    if before / PROGRESS_INTERVAL != downloaded.get() / PROGRESS_INTERVAL {
+                        debug!(downloaded = downloaded.get(), total = ?total.get(), "Download progress");
+                        report::emit(Message::DownloadProgress {
+                            url: utils::redact_url(url),
+                            downloaded: downloaded.get(),
+                            total: total.get(),
+                        });
+                    }
+                }
+                Event::ResumingPartialDownload => {}
             }
             Ok(())
         })?;
@@ -163,18 +267,33 @@ mod tests {
         let url = Url::parse("https://static.rust-lang.org/dist/2023-06-14/rust-nightly-x86_64-pc-windows-msvc.tar.gz");
         let path = PathBuf::from(r"D:\Normal\projects\rustup-plus-plus\rust-nightly-x86_64-pc-windows-msvc.tar.gz");
         if let Ok(url) = url {
-            let _ = download_file(&url, &path);
+            let _ = download_file(&url, &path, &NetworkConfig::default());
         }
     }
 
+    #[test]
+    fn test_download_local_file() {
+        let mirror = env::temp_dir().join("rustup-distribution-test-mirror");
+        fs::create_dir_all(&mirror).unwrap();
+        fs::write(mirror.join("channel-rust-nightly.toml"), "manifest-version = \"2\"").unwrap();
+
+        let url = Url::from_file_path(mirror.join("channel-rust-nightly.toml")).unwrap();
+        let path = mirror.join("downloaded.toml");
+        download_file(&url, &path, &NetworkConfig::default()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "manifest-version = \"2\"");
+
+        fs::remove_dir_all(&mirror).unwrap();
+    }
+
     #[test]
     fn test_extract_download_file() {
         env::set_var("RUSTUP_USE_CURL", "true");
         let download_cfg = DownloadCfg{
             dist_root: "https://mirrors.tuna.tsinghua.edu.cn/rustup/dist/2023-06-25/cargo-nightly-aarch64-apple-darwin.tar.gz".to_string(),
             download_dir: PathBuf::from(r"D:\Normal\projects\rustup-plus-plus\"),
+            network: NetworkConfig::default(),
         };
         let target_file_name = "cargo-nightly-aarch64-apple-darwin.tar.gz".to_string();
-        let _ = download_cfg.extract_after_download(&target_file_name);
+        let _ = download_cfg.extract_after_download(&target_file_name, None, &crate::ProcessRunner::default());
     }
 }
\ No newline at end of file
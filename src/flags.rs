@@ -1,6 +1,9 @@
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand};
+
+use crate::package::PackageFormat;
+use crate::{MessageFormat, SbomFormat, LATEST_AVAILABLE_MAX_DAYS};
 
 #[derive(Debug, Clone, Subcommand)]
 pub enum SubCommand {
@@ -12,8 +15,22 @@ pub enum SubCommand {
             rustup_plus_plus install -s /home/toolchain.yaml"
     )]
     Install {
-        #[arg(short, long, required = true)]
+        #[arg(short, long)]
         source_dir: Option<PathBuf>,
+        #[command(flatten)]
+        overrides: ConfigOverrides,
+        /// Refuse to proceed if anything would resolve differently than toolchain.lock
+        #[arg(long)]
+        locked: bool,
+        /// Print every download, command and written file without doing any of it
+        #[arg(long)]
+        dry_run: bool,
+        /// Emit newline-delimited json messages on stdout instead of human readable progress
+        #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+        message_format: MessageFormat,
+        /// Also write the json summary of the run to this file
+        #[arg(long)]
+        report: Option<PathBuf>,
     },
     #[clap(
         name = "package",
@@ -23,16 +40,200 @@ pub enum SubCommand {
             rustup_plus_plus package -s /home/toolchain.yaml -o /home/package/"
     )]
     Package {
-        #[arg(short, long, required = true)]
+        #[arg(short, long)]
         source_dir: Option<PathBuf>,
+        #[command(flatten)]
+        overrides: ConfigOverrides,
         #[arg(short, long, required = true)]
         output_dir: Option<PathBuf>,
+        /// Write a software bill of materials next to each bundle
+        #[arg(long, value_enum, value_delimiter = ',')]
+        sbom: Vec<SbomFormat>,
+        /// What to package each toolchain as: a `.tar.gz` bundle, a self-extracting `.run` installer, a `.deb` or an `.rpm` package
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "tar-gz")]
+        format: Vec<PackageFormat>,
+        /// Refuse to proceed if anything would resolve differently than toolchain.lock
+        #[arg(long)]
+        locked: bool,
+        /// Print every download, command and written file without doing any of it
+        #[arg(long)]
+        dry_run: bool,
+        /// Emit newline-delimited json messages on stdout instead of human readable progress
+        #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+        message_format: MessageFormat,
+        /// Also write the json summary of the run to this file
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    #[clap(
+        name = "sync",
+        long_about = "Arguments:
+        This subcommand accepts the `<PATH>` of a configuration file, and makes the machine match it:
+        missing toolchains, components, targets and tools are installed and, with `--prune`, toolchains
+        and tools it does not list are removed. Every change is shown and confirmed first. For example:
+            rustup_plus_plus sync -s /home/toolchain.yaml --prune"
+    )]
+    Sync {
+        #[arg(short, long)]
+        source_dir: Option<PathBuf>,
+        #[command(flatten)]
+        overrides: ConfigOverrides,
+        /// Also uninstall toolchains and cargo-installed tools the configuration does not list
+        #[arg(long)]
+        prune: bool,
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+        /// Refuse to proceed if anything would resolve differently than toolchain.lock
+        #[arg(long)]
+        locked: bool,
+        /// Print every download, command and written file without doing any of it
+        #[arg(long)]
+        dry_run: bool,
+        /// Emit newline-delimited json messages on stdout instead of human readable progress
+        #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+        message_format: MessageFormat,
+        /// Also write the json summary of the run to this file
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    #[clap(
+        name = "uninstall",
+        long_about = "Arguments:
+        This subcommand accepts the `<PATH>` of a configuration file, and uninstalls the toolchains
+        and tools it lists. With `--bundle` it instead removes the files a bundle installed into
+        `--prefix`. For example:
+            rustup_plus_plus uninstall -s /home/toolchain.yaml
+            rustup_plus_plus uninstall --bundle /home/package/rust-nightly-x86_64-unknown-linux-gnu.tar.gz --prefix /opt/rust"
+    )]
+    Uninstall {
+        #[arg(short, long, conflicts_with = "bundle")]
+        source_dir: Option<PathBuf>,
+        #[command(flatten)]
+        overrides: ConfigOverrides,
+        /// Bundle produced by `package` whose installed files are removed from --prefix
+        #[arg(long, requires = "prefix")]
+        bundle: Option<PathBuf>,
+        /// Directory the bundle was installed into
+        #[arg(long, requires = "bundle")]
+        prefix: Option<PathBuf>,
+        /// Print every command and removed file without doing any of it
+        #[arg(long)]
+        dry_run: bool,
+        /// Emit newline-delimited json messages on stdout instead of human readable progress
+        #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+        message_format: MessageFormat,
+        /// Also write the json summary of the run to this file
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    #[clap(
+        name = "resolve",
+        long_about = "Arguments:
+        This subcommand accepts the `<PATH>` of a configuration file, and prints the date each target
+        resolves to. Floating channels and `date: latest-available` walk back through dated manifests
+        until every requested component is available. For example:
+            rustup_plus_plus resolve -s /home/toolchain.yaml"
+    )]
+    Resolve {
+        #[arg(short, long)]
+        source_dir: Option<PathBuf>,
+        #[command(flatten)]
+        overrides: ConfigOverrides,
+        /// How many days to walk back before giving up
+        #[arg(long, default_value_t = LATEST_AVAILABLE_MAX_DAYS)]
+        max_days: usize,
+    },
+    #[clap(
+        name = "verify",
+        long_about = "Arguments:
+        This subcommand accepts the `<PATH>` of a bundle produced by `package`, and re-hashes its
+        contents against the embedded metadata. For example:
+            rustup_plus_plus verify /home/package/rust-nightly-x86_64-unknown-linux-gnu.tar.gz"
+    )]
+    Verify {
+        bundle: PathBuf,
+    },
+    #[clap(name = "config")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
     },
 }
 
+#[derive(Debug, Clone, Subcommand)]
+pub enum ConfigCommand {
+    #[clap(
+        name = "show",
+        long_about = "Arguments:
+        This subcommand accepts the `<PATH>` of a configuration file, and prints the configuration
+        with its includes merged and variables interpolated, along with the file, environment
+        variable or default each value comes from. For example:
+            rustup_plus_plus config show -s /home/toolchain.yaml"
+    )]
+    Show {
+        #[arg(short, long)]
+        source_dir: Option<PathBuf>,
+        #[command(flatten)]
+        overrides: ConfigOverrides,
+    },
+}
+
+// Configuration values given on the command line, taking precedence over the environment
+// and the configuration file
+#[derive(Debug, Clone, Default, Args)]
+pub struct ConfigOverrides {
+    /// Dist server to download toolchains from [env: RUSTUP_DIST_SERVER]
+    #[arg(long, value_name = "URL")]
+    pub dist_server: Option<String>,
+    /// Root of the rustup update server [env: RUSTUP_UPDATE_ROOT]
+    #[arg(long, value_name = "URL")]
+    pub update_root: Option<String>,
+    /// Host triples to package toolchains for, replacing the targets of the configuration [env: RUSTUP_DISTRIBUTION_TARGETS]
+    #[arg(long = "target", value_name = "TRIPLE", value_delimiter = ',')]
+    pub targets: Vec<String>,
+    /// Channel of every toolchain, eg: stable, nightly, 1.75.0 [env: RUSTUP_DISTRIBUTION_CHANNEL]
+    #[arg(long)]
+    pub channel: Option<String>,
+    /// Date of every toolchain, YYYY-MM-DD or latest-available [env: RUSTUP_DISTRIBUTION_DATE]
+    #[arg(long)]
+    pub date: Option<String>,
+    /// Take every toolchain from the staged release candidates [env: RUSTUP_DISTRIBUTION_STAGING]
+    #[arg(long)]
+    pub staging: bool,
+    /// Extra tool to install, added to or replacing the configured version [env: RUSTUP_DISTRIBUTION_TOOLS]
+    #[arg(long = "tool", value_name = "NAME[@VERSION]")]
+    pub tools: Vec<String>,
+    /// Install toolchains into this directory instead of the default rustup home. Only this flag or the configuration file isolates an install, not RUSTUP_HOME
+    #[arg(long, value_name = "DIR")]
+    pub rustup_home: Option<PathBuf>,
+    /// Install tools into this directory instead of the default cargo home. Only this flag or the configuration file isolates an install, not CARGO_HOME
+    #[arg(long, value_name = "DIR")]
+    pub cargo_home: Option<PathBuf>,
+}
+
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about=None)]
 pub struct Options {
     #[command(subcommand)]
     pub subcommand: SubCommand,
+    /// Log more, repeat for even more (-vv). Ignored when RUST_LOG is set
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Log less, repeat for even less (-qq). Ignored when RUST_LOG is set
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    pub quiet: u8,
+    /// Also write debug logs, including the full output of rustup, cargo and tar, to this file
+    #[arg(long, global = true)]
+    pub log_file: Option<PathBuf>,
+    /// Stop rustup, cargo and tar if they run for longer than this many seconds
+    #[arg(long, global = true, value_name = "SECONDS")]
+    pub command_timeout: Option<u64>,
+}
+
+impl Options {
+    // Number of `-v` minus number of `-q`
+    pub fn verbosity(&self) -> i8 {
+        self.verbose.min(i8::MAX as u8) as i8 - self.quiet.min(i8::MAX as u8) as i8
+    }
 }
\ No newline at end of file
@@ -0,0 +1,216 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{CommandSpec, ExtendTool, InstallHomes, Runner, TargetSelection};
+
+// What is already installed, to only install the differences
+#[derive(Clone, Debug, Default)]
+pub struct InstalledState {
+    // toolchain name -> installed components, eg: clippy-x86_64-unknown-linux-gnu
+    pub toolchains: BTreeMap<String, BTreeSet<String>>,
+    // crate name -> installed version
+    pub crates: BTreeMap<String, String>,
+}
+
+// Outcome of installing one toolchain or tool
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InstallStatus {
+    Installed,
+    AlreadyPresent,
+    Upgraded,
+    Removed,
+}
+
+#[derive(Deserialize)]
+struct CratesV2 {
+    #[serde(default)]
+    installs: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct CratesV1 {
+    #[serde(default)]
+    v1: BTreeMap<String, Vec<String>>,
+}
+
+impl fmt::Display for InstallStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            InstallStatus::Installed => "installed",
+            InstallStatus::AlreadyPresent => "already present",
+            InstallStatus::Upgraded => "upgraded",
+            InstallStatus::Removed => "removed",
+        };
+        f.pad(status)
+    }
+}
+
+impl InstalledState {
+    // Installed toolchains and components as reported by rustup, installed crates as recorded
+    // by cargo, both in `homes`. rustup is only asked with `toolchains`, it may not even be
+    // there when only tools are configured.
+    pub fn read(runner: &dyn Runner, homes: &InstallHomes, toolchains: bool) -> Result<Self> {
+        let mut state = InstalledState::default();
+
+        let listed = match toolchains {
+            true => runner.run(&homes.apply(CommandSpec::rustup(vec!["toolchain".to_string(), "list".to_string()])))?.stdout,
+            false => String::new(),
+        };
+        for toolchain in parse_toolchain_list(&listed) {
+            let output = runner.run(&homes.apply(CommandSpec::rustup(vec![
+                "component".to_string(),
+                "list".to_string(),
+                "--installed".to_string(),
+                "--toolchain".to_string(),
+                toolchain.clone(),
+            ])))?;
+            let components = output.stdout.lines().map(str::trim).filter(|line| !line.is_empty()).map(String::from).collect();
+            state.toolchains.insert(toolchain, components);
+        }
+
+        if let Some(cargo_home) = homes.cargo_home() {
+            state.crates = read_installed_crates(&cargo_home)?;
+        }
+        debug!(toolchains = state.toolchains.len(), crates = state.crates.len(), "Read installed state");
+
+        Ok(state)
+    }
+
+    // Components and targets of `target_selection` missing from its installed toolchain,
+    // `None` when the toolchain is not installed at all
+    pub fn missing(&self, target_selection: &TargetSelection) -> Option<(Vec<String>, Vec<String>)> {
+        let installed = self.toolchains.get(&target_selection.rustup_toolchain_name().to_lowercase())?;
+        let host = &target_selection.target;
+        let components = target_selection
+            .components
+            .iter()
+            .filter(|component| !installed.contains(*component) && !installed.contains(&format!("{}-{}", component, host)))
+            .cloned()
+            .collect();
+        let targets = target_selection
+            .targets
+            .iter()
+            .filter(|target| !installed.contains(&format!("rust-std-{}", target)))
+            .cloned()
+            .collect();
+        Some((components, targets))
+    }
+
+    // Installed version of `tool`, and whether it is the requested one. Any version will do
+    // when none is requested.
+    pub fn tool(&self, tool: &ExtendTool) -> Option<(&str, bool)> {
+        let installed = self.crates.get(&tool.name)?;
+        let up_to_date = tool.version.as_deref().is_none_or(|version| version == installed);
+        Some((installed, up_to_date))
+    }
+}
+
+// `rustup toolchain list` prints one toolchain per line, followed by `(default)` or `(active)`
+fn parse_toolchain_list(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| *name != "no")
+        .map(String::from)
+        .collect()
+}
+
+// Crates installed by `cargo install`, from `.crates2.json` and the older `.crates.toml`
+fn read_installed_crates(cargo_home: &Path) -> Result<BTreeMap<String, String>> {
+    let mut crates = BTreeMap::new();
+
+    let v1 = cargo_home.join(".crates.toml");
+    if let Ok(content) = fs::read_to_string(&v1) {
+        let v1: CratesV1 = toml::from_str(&content).with_context(|| format!("failed to parse {}", v1.display()))?;
+        crates.extend(v1.v1.keys().filter_map(|key| parse_package_id(key)));
+    }
+    let v2 = cargo_home.join(".crates2.json");
+    if let Ok(content) = fs::read_to_string(&v2) {
+        let v2: CratesV2 = serde_json::from_str(&content).with_context(|| format!("failed to parse {}", v2.display()))?;
+        crates.extend(v2.installs.keys().filter_map(|key| parse_package_id(key)));
+    }
+
+    Ok(crates)
+}
+
+// `grcov 0.8.18 (registry+https://github.com/rust-lang/crates.io-index)` -> (grcov, 0.8.18)
+fn parse_package_id(id: &str) -> Option<(String, String)> {
+    let mut parts = id.split_whitespace();
+    Some((parts.next()?.to_string(), parts.next()?.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandOutput, RecordingRunner};
+
+    #[test]
+    fn test_read_installed_state() {
+        let cargo_home = std::env::temp_dir().join("rustup-distribution-test-installed-state");
+        let _ = fs::remove_dir_all(&cargo_home);
+        fs::create_dir_all(&cargo_home).unwrap();
+        fs::write(
+            cargo_home.join(".crates.toml"),
+            "[v1]\n\"grcov 0.8.18 (registry+https://github.com/rust-lang/crates.io-index)\" = [\"grcov\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            cargo_home.join(".crates2.json"),
+            r#"{"installs":{"cargo-nextest 0.9.60 (registry+https://github.com/rust-lang/crates.io-index)":{"bins":["cargo-nextest"]}}}"#,
+        )
+        .unwrap();
+
+        let runner = RecordingRunner::with_handler(|command| {
+            let stdout = match command.args.first().map(String::as_str) {
+                Some("toolchain") => "stable-x86_64-unknown-linux-gnu (active, default)\nnightly-2024-01-01-x86_64-unknown-linux-gnu\n",
+                _ if command.args.contains(&"stable-x86_64-unknown-linux-gnu".to_string()) => {
+                    "cargo-x86_64-unknown-linux-gnu\nclippy-x86_64-unknown-linux-gnu\nrust-std-wasm32-unknown-unknown\n"
+                }
+                _ => "cargo-x86_64-unknown-linux-gnu\n",
+            };
+            Ok(CommandOutput { stdout: stdout.to_string(), stderr: String::new() })
+        });
+        let homes = InstallHomes { cargo_home: Some(cargo_home.clone()), ..Default::default() };
+        let state = InstalledState::read(&runner, &homes, true).unwrap();
+        assert_eq!(state.toolchains.len(), 2);
+        assert_eq!(runner.commands().len(), 3);
+        // only tools configured, rustup is left alone
+        let tools_only = RecordingRunner::new();
+        let tools_state = InstalledState::read(&tools_only, &homes, false).unwrap();
+        assert!(tools_only.commands().is_empty());
+        assert!(tools_state.toolchains.is_empty());
+        assert_eq!(tools_state.crates.len(), 2);
+        assert_eq!(state.crates["grcov"], "0.8.18");
+        assert_eq!(state.crates["cargo-nextest"], "0.9.60");
+
+        let mut stable = TargetSelection {
+            target: "x86_64-unknown-linux-gnu".to_string(),
+            channel: "stable".to_string(),
+            components: vec!["clippy".to_string(), "miri".to_string()],
+            targets: vec!["wasm32-unknown-unknown".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(state.missing(&stable), Some((vec!["miri".to_string()], vec![])));
+        stable.channel = "beta".to_string();
+        assert_eq!(state.missing(&stable), None);
+
+        let grcov = ExtendTool { name: "grcov".to_string(), version: Some("0.8.19".to_string()) };
+        assert_eq!(state.tool(&grcov), Some(("0.8.18", false)));
+        assert_eq!(state.tool(&ExtendTool { version: None, ..grcov }), Some(("0.8.18", true)));
+
+        fs::remove_dir_all(&cargo_home).unwrap();
+    }
+
+    #[test]
+    fn test_parse_toolchain_list() {
+        assert!(parse_toolchain_list("no installed toolchains\n").is_empty());
+        assert_eq!(parse_toolchain_list("stable-x86_64-unknown-linux-gnu (default)\n"), ["stable-x86_64-unknown-linux-gnu"]);
+    }
+}
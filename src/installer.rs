@@ -0,0 +1,415 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{archive, parse_component_manifest, utils, BundleMetadata, ManifestEntry};
+
+// POSIX installer written at the root of every bundle, in place of rust-installer's bash one,
+// taking the same options
+pub const INSTALL_SCRIPT_FILENAME: &str = "install.sh";
+
+const INSTALL_SCRIPT: &str = include_str!("installer/install.sh");
+const WRAPPER_TEMPLATE: &str = include_str!("installer/wrapper.sh");
+const SFX_TEMPLATE: &str = include_str!("installer/sfx.sh");
+// rustup registration and uninstall record, shared by the wrapper and self-extracting installers
+const POST_INSTALL: &str = include_str!("installer/post_install.sh");
+// maintainer scripts of the system packages
+const RUSTUP_LINK_TEMPLATE: &str = include_str!("installer/rustup_link.sh");
+const RUSTUP_UNLINK_TEMPLATE: &str = include_str!("installer/rustup_unlink.sh");
+// runs rustup as the user who ran sudo rather than root, shared by the maintainer scripts
+const RUSTUP_AS_USER: &str = include_str!("installer/rustup_as_user.sh");
+
+// Where system packages install a toolchain
+pub const PACKAGE_ROOT: &str = "/opt/rust";
+
+// Write the installer every component of `bundle_dir`, extra tools included, is installed by
+pub fn write_install_script(bundle_dir: &Path) -> Result<()> {
+    let path = bundle_dir.join(INSTALL_SCRIPT_FILENAME);
+    write_script(&path, INSTALL_SCRIPT)
+}
+
+// The standalone installer is a shell script, bundles of Windows toolchains get none
+pub fn has_wrapper(target: &str) -> bool {
+    !target.contains("-windows")
+}
+
+// The standalone installer of `bundle`, next to it: rust-nightly-x86_64-unknown-linux-gnu-install.sh
+pub fn wrapper_path(bundle: &Path) -> PathBuf {
+    let stem = utils::strip_extensions(&utils::strip_extensions(bundle));
+    let mut path = stem.into_os_string();
+    path.push("-install.sh");
+    path.into()
+}
+
+// Write the standalone installer of `bundle`, which must stay next to it. Returns its path.
+pub fn write_wrapper(bundle: &Path, sha256: &str, metadata: &BundleMetadata) -> Result<PathBuf> {
+    let bundle_name = bundle.file_name().context("bundle has no file name")?.to_string_lossy();
+    let path = wrapper_path(bundle);
+    write_script(&path, &render_wrapper(&bundle_name, sha256, metadata))?;
+    Ok(path)
+}
+
+// Script the standalone installer leaves in `prefix` to remove `toolchain` again
+pub fn uninstall_record(prefix: &Path, toolchain: &str) -> PathBuf {
+    prefix.join("lib/rustlib").join(format!("uninstall-{}.sh", toolchain.to_lowercase()))
+}
+
+// Write a single executable file installing the `.tar.gz` bundle `payload`: a shell script
+// followed by the bundle itself
+pub fn write_self_extracting(payload: &Path, dest: &Path, metadata: &BundleMetadata) -> Result<()> {
+    let header = render_sfx(&utils::sha256_file(payload)?, metadata);
+
+    let mut file = File::create(dest).with_context(|| format!("failed to create {}", dest.display()))?;
+    file.write_all(header.as_bytes())?;
+    io::copy(&mut File::open(payload)?, &mut file)?;
+    file.sync_all()?;
+    drop(file);
+    set_executable(dest)
+}
+
+// Where the system packages of `metadata` install it, eg: /opt/rust/nightly-x86_64-unknown-linux-gnu
+pub fn package_prefix(metadata: &BundleMetadata) -> PathBuf {
+    Path::new(PACKAGE_ROOT).join(&metadata.toolchain)
+}
+
+// The Debian and RPM architectures of a Linux target triple. 32-bit ARM targets ending in `hf`
+// use the hard-float ABI, eg: armv7-unknown-linux-gnueabihf -> (armhf, armv7hl)
+pub fn package_architectures(target: &str) -> Option<(&'static str, &'static str)> {
+    if !target.contains("-linux-") {
+        return None;
+    }
+    let arch = target.split('-').next()?;
+    let architectures = match (arch, target.ends_with("hf")) {
+        ("x86_64", _) => ("amd64", "x86_64"),
+        ("aarch64", _) => ("arm64", "aarch64"),
+        ("i586", _) => ("i386", "i586"),
+        ("i686", _) => ("i386", "i686"),
+        ("arm", true) => ("armhf", "armv6hl"),
+        ("arm", false) => ("armel", "armv6l"),
+        ("armv5te", false) => ("armel", "armv5tel"),
+        ("armv7", true) => ("armhf", "armv7hl"),
+        ("armv7", false) => ("armel", "armv7l"),
+        ("powerpc64le", _) => ("ppc64el", "ppc64le"),
+        ("powerpc64", _) => ("ppc64", "ppc64"),
+        ("s390x", _) => ("s390x", "s390x"),
+        ("riscv64gc", _) => ("riscv64", "riscv64"),
+        ("loongarch64", _) => ("loong64", "loongarch64"),
+        ("mips64el", _) => ("mips64el", "mips64el"),
+        _ => return None,
+    };
+    Some(architectures)
+}
+
+// Lay out the staged bundle `bundle_dir` in `<dest>.root` the way the system package `dest`
+// installs it, replacing what a previous run left there. Returns the root.
+pub fn stage_package_root(bundle_dir: &Path, dest: &Path, metadata: &BundleMetadata) -> Result<PathBuf> {
+    let root = dest.with_extension("root");
+    let _ = fs::remove_dir_all(&root);
+    install_tree(bundle_dir, &root, &package_prefix(metadata))?;
+    Ok(root)
+}
+
+// Lay out the staged bundle `bundle_dir` below `root` the way install.sh installs it into the
+// absolute `prefix`, records under `lib/rustlib` included. Files are hard linked when possible.
+pub fn install_tree(bundle_dir: &Path, root: &Path, prefix: &Path) -> Result<()> {
+    let dest = root.join(prefix.strip_prefix("/").unwrap_or(prefix));
+    let rustlib = dest.join("lib/rustlib");
+    fs::create_dir_all(&rustlib)?;
+
+    let components = fs::read_to_string(bundle_dir.join("components")).context("bundle has no components file")?;
+    let components: Vec<&str> = components.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+    for component in &components {
+        let manifest = bundle_dir.join(component).join("manifest.in");
+        let manifest = fs::read_to_string(&manifest).with_context(|| format!("failed to read {}", manifest.display()))?;
+        let mut record = String::new();
+        for entry in parse_component_manifest(&manifest)? {
+            let (kind, path) = match entry {
+                ManifestEntry::File(path) => ("file", path),
+                ManifestEntry::Dir(path) => ("dir", path),
+            };
+            let source = bundle_dir.join(component).join(&path);
+            if kind == "dir" {
+                link_tree(&source, &dest.join(&path))?;
+            } else {
+                link_file(&source, &dest.join(&path))?;
+            }
+            record.push_str(&format!("{}:{}\n", kind, prefix.join(&path).display()));
+        }
+        fs::write(rustlib.join(format!("manifest-{}", component)), record)?;
+    }
+    fs::write(rustlib.join("components"), components.iter().map(|component| format!("{}\n", component)).collect::<String>())?;
+    fs::write(rustlib.join("rust-installer-version"), "3\n")?;
+    Ok(())
+}
+
+fn link_tree(source: &Path, dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    for rel_path in archive::sorted_entries(source)? {
+        let path = source.join(&rel_path);
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.file_type().is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(fs::read_link(&path)?, dest.join(&rel_path))?;
+        } else if metadata.is_dir() {
+            fs::create_dir_all(dest.join(&rel_path))?;
+        } else {
+            link_file(&path, &dest.join(&rel_path))?;
+        }
+    }
+    Ok(())
+}
+
+fn link_file(source: &Path, dest: &Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::hard_link(source, dest).is_err() {
+        fs::copy(source, dest).with_context(|| format!("failed to copy {}", source.display()))?;
+    }
+    Ok(())
+}
+
+// Maintainer script registering the packaged toolchain with rustup after install
+pub fn rustup_link_script(metadata: &BundleMetadata) -> String {
+    render(RUSTUP_LINK_TEMPLATE, metadata).replace("@PREFIX@", &utils::shell_quote(&package_prefix(metadata).display().to_string()))
+}
+
+// Maintainer script unregistering the packaged toolchain from rustup before removal
+pub fn rustup_unlink_script(metadata: &BundleMetadata) -> String {
+    render(RUSTUP_UNLINK_TEMPLATE, metadata)
+}
+
+fn render_wrapper(bundle_name: &str, sha256: &str, metadata: &BundleMetadata) -> String {
+    render(WRAPPER_TEMPLATE, metadata)
+        .replace("@BUNDLE@", &utils::shell_quote(bundle_name))
+        .replace("@SHA256@", &utils::shell_quote(sha256))
+}
+
+// The script part of a self-extracting installer. The payload offset is the length of the
+// script itself, which depends on how many digits the offset has.
+fn render_sfx(payload_sha256: &str, metadata: &BundleMetadata) -> String {
+    let template = render(SFX_TEMPLATE, metadata).replace("@PAYLOAD_SHA256@", &utils::shell_quote(payload_sha256));
+    let mut offset = 0;
+    loop {
+        let header = template.replace("@PAYLOAD_OFFSET@", &offset.to_string());
+        if header.len() == offset {
+            return header;
+        }
+        offset = header.len();
+    }
+}
+
+fn render(template: &str, metadata: &BundleMetadata) -> String {
+    template
+        .replace("@POST_INSTALL@", POST_INSTALL)
+        .replace("@RUSTUP_AS_USER@", RUSTUP_AS_USER)
+        .replace("@TOOLCHAIN_NAME@", &metadata.toolchain)
+        .replace("@TOOLCHAIN@", &utils::shell_quote(&metadata.toolchain.to_lowercase()))
+        .replace("@COMPONENTS@", &utils::shell_quote(&metadata.components.join(" ")))
+        .replace("@GENERATOR@", &metadata.generator)
+}
+
+fn write_script(path: &Path, content: &str) -> Result<()> {
+    fs::write(path, content).with_context(|| format!("failed to write {}", path.display()))?;
+    set_executable(path)
+}
+
+fn set_executable(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{bundle_metadata, linux_target, temp_dir, write_files};
+    use crate::{archive, metadata};
+
+    fn stage(root: &Path) -> PathBuf {
+        let dir = root.join("rust-nightly-x86_64-unknown-linux-gnu");
+        write_files(
+            &dir,
+            &[
+                ("components", "rustc\ngrcov-0.8.18\n"),
+                ("rustc/manifest.in", "file:bin/rustc\ndir:share/doc/rust\n"),
+                ("rustc/bin/rustc", "rustc"),
+                ("rustc/share/doc/rust/README.md", "readme"),
+                ("grcov-0.8.18/manifest.in", "file:bin/grcov\n"),
+                ("grcov-0.8.18/bin/grcov", "grcov"),
+            ],
+        );
+        dir
+    }
+
+    #[test]
+    fn test_render_wrapper() {
+        let root = temp_dir("rustup-distribution-test-render-wrapper");
+        let metadata = bundle_metadata(&stage(&root), linux_target("nightly", None), &[]);
+
+        let wrapper = render_wrapper("rust-nightly-x86_64-unknown-linux-gnu.tar.gz", "abc123", &metadata);
+        assert!(wrapper.contains("BUNDLE='rust-nightly-x86_64-unknown-linux-gnu.tar.gz'\n"));
+        assert!(wrapper.contains("SHA256='abc123'\n"));
+        assert!(wrapper.contains("TOOLCHAIN='nightly-x86_64-unknown-linux-gnu'\n"));
+        assert!(wrapper.contains("COMPONENTS='rustc grcov-0.8.18'\n"));
+        assert!(!wrapper.contains('@'));
+
+        let sfx = render_sfx("abc123", &metadata);
+        assert!(sfx.contains(&format!("PAYLOAD_OFFSET={}\n", sfx.len())));
+        assert!(sfx.ends_with("exit 0\n"));
+        assert!(has_wrapper("aarch64-apple-darwin"));
+        assert!(!has_wrapper("x86_64-pc-windows-msvc"));
+        assert_eq!(
+            wrapper_path(Path::new("/out/rust-nightly-x86_64-unknown-linux-gnu.tar.gz")),
+            Path::new("/out/rust-nightly-x86_64-unknown-linux-gnu-install.sh")
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // Runs install.sh with the system shell
+    #[cfg(unix)]
+    #[test]
+    fn test_install_script_options() {
+        use std::process::Command;
+
+        let root = temp_dir("rustup-distribution-test-install-options");
+        let dir = stage(&root);
+        write_install_script(&dir).unwrap();
+        let install = |args: &[&str]| Command::new("sh").arg(dir.join(INSTALL_SCRIPT_FILENAME)).args(args).status().unwrap().success();
+
+        // staged below a destdir, recorded with the final paths
+        let destdir = root.join("destdir");
+        let destdir_arg = format!("--destdir={}", destdir.display());
+        assert!(install(&["--prefix=/opt/rust", &destdir_arg, "--without=grcov-0.8.18"]));
+        assert_eq!(fs::read_to_string(destdir.join("opt/rust/bin/rustc")).unwrap(), "rustc");
+        assert!(!destdir.join("opt/rust/bin/grcov").exists());
+        assert_eq!(fs::read_to_string(destdir.join("opt/rust/lib/rustlib/components")).unwrap(), "rustc\n");
+        assert_eq!(fs::read_to_string(destdir.join("opt/rust/lib/rustlib/manifest-rustc")).unwrap().lines().next(), Some("file:/opt/rust/bin/rustc"));
+        assert!(!install(&["--prefix=relative", &destdir_arg]));
+
+        let prefix = root.join("prefix");
+        let prefix_arg = format!("--prefix={}", prefix.display());
+        assert!(install(&[&prefix_arg, "--components=grcov-0.8.18", "--disable-ldconfig"]));
+        assert!(prefix.join("bin/grcov").exists());
+        assert!(!prefix.join("bin/rustc").exists());
+        assert!(install(&[&prefix_arg, "--disable-ldconfig"]));
+        // reinstalling does not list a component twice
+        assert_eq!(fs::read_to_string(prefix.join("lib/rustlib/components")).unwrap(), "rustc\ngrcov-0.8.18\n");
+
+        assert!(install(&[&prefix_arg, "--uninstall", "--components=rustc"]));
+        assert!(!prefix.join("bin/rustc").exists());
+        assert!(prefix.join("bin/grcov").exists());
+        assert!(install(&[&prefix_arg, "--uninstall"]));
+        assert!(!prefix.join("bin/grcov").exists());
+        assert!(!prefix.join("lib/rustlib/components").exists());
+        assert!(!install(&[&prefix_arg, "--uninstall"]));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // Runs the generated scripts with the system shell
+    #[cfg(unix)]
+    #[test]
+    fn test_install_bundle_with_wrapper() {
+        use std::process::Command;
+
+        let root = temp_dir("rustup-distribution-test-install-wrapper");
+        let dir = stage(&root);
+        write_install_script(&dir).unwrap();
+        let metadata = bundle_metadata(&dir, linux_target("nightly", None), &[]);
+        let bundle = root.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz");
+        archive::write_reproducible_tar_gz(&dir, &bundle).unwrap();
+        let sha256 = metadata::write_checksum_file(&bundle).unwrap();
+        let wrapper = write_wrapper(&bundle, &sha256, &metadata).unwrap();
+
+        let prefix = root.join("prefix");
+        let status = Command::new(&wrapper)
+            .arg(format!("--prefix={}", prefix.display()))
+            .args(["--no-rustup", "--disable-ldconfig"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(fs::read_to_string(prefix.join("bin/grcov")).unwrap(), "grcov");
+        assert_eq!(fs::read_to_string(prefix.join("share/doc/rust/README.md")).unwrap(), "readme");
+        assert_eq!(fs::read_to_string(prefix.join("lib/rustlib/components")).unwrap(), "rustc\ngrcov-0.8.18\n");
+        assert_eq!(
+            fs::read_to_string(prefix.join("lib/rustlib/manifest-grcov-0.8.18")).unwrap(),
+            format!("file:{}\n", prefix.join("bin/grcov").display())
+        );
+
+        let uninstall = uninstall_record(&prefix, &metadata.toolchain);
+        assert!(Command::new(&uninstall).status().unwrap().success());
+        assert!(!prefix.join("bin/rustc").exists());
+        assert!(!prefix.join("share/doc/rust").exists());
+        assert!(!prefix.join("lib/rustlib/components").exists());
+        assert!(!uninstall.exists());
+
+        // the self-extracting installer carries the same bundle
+        let sfx = root.join("rust-nightly-x86_64-unknown-linux-gnu.run");
+        write_self_extracting(&bundle, &sfx, &metadata).unwrap();
+        let output = Command::new(&sfx).arg("--list").output().unwrap();
+        assert!(String::from_utf8_lossy(&output.stdout).lines().any(|line| line.trim_start_matches("./") == "grcov-0.8.18/bin/grcov"));
+        assert!(Command::new(&sfx).arg("--verify").status().unwrap().success());
+        let status =
+            Command::new(&sfx).args(["--prefix", &prefix.display().to_string(), "--no-rustup", "--disable-ldconfig"]).status().unwrap();
+        assert!(status.success());
+        assert_eq!(fs::read_to_string(prefix.join("bin/rustc")).unwrap(), "rustc");
+        assert!(Command::new(&uninstall).status().unwrap().success());
+
+        let mut corrupted = fs::read(&sfx).unwrap();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        fs::write(&sfx, corrupted).unwrap();
+        assert!(!Command::new(&sfx).arg("--verify").status().unwrap().success());
+
+        // a tampered bundle is not installed
+        fs::write(&bundle, "tampered").unwrap();
+        let status = Command::new(&wrapper).arg(format!("--prefix={}", prefix.display())).arg("--no-rustup").status().unwrap();
+        assert!(!status.success());
+        assert!(!prefix.join("bin/rustc").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // Runs the maintainer scripts with the system shell, against a rustup recording its arguments
+    #[cfg(unix)]
+    #[test]
+    fn test_rustup_link_scripts() {
+        use std::process::Command;
+
+        let root = temp_dir("rustup-distribution-test-rustup-link");
+        let metadata = bundle_metadata(&stage(&root), linux_target("nightly", None), &[]);
+        let bin = root.join("bin");
+        write_files(&bin, &[("rustup", "#!/bin/sh\necho \"$*\" >>\"$(dirname \"$0\")/calls\"\n")]);
+        set_executable(&bin.join("rustup")).unwrap();
+        let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+        let run = |script: &str, arg: &str| {
+            let output = Command::new("sh").arg("-c").arg(script).arg("script").arg(arg).env("PATH", &path).env_remove("SUDO_USER").output().unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        let stdout = run(&rustup_link_script(&metadata), "configure");
+        assert!(stdout.contains("as dist-nightly-x86_64-unknown-linux-gnu"));
+        assert!(stdout.contains("rustup toolchain link dist-nightly-x86_64-unknown-linux-gnu /opt/rust/nightly-x86_64-unknown-linux-gnu\n"));
+        run(&rustup_unlink_script(&metadata), "upgrade");
+        run(&rustup_unlink_script(&metadata), "remove");
+        assert_eq!(
+            fs::read_to_string(bin.join("calls")).unwrap(),
+            "toolchain link dist-nightly-x86_64-unknown-linux-gnu /opt/rust/nightly-x86_64-unknown-linux-gnu\n\
+             toolchain uninstall dist-nightly-x86_64-unknown-linux-gnu\n"
+        );
+
+        // without rustup, the command to register it is printed. `id` is not found either then.
+        fs::remove_file(bin.join("rustup")).unwrap();
+        let stdout = Command::new("/bin/sh").arg("-c").arg(rustup_link_script(&metadata)).env("PATH", &bin).env_remove("SUDO_USER").output().unwrap().stdout;
+        assert!(String::from_utf8(stdout).unwrap().starts_with("rustup not found, register the toolchain with: rustup toolchain link "));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
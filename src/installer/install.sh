@@ -0,0 +1,162 @@
+#!/bin/sh
+# Installs every component listed in `components` into a prefix, reading the same
+# `manifest.in` files as rust-installer, and records what each one installed under
+# `<prefix>/lib/rustlib`. Takes the options of rust-installer's install.sh it has a use for.
+# Written into the bundle by rustup-distribution.
+set -eu
+
+usage() {
+    echo "Usage: install.sh [--prefix=DIR] [--destdir=DIR] [--components=LIST] [--without=LIST] [--disable-ldconfig] [--uninstall]"
+    echo "Install the components of this bundle into DIR, /usr/local by default"
+    echo "  --destdir=DIR       stage the install below DIR, as a package build does"
+    echo "  --components=LIST   only install these comma separated components"
+    echo "  --without=LIST      do not install these comma separated components"
+    echo "  --disable-ldconfig  do not run ldconfig after installing on Linux"
+    echo "  --uninstall         remove the installed components instead"
+}
+
+fail() {
+    echo "install.sh: $*" >&2
+    exit 1
+}
+
+prefix=/usr/local
+destdir=
+only=
+without=
+ldconfig=yes
+mode=install
+while [ $# -gt 0 ]; do
+    case $1 in
+        --prefix=* | --destdir=* | --components=* | --without=*)
+            option=${1%%=*}
+            value=${1#*=}
+            ;;
+        --prefix | --destdir | --components | --without)
+            [ $# -gt 1 ] || { usage >&2; exit 1; }
+            option=$1
+            shift
+            value=$1
+            ;;
+        --disable-ldconfig) option= ldconfig=no ;;
+        --uninstall) option= mode=uninstall ;;
+        -h | --help)
+            usage
+            exit 0
+            ;;
+        *)
+            echo "install.sh: unknown option $1" >&2
+            usage >&2
+            exit 1
+            ;;
+    esac
+    case $option in
+        --prefix) prefix=$value ;;
+        --destdir) destdir=${value%/} ;;
+        --components) only=$value ;;
+        --without) without=$value ;;
+    esac
+    shift
+done
+
+# Whether `component` is picked by --components and not excluded by --without
+selected() {
+    if [ -n "$only" ]; then
+        case ",$only," in
+            *",$1,"*) ;;
+            *) return 1 ;;
+        esac
+    fi
+    case ",$without," in
+        *",$1,"*) return 1 ;;
+    esac
+    return 0
+}
+
+# Remove what the record of `component` lists, below the destdir if any
+remove_component() {
+    record="$rustlib/manifest-$1"
+    [ -f "$record" ] || return 0
+    while IFS= read -r entry || [ -n "$entry" ]; do
+        case $entry in
+            file:*) rm -f "$destdir${entry#file:}" ;;
+            dir:*) rm -rf "$destdir${entry#dir:}" ;;
+        esac
+    done <"$record"
+    rm -f "$record"
+    if [ -f "$rustlib/components" ]; then
+        grep -vxF "$1" "$rustlib/components" >"$rustlib/components.new" || true
+        mv "$rustlib/components.new" "$rustlib/components"
+    fi
+}
+
+# Records hold the final paths, without the destdir
+if [ -n "$destdir" ]; then
+    case $prefix in
+        /*) ;;
+        *) fail "--prefix must be absolute with --destdir" ;;
+    esac
+    prefix=${prefix%/}
+elif [ "$mode" = install ]; then
+    mkdir -p "$prefix"
+    prefix=$(cd "$prefix" && pwd)
+else
+    [ -d "$prefix" ] || fail "nothing is installed in $prefix"
+    prefix=$(cd "$prefix" && pwd)
+fi
+rustlib="$destdir$prefix/lib/rustlib"
+
+if [ "$mode" = uninstall ]; then
+    [ -f "$rustlib/components" ] || fail "nothing is installed in $prefix"
+    for component in $(cat "$rustlib/components"); do
+        selected "$component" || continue
+        echo "install: uninstalling component '$component'"
+        remove_component "$component"
+    done
+    if [ ! -s "$rustlib/components" ]; then
+        rm -f "$rustlib/components" "$rustlib/rust-installer-version"
+    fi
+    echo "install: uninstalled from $prefix"
+    exit 0
+fi
+
+src=$(cd "$(dirname "$0")" && pwd)
+mkdir -p "$rustlib"
+
+while IFS= read -r component || [ -n "$component" ]; do
+    [ -n "$component" ] || continue
+    selected "$component" || continue
+    # files a previous version of the component installed and this one does not are dropped
+    remove_component "$component"
+    echo "install: installing component '$component'"
+    record="$rustlib/manifest-$component"
+    : >"$record"
+    while IFS= read -r entry || [ -n "$entry" ]; do
+        [ -n "$entry" ] || continue
+        path=${entry#*:}
+        case $entry in
+            file:*)
+                mkdir -p "$(dirname "$destdir$prefix/$path")"
+                cp -pf "$src/$component/$path" "$destdir$prefix/$path"
+                ;;
+            dir:*)
+                mkdir -p "$destdir$prefix/$path"
+                cp -pRf "$src/$component/$path/." "$destdir$prefix/$path"
+                ;;
+            *)
+                echo "install.sh: invalid entry '$entry' in $component/manifest.in" >&2
+                exit 1
+                ;;
+        esac
+        echo "${entry%%:*}:$prefix/$path" >>"$record"
+    done <"$src/$component/manifest.in"
+    echo "$component" >>"$rustlib/components"
+done <"$src/components"
+echo 3 >"$rustlib/rust-installer-version"
+
+# Like rust-installer, refresh the dynamic linker cache so the installed libraries are found
+if [ "$ldconfig" = yes ] && [ -z "$destdir" ] && [ "$(uname -s)" = Linux ] && command -v ldconfig >/dev/null 2>&1; then
+    ldconfig || echo "install.sh: warning: failed to run ldconfig, this may happen when not installing as root" >&2
+fi
+
+echo "install: installed to $prefix"
@@ -0,0 +1,50 @@
+# Register the installed toolchain with rustup, when asked and available, and leave a
+# script removing it again. Shared by the standalone and self-extracting installers.
+linked=
+if [ "$register" = yes ] && command -v rustup >/dev/null 2>&1; then
+    if rustup toolchain link "$rustup_name" "$prefix"; then
+        linked=$rustup_name
+        echo "Registered with rustup as $rustup_name, eg: cargo +$rustup_name build"
+    else
+        echo "warning: could not register $prefix with rustup" >&2
+    fi
+fi
+
+uninstall="$prefix/lib/rustlib/uninstall-$TOOLCHAIN.sh"
+{
+    echo "#!/bin/sh"
+    echo "# Removes $TOOLCHAIN from $prefix, written by $(basename "$0")"
+    echo "COMPONENTS='$COMPONENTS'"
+    echo "RUSTUP_NAME='$linked'"
+    cat <<'UNINSTALL'
+set -eu
+
+rustlib=$(cd "$(dirname "$0")" && pwd)
+for component in $COMPONENTS; do
+    record="$rustlib/manifest-$component"
+    [ -f "$record" ] || continue
+    while IFS= read -r entry || [ -n "$entry" ]; do
+        case $entry in
+            file:*) rm -f "${entry#file:}" ;;
+            dir:*) rm -rf "${entry#dir:}" ;;
+        esac
+    done <"$record"
+    rm -f "$record"
+    if [ -f "$rustlib/components" ]; then
+        grep -vxF "$component" "$rustlib/components" >"$rustlib/components.new" || true
+        mv "$rustlib/components.new" "$rustlib/components"
+    fi
+done
+if [ ! -s "$rustlib/components" ]; then
+    rm -f "$rustlib/components" "$rustlib/rust-installer-version"
+fi
+if [ -n "$RUSTUP_NAME" ] && command -v rustup >/dev/null 2>&1; then
+    rustup toolchain uninstall "$RUSTUP_NAME" || true
+fi
+rm -f "$0"
+echo "Uninstalled"
+UNINSTALL
+} >"$uninstall"
+chmod +x "$uninstall"
+
+echo "Installed $TOOLCHAIN into $prefix, remove it with $uninstall"
@@ -0,0 +1,13 @@
+# Package managers run maintainer scripts as root, where rustup is root's and registers into
+# root's RUSTUP_HOME. Under sudo, use the rustup of the user who ran sudo instead, found the
+# way their shell would. Returns 127 when there is no rustup to use.
+rustup_user=$(id -un)
+rustup_as_user() {
+    if [ "$(id -u)" = 0 ] && [ -n "${SUDO_USER:-}" ] && [ "$SUDO_USER" != root ]; then
+        rustup_user=$SUDO_USER
+        su "$SUDO_USER" -s /bin/sh -c 'PATH="$HOME/.cargo/bin:$PATH"; command -v rustup >/dev/null 2>&1 || exit 127; exec rustup "$@"' rustup "$@"
+    else
+        command -v rustup >/dev/null 2>&1 || return 127
+        rustup "$@"
+    fi
+}
@@ -0,0 +1,20 @@
+#!/bin/sh
+# Registers @TOOLCHAIN_NAME@ with rustup once its package is installed, when rustup is available.
+# Generated by @GENERATOR@.
+set -u
+
+TOOLCHAIN=@TOOLCHAIN@
+PREFIX=@PREFIX@
+rustup_name="dist-$TOOLCHAIN"
+
+@RUSTUP_AS_USER@
+rustup_as_user toolchain link "$rustup_name" "$PREFIX"
+case $? in
+    0)
+        echo "Registered with the rustup of $rustup_user as $rustup_name, eg: cargo +$rustup_name build"
+        echo "Other users register it with: rustup toolchain link $rustup_name $PREFIX"
+        ;;
+    127) echo "rustup not found, register the toolchain with: rustup toolchain link $rustup_name $PREFIX" ;;
+    *) echo "warning: could not register $PREFIX with rustup, run: rustup toolchain link $rustup_name $PREFIX" >&2 ;;
+esac
+exit 0
@@ -0,0 +1,15 @@
+#!/bin/sh
+# Unregisters @TOOLCHAIN_NAME@ from rustup before its package is removed. Upgrades keep it
+# registered: dpkg passes `upgrade`, rpm the number of versions left installed.
+# Generated by @GENERATOR@.
+set -u
+
+TOOLCHAIN=@TOOLCHAIN@
+rustup_name="dist-$TOOLCHAIN"
+
+case ${1:-} in
+    upgrade | failed-upgrade | [1-9]*) exit 0 ;;
+esac
+@RUSTUP_AS_USER@
+rustup_as_user toolchain uninstall "$rustup_name" >/dev/null 2>&1 || true
+exit 0
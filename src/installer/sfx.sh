@@ -0,0 +1,97 @@
+#!/bin/sh
+# Self-extracting installer of @TOOLCHAIN_NAME@. A gzipped tar of the bundle, with its
+# install.sh, follows this script from byte PAYLOAD_OFFSET on. Generated by @GENERATOR@.
+set -eu
+
+TOOLCHAIN=@TOOLCHAIN@
+COMPONENTS=@COMPONENTS@
+PAYLOAD_OFFSET=@PAYLOAD_OFFSET@
+PAYLOAD_SHA256=@PAYLOAD_SHA256@
+
+prefix=/usr/local
+rustup_name="dist-$TOOLCHAIN"
+register=yes
+ldconfig=
+action=install
+
+usage() {
+    echo "Usage: $0 [--prefix=DIR] [--rustup-name=NAME] [--no-rustup] [--list] [--verify] [--disable-ldconfig]"
+    echo "Install $TOOLCHAIN into DIR, /usr/local by default"
+    echo "  --rustup-name=NAME  register the toolchain with rustup as NAME, $rustup_name by default"
+    echo "  --no-rustup         do not register the toolchain with rustup"
+    echo "  --disable-ldconfig  do not run ldconfig after installing on Linux"
+    echo "  --list              list the files of the payload instead of installing them"
+    echo "  --verify            check the payload against its sha256 instead of installing it"
+}
+
+fail() {
+    echo "error: $*" >&2
+    exit 1
+}
+
+while [ $# -gt 0 ]; do
+    case $1 in
+        --prefix=*) prefix=${1#--prefix=} ;;
+        --prefix)
+            [ $# -gt 1 ] || fail "--prefix needs a directory"
+            shift
+            prefix=$1
+            ;;
+        --rustup-name=*) rustup_name=${1#--rustup-name=} ;;
+        --no-rustup) register=no ;;
+        --disable-ldconfig) ldconfig=--disable-ldconfig ;;
+        --list) action=list ;;
+        --verify) action=verify ;;
+        -h | --help)
+            usage
+            exit 0
+            ;;
+        *)
+            usage >&2
+            fail "unknown option $1"
+            ;;
+    esac
+    shift
+done
+case $rustup_name in
+    "" | *[!A-Za-z0-9._-]*) fail "invalid rustup toolchain name '$rustup_name'" ;;
+esac
+
+payload() {
+    tail -c +$((PAYLOAD_OFFSET + 1)) "$0"
+}
+
+verify() {
+    if command -v sha256sum >/dev/null 2>&1; then
+        actual=$(payload | sha256sum | cut -d ' ' -f 1)
+    elif command -v shasum >/dev/null 2>&1; then
+        actual=$(payload | shasum -a 256 | cut -d ' ' -f 1)
+    elif command -v openssl >/dev/null 2>&1; then
+        actual=$(payload | openssl dgst -sha256 -r | cut -d ' ' -f 1)
+    else
+        fail "sha256sum, shasum or openssl is needed to verify $0"
+    fi
+    [ "$actual" = "$PAYLOAD_SHA256" ] || fail "$0 is corrupted: expected sha256 $PAYLOAD_SHA256, found $actual"
+}
+
+case $action in
+    list)
+        payload | gzip -dc | tar -tf -
+        exit 0
+        ;;
+    verify)
+        verify
+        echo "Verified $0"
+        exit 0
+        ;;
+esac
+
+verify
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+payload | gzip -dc | (cd "$work" && tar -xf -)
+mkdir -p "$prefix"
+prefix=$(cd "$prefix" && pwd)
+sh "$work/install.sh" --prefix="$prefix" $ldconfig
+
+@POST_INSTALL@exit 0
@@ -0,0 +1,76 @@
+#!/bin/sh
+# Installs @BUNDLE@ from the same directory after checking its sha256, writes a script
+# removing it again and, when rustup is available, registers it as a rustup toolchain.
+# Generated by @GENERATOR@.
+set -eu
+
+BUNDLE=@BUNDLE@
+SHA256=@SHA256@
+TOOLCHAIN=@TOOLCHAIN@
+COMPONENTS=@COMPONENTS@
+
+prefix=/usr/local
+rustup_name="dist-$TOOLCHAIN"
+register=yes
+ldconfig=
+
+usage() {
+    echo "Usage: $0 [--prefix=DIR] [--rustup-name=NAME] [--no-rustup] [--disable-ldconfig]"
+    echo "Install $TOOLCHAIN into DIR, /usr/local by default"
+    echo "  --rustup-name=NAME  register the toolchain with rustup as NAME, $rustup_name by default"
+    echo "  --no-rustup         do not register the toolchain with rustup"
+    echo "  --disable-ldconfig  do not run ldconfig after installing on Linux"
+}
+
+fail() {
+    echo "error: $*" >&2
+    exit 1
+}
+
+while [ $# -gt 0 ]; do
+    case $1 in
+        --prefix=*) prefix=${1#--prefix=} ;;
+        --prefix)
+            [ $# -gt 1 ] || fail "--prefix needs a directory"
+            shift
+            prefix=$1
+            ;;
+        --rustup-name=*) rustup_name=${1#--rustup-name=} ;;
+        --no-rustup) register=no ;;
+        --disable-ldconfig) ldconfig=--disable-ldconfig ;;
+        -h | --help)
+            usage
+            exit 0
+            ;;
+        *)
+            usage >&2
+            fail "unknown option $1"
+            ;;
+    esac
+    shift
+done
+case $rustup_name in
+    "" | *[!A-Za-z0-9._-]*) fail "invalid rustup toolchain name '$rustup_name'" ;;
+esac
+
+bundle="$(cd "$(dirname "$0")" && pwd)/$BUNDLE"
+[ -f "$bundle" ] || fail "$BUNDLE is not next to $0"
+if command -v sha256sum >/dev/null 2>&1; then
+    actual=$(sha256sum "$bundle" | cut -d ' ' -f 1)
+elif command -v shasum >/dev/null 2>&1; then
+    actual=$(shasum -a 256 "$bundle" | cut -d ' ' -f 1)
+elif command -v openssl >/dev/null 2>&1; then
+    actual=$(openssl dgst -sha256 -r "$bundle" | cut -d ' ' -f 1)
+else
+    fail "sha256sum, shasum or openssl is needed to verify $BUNDLE"
+fi
+[ "$actual" = "$SHA256" ] || fail "$BUNDLE is corrupted: expected sha256 $SHA256, found $actual"
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+gzip -dc "$bundle" | (cd "$work" && tar -xf -)
+mkdir -p "$prefix"
+prefix=$(cd "$prefix" && pwd)
+sh "$work/install.sh" --prefix="$prefix" $ldconfig
+
+@POST_INSTALL@
\ No newline at end of file
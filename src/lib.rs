@@ -7,15 +7,63 @@ pub use crate::utils::*;
 mod config;
 pub use crate::config::*;
 
+mod archive;
+pub use crate::archive::*;
+
+mod component_manifest;
+pub use crate::component_manifest::*;
+
+mod credentials;
+pub use crate::credentials::*;
+
+mod deb;
+pub use crate::deb::*;
+
 mod download;
 pub use crate::download::*;
 
+mod installed;
+pub use crate::installed::*;
+
+mod installer;
+pub use crate::installer::*;
+
+mod logging;
+pub use crate::logging::*;
+
+mod lockfile;
+pub use crate::lockfile::*;
+
+mod manifest;
+pub use crate::manifest::*;
+
+mod metadata;
+pub use crate::metadata::*;
+
+mod plan;
+pub use crate::plan::*;
+
+mod report;
+pub use crate::report::*;
+
+mod rpm;
+pub use crate::rpm::*;
+
+mod runner;
+pub use crate::runner::*;
+
+mod sbom;
+pub use crate::sbom::*;
+
 mod subcommands;
 pub use crate::subcommands::*;
 
 mod backend;
 pub use crate::backend::*;
 
+#[cfg(test)]
+mod test_utils;
+
 pub enum Backend {
     Curl,
     Reqwest(TlsBackend),
@@ -0,0 +1,487 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    find_latest_available, utils, ChannelManifest, Config, DownloadCfg, ExtendTool, NetworkConfig, TargetSelection,
+    LATEST_AVAILABLE_MAX_DAYS,
+};
+
+pub const LOCKFILE_NAME: &str = "toolchain.lock";
+pub const LOCKFILE_VERSION: u32 = 1;
+
+const CRATES_IO_INDEX: &str = "https://index.crates.io";
+
+// Everything a `toolchain.yaml` resolved to: manifest dates and hashes for every
+// target, and the exact version of every extra tool.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lockfile {
+    pub version: u32,
+    #[serde(default, rename = "toolchain")]
+    pub toolchains: Vec<LockedToolchain>,
+    #[serde(default, rename = "tool")]
+    pub tools: Vec<LockedTool>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LockedToolchain {
+    // the selection as written in the configuration
+    pub target: String,
+    pub channel: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub staging: bool,
+    // what it resolved to
+    pub date: String,
+    pub manifest_url: String,
+    pub manifest_sha256: String,
+    #[serde(default, rename = "artifact")]
+    pub artifacts: Vec<LockedArtifact>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedArtifact {
+    pub name: String,
+    pub url: String,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LockedTool {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_version: Option<String>,
+    pub version: String,
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    vers: String,
+    #[serde(default)]
+    yanked: bool,
+}
+
+impl LockedToolchain {
+    fn matches(&self, target_selection: &TargetSelection) -> bool {
+        self.target == target_selection.target
+            && self.channel == target_selection.channel
+            && self.requested_date == target_selection.date
+            && self.profile == target_selection.profile
+            && self.components == target_selection.components
+            && self.targets == target_selection.targets
+            && self.staging == target_selection.staging
+    }
+
+    // The sha256 recorded for an artifact, eg: `rust`
+    pub fn artifact_sha256(&self, name: &str) -> Option<&str> {
+        self.artifacts.iter().find(|artifact| artifact.name == name).map(|artifact| artifact.sha256.as_str())
+    }
+
+    // Check a downloaded channel manifest against the sha256 recorded for it
+    pub fn verify_manifest(&self, sha256: &str) -> Result<()> {
+        if self.manifest_sha256 != sha256 {
+            bail!(
+                "{} does not match {}: expected sha256 {}, found {}",
+                utils::redact_url_str(&self.manifest_url),
+                LOCKFILE_NAME,
+                self.manifest_sha256,
+                sha256
+            );
+        }
+        Ok(())
+    }
+}
+
+impl LockedTool {
+    fn matches(&self, tool: &ExtendTool) -> bool {
+        self.name == tool.name && self.requested_version == tool.version
+    }
+}
+
+impl Lockfile {
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let lockfile = toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))?;
+        Ok(Some(lockfile))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let content = format!(
+            "# This file is automatically generated by {}.\n# It is not intended for manual editing.\n{}",
+            env!("CARGO_PKG_NAME"),
+            toml::to_string_pretty(self)?
+        );
+        fs::write(path, content).with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    // Resolve every target and tool of `config`. Entries of `existing` still matching the
+    // configuration are kept as they are, without going to the network, and only the others
+    // are resolved upstream. In locked mode nothing may be resolved: every target and tool
+    // must already be in `existing`.
+    pub fn resolve(config: &Config, existing: Option<&Lockfile>, locked: bool, download_dir: &Path) -> Result<Self> {
+        if locked && existing.is_none() {
+            bail!("`--locked` was passed but {} does not exist", LOCKFILE_NAME);
+        }
+
+        let mut toolchains = Vec::new();
+        for target_selection in &config.target_selections {
+            let existing_entry = existing.and_then(|lockfile| lockfile.toolchain(target_selection));
+            let resolved = match existing_entry {
+                Some(entry) => entry.clone(),
+                None if locked => bail!(
+                    "{} is not in {}, run without `--locked` to update it",
+                    target_selection.toolchain_name(),
+                    LOCKFILE_NAME
+                ),
+                None => {
+                    let mut pinned = target_selection.clone();
+                    if target_selection.is_latest_available() {
+                        pinned.locked_date =
+                            Some(find_latest_available(target_selection, config, download_dir, LATEST_AVAILABLE_MAX_DAYS)?);
+                    }
+                    resolve_toolchain(target_selection, &pinned, config, download_dir)?
+                }
+            };
+            toolchains.push(resolved);
+        }
+
+        let mut tools = Vec::new();
+        for tool in &config.extra_tools {
+            let existing_entry = existing.and_then(|lockfile| lockfile.tools.iter().find(|entry| entry.matches(tool)));
+            let resolved = match existing_entry {
+                Some(entry) => entry.clone(),
+                None if locked => bail!("{} is not in {}, run without `--locked` to update it", tool.crate_name(), LOCKFILE_NAME),
+                None => LockedTool {
+                    name: tool.name.clone(),
+                    requested_version: tool.version.clone(),
+                    version: resolve_tool_version(tool, &config.network, download_dir)?,
+                },
+            };
+            tools.push(resolved);
+        }
+
+        Ok(Lockfile { version: LOCKFILE_VERSION, toolchains, tools })
+    }
+
+    // The configuration with every toolchain downloaded from the date it was locked to, under
+    // its configured name, and every tool pinned to its exact version.
+    pub fn pin(&self, config: &Config) -> Config {
+        let mut pinned = config.clone();
+        for target_selection in pinned.target_selections.iter_mut() {
+            if let Some(entry) = self.toolchain(target_selection) {
+                target_selection.locked_date = Some(entry.date.clone());
+            }
+        }
+        for tool in pinned.extra_tools.iter_mut() {
+            if let Some(entry) = self.tools.iter().find(|entry| entry.matches(tool)) {
+                tool.version = Some(entry.version.clone());
+            }
+        }
+        pinned
+    }
+
+    // Whether every target and tool of `config` has an entry, so resolving it needs no network
+    pub fn covers(&self, config: &Config) -> bool {
+        config.target_selections.iter().all(|target_selection| self.toolchain(target_selection).is_some())
+            && config.extra_tools.iter().all(|tool| self.tools.iter().any(|entry| entry.matches(tool)))
+    }
+
+    // Whether resolving `config` would leave this lock file as it is
+    pub fn is_current(&self, config: &Config) -> bool {
+        self.toolchains.len() == config.target_selections.len()
+            && self.tools.len() == config.extra_tools.len()
+            && self.toolchains.iter().zip(&config.target_selections).all(|(entry, target_selection)| entry.matches(target_selection))
+            && self.tools.iter().zip(&config.extra_tools).all(|(entry, tool)| entry.matches(tool))
+    }
+
+    // Fail when a floating channel of `config` has been published again since it was locked.
+    // rustup installs such a channel as it is published now, not from the locked date, so
+    // `install --locked` must not go ahead. Dated toolchains cannot move and are not fetched.
+    pub fn check_floating_channels(&self, config: &Config, download_dir: &Path) -> Result<()> {
+        for target_selection in config.target_selections.iter().filter(|target_selection| target_selection.date.is_none()) {
+            let Some(entry) = self.toolchain(target_selection) else {
+                continue;
+            };
+            let mut current = target_selection.clone();
+            current.locked_date = None;
+            let (manifest, manifest_sha256) =
+                ChannelManifest::fetch(&current, &config.rustup_dist_server, &config.network, download_dir)?;
+            if manifest.date != entry.date || manifest_sha256 != entry.manifest_sha256 {
+                bail!(
+                    "{} is published for {} now but {} locks it to {}, rustup would not install the locked toolchain. \
+                     Delete its entry and run without `--locked` to update it",
+                    target_selection.toolchain_name(),
+                    manifest.date,
+                    LOCKFILE_NAME,
+                    entry.date
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // The entry a selection, pinned or not, was resolved to
+    pub fn toolchain(&self, target_selection: &TargetSelection) -> Option<&LockedToolchain> {
+        self.toolchains.iter().find(|entry| entry.matches(target_selection))
+    }
+}
+
+// Resolve `config` against the lock file next to it, write the lock file when entries were
+// added or dropped, and return the pinned configuration used by `package` and `install`
+// along with the lock it was pinned by.
+pub fn lock_config(config: &Config, lockfile_path: &Path, locked: bool) -> Result<(Config, Lockfile)> {
+    let (lockfile, changed) = resolve_lockfile(config, lockfile_path, locked)?;
+
+    if changed {
+        lockfile.write(lockfile_path)?;
+        info!(path = %lockfile_path.display(), "Updated {}", LOCKFILE_NAME);
+    }
+
+    Ok((lockfile.pin(config), lockfile))
+}
+
+// Resolve `config` against the lock file next to it without writing anything, and tell
+// whether the lock file would have to be updated. Only what the lock file lacks is resolved
+// over the network.
+pub fn resolve_lockfile(config: &Config, lockfile_path: &Path, locked: bool) -> Result<(Lockfile, bool)> {
+    let existing = Lockfile::load(lockfile_path)?;
+    let lockfile = Lockfile::resolve(config, existing.as_ref(), locked, &lock_download_dir())?;
+
+    let changed = !locked && existing.as_ref() != Some(&lockfile);
+    Ok((lockfile, changed))
+}
+
+// Where manifests and crate indexes are fetched to while locking
+pub fn lock_download_dir() -> PathBuf {
+    env::temp_dir().join(format!("{}-lock", env!("CARGO_PKG_NAME")))
+}
+
+fn resolve_toolchain(
+    requested: &TargetSelection,
+    pinned: &TargetSelection,
+    config: &Config,
+    download_dir: &Path,
+) -> Result<LockedToolchain> {
+    let dist_root = format!("{}/dist", config.rustup_dist_server);
+    let (manifest, manifest_sha256) =
+        ChannelManifest::fetch(pinned, &config.rustup_dist_server, &config.network, download_dir)?;
+
+    // `rust` is the combined installer `package` downloads, the profile and extra components are what `install` downloads
+    let mut names = vec![("rust".to_string(), requested.target.clone())];
+    names.extend(manifest.requested_components(requested));
+
+    let mut artifacts = Vec::new();
+    for (name, target) in names {
+        let artifact = manifest
+            .package(&name)
+            .and_then(|pkg| pkg.for_target(&target))
+            .filter(|manifest_target| manifest_target.available);
+        if let Some(manifest_target) = artifact {
+            artifacts.push(LockedArtifact {
+                name: if target == requested.target { name } else { format!("{}-{}", name, target) },
+                url: manifest_target.url.clone().unwrap_or_default(),
+                sha256: manifest_target.hash.clone().unwrap_or_default(),
+            });
+        }
+    }
+
+    // Always record the dated manifest, which stays available once the channel moves on
+    let mut dated = requested.clone();
+    dated.locked_date = Some(manifest.date.clone());
+
+    Ok(LockedToolchain {
+        target: requested.target.clone(),
+        channel: requested.channel.clone(),
+        requested_date: requested.date.clone(),
+        profile: requested.profile.clone(),
+        components: requested.components.clone(),
+        targets: requested.targets.clone(),
+        staging: requested.staging,
+        date: manifest.date,
+        manifest_url: utils::redact_url_str(&dated.mainifest_v2_url(&dist_root)),
+        manifest_sha256,
+        artifacts,
+    })
+}
+
+// Sparse index path of a crate, see https://doc.rust-lang.org/cargo/reference/registry-index.html#index-files
+fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+// The version `cargo install` would pick: an exact version as is, otherwise the
+// newest non-yanked version matching the requirement.
+fn resolve_tool_version(tool: &ExtendTool, network: &NetworkConfig, download_dir: &Path) -> Result<String> {
+    if let Some(version) = tool.version.as_deref().and_then(|version| semver::Version::parse(version).ok()) {
+        return Ok(version.to_string());
+    }
+
+    let index = env::var("CARGO_REGISTRIES_CRATES_IO_INDEX")
+        .map(|index| index.trim_start_matches("sparse+").trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| CRATES_IO_INDEX.to_string());
+    let download_cfg = DownloadCfg {
+        dist_root: format!("{}/{}", index, index_path(&tool.name)),
+        download_dir: download_dir.to_path_buf(),
+        network: network.clone(),
+    };
+    let file = download_cfg.download(&format!("{}.index", tool.name))?;
+    let content = fs::read_to_string(&file.path).context("reading crate index")?;
+    fs::remove_file(&file.path).context("cleaning up crate index")?;
+
+    select_version(&content, tool.version.as_deref())
+        .ok_or_else(|| anyhow!("no published version of '{}' matches the requested version", tool.crate_name()))
+}
+
+fn select_version(index_content: &str, requirement: Option<&str>) -> Option<String> {
+    let requirement = match requirement {
+        Some(requirement) => semver::VersionReq::parse(requirement).ok()?,
+        None => semver::VersionReq::STAR,
+    };
+
+    index_content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<IndexEntry>(line).ok())
+        .filter(|entry| !entry.yanked)
+        .filter_map(|entry| semver::Version::parse(&entry.vers).ok())
+        .filter(|version| requirement.matches(version))
+        .max()
+        .map(|version| version.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_path() {
+        assert_eq!(index_path("a"), "1/a");
+        assert_eq!(index_path("cc"), "2/cc");
+        assert_eq!(index_path("syn"), "3/s/syn");
+        assert_eq!(index_path("Grcov"), "gr/co/grcov");
+    }
+
+    #[test]
+    fn test_select_version() {
+        let index = r#"{"name":"grcov","vers":"0.8.17","yanked":false}
+{"name":"grcov","vers":"0.8.18","yanked":false}
+{"name":"grcov","vers":"0.8.19","yanked":true}
+{"name":"grcov","vers":"0.9.0-beta.1","yanked":false}"#;
+        assert_eq!(select_version(index, None).as_deref(), Some("0.8.18"));
+        assert_eq!(select_version(index, Some("~0.8.17")).as_deref(), Some("0.8.18"));
+        assert_eq!(select_version(index, Some("<0.8.18")).as_deref(), Some("0.8.17"));
+        assert_eq!(select_version(index, Some("1")), None);
+    }
+
+    #[test]
+    fn test_lockfile_roundtrip_and_pin() {
+        let lockfile = Lockfile {
+            version: LOCKFILE_VERSION,
+            toolchains: vec![LockedToolchain {
+                target: "x86_64-unknown-linux-gnu".to_string(),
+                channel: "nightly".to_string(),
+                requested_date: None,
+                profile: Some("minimal".to_string()),
+                components: Vec::new(),
+                targets: Vec::new(),
+                staging: false,
+                date: "2023-06-25".to_string(),
+                manifest_url: "https://static.rust-lang.org/dist/2023-06-25/channel-rust-nightly.toml".to_string(),
+                manifest_sha256: "aaaa".to_string(),
+                artifacts: vec![LockedArtifact {
+                    name: "rust".to_string(),
+                    url: "https://static.rust-lang.org/dist/2023-06-25/rust-nightly-x86_64-unknown-linux-gnu.tar.gz".to_string(),
+                    sha256: "bbbb".to_string(),
+                }],
+            }],
+            tools: vec![LockedTool { name: "grcov".to_string(), requested_version: None, version: "0.8.18".to_string() }],
+        };
+
+        let content = toml::to_string_pretty(&lockfile).unwrap();
+        assert!(content.contains("[[toolchain.artifact]]"));
+        assert_eq!(toml::from_str::<Lockfile>(&content).unwrap(), lockfile);
+
+        let config = Config {
+            target_selections: vec![TargetSelection {
+                target: "x86_64-unknown-linux-gnu".to_string(),
+                channel: "nightly".to_string(),
+                profile: Some("minimal".to_string()),
+                ..Default::default()
+            }],
+            extra_tools: vec![ExtendTool { name: "grcov".to_string(), version: None }],
+            ..Default::default()
+        };
+        let pinned = lockfile.pin(&config);
+        assert_eq!(pinned.target_selections[0].date, None);
+        assert_eq!(pinned.target_selections[0].toolchain_name(), "nightly-x86_64-unknown-linux-gnu");
+        assert_eq!(
+            pinned.target_selections[0].package_dir("https://static.rust-lang.org"),
+            "https://static.rust-lang.org/dist/2023-06-25"
+        );
+        assert_eq!(pinned.extra_tools[0].version.as_deref(), Some("0.8.18"));
+        assert!(lockfile.toolchain(&pinned.target_selections[0]).is_some());
+
+        // a lock covering the configuration is reused as is, without going to the network
+        let download_dir = env::temp_dir().join("rustup-distribution-test-lock-reuse");
+        let resolved = Lockfile::resolve(&config, Some(&lockfile), true, &download_dir).unwrap();
+        assert_eq!(resolved, lockfile);
+        assert!(!download_dir.exists());
+
+        assert!(lockfile.covers(&config) && lockfile.is_current(&config));
+
+        let mut missing = config.clone();
+        missing.target_selections[0].channel = "beta".to_string();
+        assert!(!lockfile.covers(&missing));
+        assert!(Lockfile::resolve(&missing, Some(&lockfile), true, &download_dir).is_err());
+
+        // an entry the configuration dropped is removed on the next write
+        let mut dropped = config.clone();
+        dropped.extra_tools.clear();
+        assert!(lockfile.covers(&dropped) && !lockfile.is_current(&dropped));
+
+        // a floating channel published again since it was locked is refused
+        let mirror = env::temp_dir().join("rustup-distribution-test-lock-floating");
+        let _ = fs::remove_dir_all(&mirror);
+        fs::create_dir_all(mirror.join("dist")).unwrap();
+        fs::write(mirror.join("dist/channel-rust-nightly.toml"), "date = \"2023-06-26\"\n").unwrap();
+        let mirrored = Config { rustup_dist_server: format!("file://{}", mirror.display()), ..lockfile.pin(&config) };
+        let err = lockfile.check_floating_channels(&mirrored, &mirror.join("download")).unwrap_err();
+        assert!(err.to_string().contains("nightly-x86_64-unknown-linux-gnu is published for 2023-06-26 now but toolchain.lock locks it to 2023-06-25"));
+        let mut current = lockfile.clone();
+        current.toolchains[0].date = "2023-06-26".to_string();
+        current.toolchains[0].manifest_sha256 = utils::sha256_file(&mirror.join("dist/channel-rust-nightly.toml")).unwrap();
+        assert!(current.check_floating_channels(&mirrored, &mirror.join("download")).is_ok());
+        // a dated toolchain is not fetched
+        let mut dated = mirrored.clone();
+        dated.target_selections[0].date = Some("2023-06-25".to_string());
+        assert!(lockfile.check_floating_channels(&dated, &mirror.join("download")).is_ok());
+        fs::remove_dir_all(&mirror).unwrap();
+
+        let entry = &lockfile.toolchains[0];
+        assert_eq!(entry.artifact_sha256("rust"), Some("bbbb"));
+        assert_eq!(entry.artifact_sha256("rust-src"), None);
+        assert!(entry.verify_manifest("aaaa").is_ok());
+        assert!(entry.verify_manifest("bbbb").is_err());
+    }
+}
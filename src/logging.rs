@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::fmt::{self, format::{DefaultFields, Writer}, FormatFields};
+use tracing_subscriber::prelude::*;
+
+// Target of the log lines carrying child process output
+pub const COMMAND_OUTPUT_TARGET: &str = "rustup_distribution::command";
+
+// Directives used when `RUST_LOG` is not set: dependencies only report warnings,
+// this crate logs at the level picked by `-v`/`-q`.
+fn default_directives(verbosity: i8) -> String {
+    let level = match verbosity {
+        i8::MIN..=-2 => "error",
+        -1 => "warn",
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    format!("warn,{}={}", env!("CARGO_CRATE_NAME"), level)
+}
+
+// Log to stderr, filtered by `RUST_LOG` or `verbosity` (the number of `-v` minus the
+// number of `-q`). With `log_file`, everything down to debug, child process output
+// included, is also written to that file regardless of the console filter.
+pub fn init_logging(verbosity: i8, log_file: Option<&Path>) -> Result<()> {
+    let console_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_directives(verbosity)));
+    let console = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .with_ansi(std::io::stderr().is_terminal())
+        .without_time()
+        .with_filter(console_filter);
+
+    let file = match log_file {
+        Some(path) => {
+            let file = File::create(path).with_context(|| format!("failed to create log file {}", path.display()))?;
+            let filter = EnvFilter::new(default_directives(verbosity.max(1)));
+            let layer = fmt::layer()
+                .with_writer(Mutex::new(file))
+                .with_ansi(false)
+                .fmt_fields(PlainFields(DefaultFields::new()));
+            Some(layer.with_filter(filter))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(console)
+        .with(file)
+        .try_init()
+        .context("failed to initialize logging")
+}
+
+// Span fields are formatted once per formatter type and shared between layers. A type of
+// its own keeps the console colors out of the log file.
+struct PlainFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for PlainFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> std::fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_directives() {
+        assert_eq!(default_directives(0), "warn,rustup_distribution=info");
+        assert_eq!(default_directives(-1), "warn,rustup_distribution=warn");
+        assert_eq!(default_directives(3), "warn,rustup_distribution=trace");
+    }
+}
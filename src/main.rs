@@ -1,48 +1,296 @@
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use clap::Parser;
-use rustup_distribution::{Options, Config, canonicalize_path, install::run_install, package::run_package};
+use tracing::error;
+use rustup_distribution::{Options, Config, InstalledState, Lockfile, Message, MessageFormat, Plan, PlanStep, canonicalize_path, emit, init_logging, ProcessRunner, message_format, set_message_format, start_run, install::{run_install, plan_install}, sync::{run_sync, plan_sync, reads_toolchains, SyncOptions}, uninstall::{run_uninstall, plan_uninstall, run_uninstall_bundle, plan_uninstall_bundle}, package::{run_package, plan_package, PackageOptions}, resolve::run_resolve, verify::run_verify, config_show::run_config_show, ConfigCommand, ConfigOverrides, lock_config, lock_download_dir, LOCKFILE_NAME};
 
 fn main() {
     let opts = Options::parse();
-    
+    if let Err(err) = init_logging(opts.verbosity(), opts.log_file.as_deref()) {
+        eprintln!("{:?}", err);
+        std::process::exit(1);
+    }
+    let runner = ProcessRunner { timeout: opts.command_timeout.map(Duration::from_secs) };
+
     match opts.subcommand {
-        rustup_distribution::SubCommand::Install { source_dir } => {
-            if let Some(source_dir) = &source_dir {
-                let source_dir = canonicalize_path(source_dir).unwrap_or_else(|| {
-                    println!("Invalid or non-existent source directory: {:?}", source_dir);
+        rustup_distribution::SubCommand::Install { source_dir, overrides, locked, dry_run, message_format, report } => {
+            set_message_format(message_format);
+            let (config, lockfile_path) = load_config("install", source_dir.as_deref(), &overrides, report.as_deref());
+            if dry_run {
+                let installed = InstalledState::read(&runner, &config.homes, !config.target_selections.is_empty()).unwrap_or_else(|err| {
+                    error!("Failed to read installed toolchains and tools : {:?}", err);
                     std::process::exit(1);
                 });
-                let config = Config::parse(&source_dir);
+                print_plan(&config, &lockfile_path, locked, |config, lockfile| plan_install(config, lockfile, &installed));
+                return;
+            }
+            start_run("install");
+            let config = lock_install_config(&config, &lockfile_path, locked, report.as_deref());
 
-                if let Err(err) = run_install(&config) {
-                    panic!("Failed to install configuration toolchain : {:?}", err);
-                }
-            } else {
-                println!("Source directory not provided");
+            let result = run_install(&config, &runner);
+            finish_report(result.as_ref().err().map(|err| err.to_string()), report.as_deref());
+            if let Err(err) = result {
+                error!("Failed to install configuration toolchain : {:?}", err);
                 std::process::exit(1);
             }
         },
-        rustup_distribution::SubCommand::Package { source_dir, output_dir } => {
-            match (source_dir, output_dir) {
-                (Some(source_dir), Some(output_dir)) => {
-                    let source_dir = canonicalize_path(&source_dir).unwrap_or_else(|| {
-                        println!("Invalid or non-existent source directory: {:?}", source_dir);
-                        std::process::exit(1);
-                    });
-                    
+        rustup_distribution::SubCommand::Package { source_dir, overrides, output_dir, sbom, format, locked, dry_run, message_format, report } => {
+            set_message_format(message_format);
+            match output_dir {
+                Some(output_dir) => {
+                    let (config, lockfile_path) = load_config("package", source_dir.as_deref(), &overrides, report.as_deref());
+
                     let output_dir = canonicalize_path(&output_dir).unwrap_or_else(|| {
-                        println!("Invalid or non-existent output directory: {:?}", output_dir);
+                        error!("Invalid or non-existent output directory: {:?}", output_dir);
                         std::process::exit(1);
                     });
 
-                    let config = Config::parse(&source_dir);
+                    let options = PackageOptions { sbom, formats: format };
+                    if dry_run {
+                        print_plan(&config, &lockfile_path, locked, |config, lockfile| {
+                            plan_package(config, lockfile, &output_dir, &options)
+                        });
+                        return;
+                    }
+                    start_run("package");
+                    let (config, lockfile) = lock_config(&config, &lockfile_path, locked).unwrap_or_else(|err| {
+                        fail_run(report.as_deref(), format!("Failed to resolve {} : {:?}", LOCKFILE_NAME, err));
+                    });
 
-                    if let Err(err) = run_package(&config, &output_dir) {
-                        panic!("Failed to package configuration toolchain : {:?}", err);
+                    let result = run_package(&config, Some(&lockfile), &output_dir, &options, &runner);
+                    finish_report(result.as_ref().err().map(|err| err.to_string()), report.as_deref());
+                    if let Err(err) = result {
+                        error!("Failed to package configuration toolchain : {:?}", err);
+                        std::process::exit(1);
                     }
                 },
-                _ => panic!("Eorror: source directory or output directory not provided")
+                None => {
+                    start_run("package");
+                    fail_run(report.as_deref(), "Output directory not provided, pass it with -o".to_string());
+                }
+            }
+        },
+        rustup_distribution::SubCommand::Sync { source_dir, overrides, prune, yes, locked, dry_run, message_format, report } => {
+            set_message_format(message_format);
+            let (config, lockfile_path) = load_config("sync", source_dir.as_deref(), &overrides, report.as_deref());
+            let options = SyncOptions { prune };
+            if dry_run {
+                let installed = InstalledState::read(&runner, &config.homes, reads_toolchains(&config, &options)).unwrap_or_else(|err| {
+                    error!("Failed to read installed toolchains and tools : {:?}", err);
+                    std::process::exit(1);
+                });
+                print_plan(&config, &lockfile_path, locked, |config, lockfile| {
+                    plan_sync(config, lockfile, &installed, &options)
+                });
+                return;
+            }
+            start_run("sync");
+            let config = lock_install_config(&config, &lockfile_path, locked, report.as_deref());
+
+            let result = run_sync(&config, &runner, &options, &|plan| confirm_sync(plan, yes));
+            finish_report(result.as_ref().err().map(|err| err.to_string()), report.as_deref());
+            if let Err(err) = result {
+                error!("Failed to sync configuration toolchain : {:?}", err);
+                std::process::exit(1);
+            }
+        },
+        rustup_distribution::SubCommand::Uninstall { source_dir, overrides, bundle, prefix, dry_run, message_format, report } => {
+            set_message_format(message_format);
+            if let (Some(bundle), Some(prefix)) = (bundle, prefix) {
+                let prefix = canonicalize_path(&prefix).unwrap_or_else(|| {
+                    error!("Invalid or non-existent prefix: {:?}", prefix);
+                    std::process::exit(1);
+                });
+                if dry_run {
+                    match plan_uninstall_bundle(&bundle, &prefix) {
+                        Ok(plan) => show_plan(&plan),
+                        Err(err) => {
+                            error!("Failed to plan uninstalling bundle {:?} : {:?}", bundle, err);
+                            std::process::exit(1);
+                        }
+                    }
+                    return;
+                }
+                start_run("uninstall");
+                let result = run_uninstall_bundle(&bundle, &prefix);
+                finish_report(result.as_ref().err().map(|err| err.to_string()), report.as_deref());
+                if let Err(err) = result {
+                    error!("Failed to uninstall bundle {:?} : {:?}", bundle, err);
+                    std::process::exit(1);
+                }
+                return;
+            }
+
+            // the same names install used, `latest-available` pinned by the lock file as it is
+            let (config, lockfile_path) = load_config("uninstall", source_dir.as_deref(), &overrides, report.as_deref());
+            let config = match load_lockfile(&lockfile_path) {
+                Some(lockfile) => lockfile.pin(&config),
+                None => config,
+            };
+            if dry_run {
+                match InstalledState::read(&runner, &config.homes, !config.target_selections.is_empty()) {
+                    Ok(installed) => show_plan(&plan_uninstall(&config, &installed)),
+                    Err(err) => {
+                        error!("Failed to read installed toolchains and tools : {:?}", err);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            start_run("uninstall");
+            let result = run_uninstall(&config, &runner);
+            finish_report(result.as_ref().err().map(|err| err.to_string()), report.as_deref());
+            if let Err(err) = result {
+                error!("Failed to uninstall configuration toolchain : {:?}", err);
+                std::process::exit(1);
             }
         },
+        rustup_distribution::SubCommand::Resolve { source_dir, overrides, max_days } => {
+            let (config, _) = load_config("resolve", source_dir.as_deref(), &overrides, None);
 
+            if let Err(err) = run_resolve(&config, max_days) {
+                error!("Failed to resolve configuration toolchain : {:?}", err);
+                std::process::exit(1);
+            }
+        },
+        rustup_distribution::SubCommand::Verify { bundle } => {
+            if let Err(err) = run_verify(&bundle) {
+                error!("Failed to verify bundle {:?} : {:?}", bundle, err);
+                std::process::exit(1);
+            }
+        },
+        rustup_distribution::SubCommand::Config { command: ConfigCommand::Show { source_dir, overrides } } => {
+            let config = Config::resolve(canonical_source_dir(source_dir.as_deref()).as_deref(), &overrides).unwrap_or_else(|err| {
+                error!("Failed to load configuration : {:?}", err);
+                std::process::exit(1);
+            });
+
+            if let Err(err) = run_config_show(&config) {
+                error!("Failed to show configuration : {:?}", err);
+                std::process::exit(1);
+            }
+        },
+
+    }
+}
+
+fn canonical_source_dir(source_dir: Option<&Path>) -> Option<PathBuf> {
+    source_dir.map(|source_dir| {
+        canonicalize_path(source_dir).unwrap_or_else(|| {
+            error!("Invalid or non-existent source directory: {:?}", source_dir);
+            std::process::exit(1);
+        })
+    })
+}
+
+// Resolve the configuration file, if any, under the command line overrides. The lock file
+// sits next to the configuration file, or in the current directory without one. A
+// configuration that fails to load is reported as a failed `command` run.
+fn load_config(command: &str, source_dir: Option<&Path>, overrides: &ConfigOverrides, report_path: Option<&Path>) -> (Config, PathBuf) {
+    let source_dir = canonical_source_dir(source_dir);
+    let config = Config::resolve(source_dir.as_deref(), overrides).unwrap_or_else(|err| {
+        start_run(command);
+        fail_run(report_path, format!("Failed to load configuration : {:?}", err));
+    });
+    if config.target_selections.is_empty() && config.extra_tools.is_empty() {
+        error!("Nothing to do: pass a configuration file with -s, or --channel, --target or --tool");
+        std::process::exit(1);
+    }
+
+    let lockfile_path = match source_dir {
+        Some(source_dir) => source_dir.with_file_name(LOCKFILE_NAME),
+        None => std::env::current_dir().unwrap_or_default().join(LOCKFILE_NAME),
+    };
+    (config, lockfile_path)
+}
+
+// Pin the configuration by the lock file for a run where rustup downloads the toolchains.
+// With `--locked`, floating channels published again since they were locked are refused, as
+// rustup would install them as they are published now.
+fn lock_install_config(config: &Config, lockfile_path: &Path, locked: bool, report_path: Option<&Path>) -> Config {
+    let (config, lockfile) = lock_config(config, lockfile_path, locked).unwrap_or_else(|err| {
+        fail_run(report_path, format!("Failed to resolve {} : {:?}", LOCKFILE_NAME, err));
+    });
+    if locked {
+        if let Err(err) = lockfile.check_floating_channels(&config, &lock_download_dir()) {
+            fail_run(report_path, format!("Failed to check {} : {:?}", LOCKFILE_NAME, err));
+        }
+    }
+    config
+}
+
+// Print what the run would do, planned from the configuration and the lock file as they are,
+// without resolving anything over the network
+fn print_plan(config: &Config, lockfile_path: &Path, locked: bool, plan: impl Fn(&Config, Option<&Lockfile>) -> anyhow::Result<Plan>) {
+    let lockfile = load_lockfile(lockfile_path);
+    if locked && !lockfile.as_ref().is_some_and(|lockfile| lockfile.covers(config)) {
+        error!("`--locked` was passed but {} does not cover the configuration", LOCKFILE_NAME);
+        std::process::exit(1);
+    }
+    let pinned = lockfile.as_ref().map_or_else(|| config.clone(), |lockfile| lockfile.pin(config));
+    let mut plan = plan(&pinned, lockfile.as_ref()).unwrap_or_else(|err| {
+        error!("Failed to plan configuration toolchain : {:?}", err);
+        std::process::exit(1);
+    });
+    if !locked && !lockfile.as_ref().is_some_and(|lockfile| lockfile.is_current(config)) {
+        plan.steps.insert(0, PlanStep::WriteFile { path: lockfile_path.to_path_buf() });
+    }
+
+    show_plan(&plan);
+    if message_format() == MessageFormat::Human {
+        for bundle in plan.bundles() {
+            println!("Would produce bundle {:?}", bundle);
+        }
+    }
+}
+
+// The lock file next to the configuration, if there is one, read without going to the network
+fn load_lockfile(lockfile_path: &Path) -> Option<Lockfile> {
+    Lockfile::load(lockfile_path).unwrap_or_else(|err| {
+        error!("Failed to read {} : {:?}", LOCKFILE_NAME, err);
+        std::process::exit(1);
+    })
+}
+
+// Print every step of `plan`, or emit it as one json message
+fn show_plan(plan: &Plan) {
+    if message_format() == MessageFormat::Json {
+        emit(Message::Plan { steps: plan.steps.clone() });
+    } else {
+        print!("{}", plan);
     }
-}
\ No newline at end of file
+}
+
+// Show every change sync is about to make, and ask before making them unless `--yes` was passed
+fn confirm_sync(plan: &Plan, yes: bool) -> bool {
+    show_plan(plan);
+    if yes {
+        return true;
+    }
+    if !std::io::stdin().is_terminal() {
+        error!("Refusing to change this machine without confirmation, pass --yes");
+        return false;
+    }
+
+    eprint!("Proceed? [y/N] ");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn finish_report(error: Option<String>, report_path: Option<&Path>) {
+    if let Err(err) = rustup_distribution::finish_run(error, report_path) {
+        error!("Failed to write run report : {:?}", err);
+    }
+}
+
+// Record the failure in the run report before exiting, so failed runs are reported too
+fn fail_run(report_path: Option<&Path>, error: String) -> ! {
+    error!("{}", error);
+    finish_report(Some(error), report_path);
+    std::process::exit(1);
+}
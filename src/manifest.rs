@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::{utils, Config, DownloadCfg, NetworkConfig, NotFoundError, TargetSelection};
+
+// How many days `latest-available` walks back before giving up
+pub const LATEST_AVAILABLE_MAX_DAYS: usize = 30;
+const DEFAULT_PROFILE: &str = "default";
+
+// The subset of a v2 channel manifest (channel-rust-*.toml) this tool reads
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChannelManifest {
+    pub date: String,
+    #[serde(default)]
+    pub pkg: BTreeMap<String, ManifestPackage>,
+    // components installed by each rustup profile, eg: minimal = ["rustc", "cargo", "rust-std"]
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Vec<String>>,
+    // component aliases, eg: rust-analyzer -> rust-analyzer-preview
+    #[serde(default)]
+    pub renames: BTreeMap<String, ManifestRename>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ManifestRename {
+    pub to: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ManifestPackage {
+    // eg: 1.72.0-nightly (839e9a6e1 2023-06-24)
+    pub version: String,
+    #[serde(default)]
+    pub target: BTreeMap<String, ManifestTarget>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ManifestTarget {
+    pub available: bool,
+    pub url: Option<String>,
+    pub hash: Option<String>,
+    pub xz_url: Option<String>,
+    pub xz_hash: Option<String>,
+}
+
+impl ChannelManifest {
+    pub fn parse(content: &str) -> Result<Self> {
+        toml::from_str(content).context("failed to parse channel manifest")
+    }
+
+    // Download and parse the channel manifest of `target_selection`, returning it with its sha256.
+    pub fn fetch(
+        target_selection: &TargetSelection,
+        dist_server: &str,
+        network: &NetworkConfig,
+        download_dir: &Path,
+    ) -> Result<(Self, String)> {
+        let download_cfg = DownloadCfg {
+            dist_root: Self::url(target_selection, dist_server),
+            download_dir: download_dir.to_path_buf(),
+            network: network.clone(),
+        };
+        let file_name = Self::file_name(target_selection);
+        let file = download_cfg.download(&file_name)?;
+        let content = fs::read_to_string(&file.path).context("reading channel manifest")?;
+        fs::remove_file(&file.path).context("cleaning up channel manifest")?;
+
+        Ok((Self::parse(&content)?, file.sha256))
+    }
+
+    pub fn url(target_selection: &TargetSelection, dist_server: &str) -> String {
+        target_selection.mainifest_v2_url(&format!("{}/dist", dist_server))
+    }
+
+    // Name of the file `fetch` downloads the manifest to
+    pub fn file_name(target_selection: &TargetSelection) -> String {
+        format!("channel-rust-{}.toml", target_selection.channel)
+    }
+
+    // Look up a package by component name, following renames
+    pub fn package(&self, name: &str) -> Option<&ManifestPackage> {
+        let name = self.renames.get(name).map_or(name, |rename| rename.to.as_str());
+        self.pkg.get(name)
+    }
+
+    // Every (component, target) pair `rustup toolchain install` would need for `target_selection`:
+    // the profile components and extra components for the host, and rust-std for extra targets.
+    pub fn requested_components(&self, target_selection: &TargetSelection) -> Vec<(String, String)> {
+        let profile = target_selection.profile.as_deref().unwrap_or(DEFAULT_PROFILE);
+        let host = &target_selection.target;
+
+        // Like rustup, profile components that are never built for the host (eg: rust-mingw) are skipped
+        let mut components: Vec<(String, String)> = self
+            .profiles
+            .get(profile)
+            .into_iter()
+            .flatten()
+            .filter(|component| self.package(component).and_then(|pkg| pkg.for_target(host)).is_some())
+            .chain(&target_selection.components)
+            .map(|component| (component.clone(), host.clone()))
+            .collect();
+        components.extend(target_selection.targets.iter().map(|target| ("rust-std".to_string(), target.clone())));
+        components.dedup();
+        components
+    }
+
+    // Requested components this manifest does not provide, as `component (target)`
+    pub fn missing_components(&self, target_selection: &TargetSelection) -> Vec<String> {
+        self.requested_components(target_selection)
+            .into_iter()
+            .filter(|(component, target)| {
+                !self
+                    .package(component)
+                    .and_then(|pkg| pkg.for_target(target))
+                    .is_some_and(|manifest_target| manifest_target.available)
+            })
+            .map(|(component, target)| format!("{} ({})", component, target))
+            .collect()
+    }
+
+    // Match the names listed in a toolchain `components` file, eg: `rust-std-x86_64-unknown-linux-gnu`,
+    // against the manifest packages they come from.
+    pub fn package_for_component(&self, component: &str, target: &str) -> Option<(&String, &ManifestPackage)> {
+        self.pkg
+            .iter()
+            .find(|(name, _)| component == name.as_str() || component == format!("{}-{}", name, target))
+    }
+}
+
+impl ManifestPackage {
+    // The version without the commit and date suffix, eg: 1.72.0-nightly
+    pub fn short_version(&self) -> &str {
+        self.version.split_whitespace().next().unwrap_or_default()
+    }
+
+    // Target specific entry, falling back to the `*` entry of target independent packages such as rust-src
+    pub fn for_target(&self, target: &str) -> Option<&ManifestTarget> {
+        self.target.get(target).or_else(|| self.target.get("*"))
+    }
+}
+
+// Walk back from the current channel manifest through the dated ones, and return the newest
+// date where every requested component and target is available.
+pub fn find_latest_available(
+    target_selection: &TargetSelection,
+    config: &Config,
+    download_dir: &Path,
+    max_days: usize,
+) -> Result<String> {
+    let mut candidate = TargetSelection { date: None, ..target_selection.clone() };
+    let mut date = None;
+
+    for _ in 0..=max_days {
+        candidate.date = date.clone();
+        match ChannelManifest::fetch(&candidate, &config.rustup_dist_server, &config.network, download_dir) {
+            Ok((manifest, _)) => {
+                let missing = manifest.missing_components(target_selection);
+                if missing.is_empty() {
+                    info!("Resolved {} to {}", target_selection.toolchain_name(), manifest.date);
+                    return Ok(manifest.date);
+                }
+                debug!("Skipping {} {}: missing {}", target_selection.channel, manifest.date, missing.join(", "));
+                date = utils::previous_date(&manifest.date);
+            }
+            // Not every day has a release, but any other failure is one
+            Err(err) if date.is_some() && NotFoundError::is_cause_of(&err) => {
+                debug!("Skipping {} {}: no manifest", target_selection.channel, date.as_deref().unwrap_or_default());
+                date = date.as_deref().and_then(utils::previous_date);
+            }
+            Err(err) => return Err(err),
+        }
+        if date.is_none() {
+            break;
+        }
+    }
+
+    Err(anyhow!(
+        "no {} in the last {} days provides every requested component for {}",
+        target_selection.channel,
+        max_days,
+        target_selection.target
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+manifest-version = "2"
+date = "2023-06-25"
+
+[profiles]
+minimal = ["rustc", "rust-std", "rust-mingw"]
+
+[renames.src]
+to = "rust-src"
+
+[pkg.rustc]
+version = "1.72.0-nightly (839e9a6e1 2023-06-24)"
+
+[pkg.rustc.target.x86_64-unknown-linux-gnu]
+available = true
+url = "https://static.rust-lang.org/dist/2023-06-25/rustc-nightly-x86_64-unknown-linux-gnu.tar.gz"
+hash = "aaaa"
+
+[pkg.rust-std]
+version = "1.72.0-nightly (839e9a6e1 2023-06-24)"
+
+[pkg.rust-std.target.x86_64-unknown-linux-gnu]
+available = false
+
+[pkg.rust-src]
+version = "1.72.0-nightly (839e9a6e1 2023-06-24)"
+
+[pkg.rust-src.target."*"]
+available = true
+url = "https://static.rust-lang.org/dist/2023-06-25/rust-src-nightly.tar.gz"
+hash = "bbbb"
+"#;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = ChannelManifest::parse(MANIFEST).unwrap();
+        assert_eq!(manifest.date, "2023-06-25");
+        assert_eq!(manifest.profiles["minimal"], vec!["rustc", "rust-std", "rust-mingw"]);
+
+        let target = "x86_64-unknown-linux-gnu";
+        let (name, rust_std) = manifest.package_for_component("rust-std-x86_64-unknown-linux-gnu", target).unwrap();
+        assert_eq!(name, "rust-std");
+        assert_eq!(rust_std.short_version(), "1.72.0-nightly");
+        assert!(!rust_std.for_target(target).unwrap().available);
+
+        let (_, rust_src) = manifest.package_for_component("rust-src", target).unwrap();
+        assert_eq!(rust_src.for_target(target).unwrap().hash.as_deref(), Some("bbbb"));
+    }
+
+    #[test]
+    fn test_missing_components() {
+        let manifest = ChannelManifest::parse(MANIFEST).unwrap();
+        let mut target_selection = TargetSelection {
+            target: "x86_64-unknown-linux-gnu".to_string(),
+            channel: "nightly".to_string(),
+            profile: Some("minimal".to_string()),
+            components: vec!["src".to_string()],
+            targets: vec!["wasm32-unknown-unknown".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            manifest.missing_components(&target_selection),
+            vec!["rust-std (x86_64-unknown-linux-gnu)", "rust-std (wasm32-unknown-unknown)"]
+        );
+
+        target_selection.profile = None;
+        target_selection.targets.clear();
+        assert_eq!(manifest.missing_components(&target_selection), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_find_latest_available() {
+        let root = std::env::temp_dir().join("rustup-distribution-test-latest-available");
+        let _ = fs::remove_dir_all(&root);
+        let manifest = |date: &str, available: bool| {
+            format!("date = \"{}\"\n[pkg.rustc]\nversion = \"1.77.0-nightly\"\n[pkg.rustc.target.x86_64-unknown-linux-gnu]\navailable = {}\n", date, available)
+        };
+        fs::create_dir_all(root.join("mirror/dist/2024-01-01")).unwrap();
+        fs::write(root.join("mirror/dist/channel-rust-nightly.toml"), manifest("2024-01-03", false)).unwrap();
+        fs::write(root.join("mirror/dist/2024-01-01/channel-rust-nightly.toml"), manifest("2024-01-01", true)).unwrap();
+        let config = Config {
+            rustup_dist_server: url::Url::from_directory_path(root.join("mirror")).unwrap().to_string().trim_end_matches('/').to_string(),
+            ..Default::default()
+        };
+        let target_selection = TargetSelection {
+            target: "x86_64-unknown-linux-gnu".to_string(),
+            channel: "nightly".to_string(),
+            components: vec!["rustc".to_string()],
+            ..Default::default()
+        };
+
+        // 2024-01-02 has no release and is skipped
+        let download_dir = root.join("download");
+        assert_eq!(find_latest_available(&target_selection, &config, &download_dir, 5).unwrap(), "2024-01-01");
+
+        // a manifest that exists but cannot be read is an error, not a missing release
+        fs::create_dir_all(root.join("mirror/dist/2024-01-02/channel-rust-nightly.toml")).unwrap();
+        assert!(find_latest_available(&target_selection, &config, &download_dir, 5).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{utils, ChannelManifest, ExtendTool, TargetSelection};
+
+// Written at the root of every bundle, next to the `components` file
+pub const BUNDLE_METADATA_FILENAME: &str = "bundle-metadata.json";
+pub const BUNDLE_METADATA_VERSION: u32 = 1;
+
+const CRATE_JSON_FILENAME: &str = ".crates2.json";
+
+// Everything that went into a bundle, plus the sha256 of each file it contains.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BundleMetadata {
+    pub format_version: u32,
+    // the tool and version that produced the bundle, eg: rustup-distribution 0.1.0
+    pub generator: String,
+    pub toolchain: String,
+    pub source: SourceArchive,
+    pub components: Vec<String>,
+    // component -> the archive it is published as upstream, from the channel manifest
+    #[serde(default)]
+    pub component_sources: BTreeMap<String, SourceArchive>,
+    pub tools: Vec<ToolMetadata>,
+    pub config: BundleConfig,
+    // relative path -> sha256, paths always use `/`
+    pub files: BTreeMap<String, String>,
+    // relative path -> link target of every symbolic link
+    #[serde(default)]
+    pub symlinks: BTreeMap<String, String>,
+}
+
+// The upstream archive the toolchain was extracted from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SourceArchive {
+    pub url: String,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ToolMetadata {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+// The part of the configuration that produced the bundle
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BundleConfig {
+    pub rustup_dist_server: String,
+    pub rustup_update_root: String,
+    pub target: TargetSelection,
+    pub extra_tools: Vec<ExtendTool>,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub verified: usize,
+    pub tampered: Vec<String>,
+    pub missing: Vec<String>,
+    pub unexpected: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.tampered.is_empty() && self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+impl BundleMetadata {
+    pub fn new(toolchain: String, source: SourceArchive, config: BundleConfig) -> Self {
+        BundleMetadata {
+            format_version: BUNDLE_METADATA_VERSION,
+            generator: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            toolchain,
+            source,
+            components: Vec::new(),
+            component_sources: BTreeMap::new(),
+            tools: Vec::new(),
+            config,
+            files: BTreeMap::new(),
+            symlinks: BTreeMap::new(),
+        }
+    }
+
+    // Fill components, tools and file hashes from the staged bundle directory, and the upstream
+    // archive of each component from `manifest`, then write the metadata file into it.
+    pub fn write_to(mut self, bundle_dir: &Path, tool_dirs: &[(ExtendTool, String)], manifest: Option<&ChannelManifest>) -> Result<Self> {
+        let components = fs::read_to_string(bundle_dir.join("components")).unwrap_or_default();
+        self.components = components.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string).collect();
+        if let Some(manifest) = manifest {
+            self.component_sources = component_sources(&self.components, &self.config.target.target, manifest);
+        }
+
+        self.tools = tool_dirs
+            .iter()
+            .map(|(tool, dir)| ToolMetadata {
+                name: tool.name.clone(),
+                version: installed_crate_version(&bundle_dir.join(dir), &tool.name).or_else(|| tool.version.clone()),
+            })
+            .collect();
+
+        (self.files, self.symlinks) = hash_directory(bundle_dir)?;
+
+        let content = serde_json::to_string_pretty(&self)?;
+        fs::write(bundle_dir.join(BUNDLE_METADATA_FILENAME), content).context("writing bundle metadata")?;
+
+        Ok(self)
+    }
+}
+
+// The archive each component is published as for `target`, extra tools having none
+fn component_sources(components: &[String], target: &str, manifest: &ChannelManifest) -> BTreeMap<String, SourceArchive> {
+    components
+        .iter()
+        .filter_map(|component| {
+            let (_, package) = manifest.package_for_component(component, target)?;
+            let archive = package.for_target(target)?;
+            let source = SourceArchive { url: utils::redact_url_str(archive.url.as_ref()?), sha256: archive.hash.clone()? };
+            Some((component.clone(), source))
+        })
+        .collect()
+}
+
+// `cargo install` records the exact version it resolved in `.crates2.json`
+fn installed_crate_version(tool_dir: &Path, name: &str) -> Option<String> {
+    let content = fs::read_to_string(tool_dir.join(CRATE_JSON_FILENAME)).ok()?;
+    let crates: serde_json::Value = serde_json::from_str(&content).ok()?;
+    crates.get("installs")?.as_object()?.keys().find_map(|package_id| {
+        let mut parts = package_id.split(' ');
+        match (parts.next(), parts.next()) {
+            (Some(crate_name), Some(version)) if crate_name == name => Some(version.to_string()),
+            _ => None,
+        }
+    })
+}
+
+// The sha256 of every file and the target of every symbolic link under `dir_path`.
+// Links are recorded as links, never followed.
+fn hash_directory(dir_path: &Path) -> Result<(BTreeMap<String, String>, BTreeMap<String, String>)> {
+    let mut files = BTreeMap::new();
+    let mut symlinks = BTreeMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(dir_path.to_path_buf());
+
+    while let Some(path) = queue.pop_front() {
+        for entry in fs::read_dir(&path)? {
+            let entry_path = entry?.path();
+            let file_type = fs::symlink_metadata(&entry_path)?.file_type();
+            if file_type.is_dir() {
+                queue.push_back(entry_path);
+                continue;
+            }
+            let rel_path = entry_path.strip_prefix(dir_path).expect("Invalid directory path");
+            let format_path = rel_path.display().to_string().replace('\\', "/");
+            if file_type.is_symlink() {
+                let target = fs::read_link(&entry_path)?;
+                symlinks.insert(format_path, target.display().to_string().replace('\\', "/"));
+            } else if format_path != BUNDLE_METADATA_FILENAME {
+                files.insert(format_path, utils::sha256_file(&entry_path)?);
+            }
+        }
+    }
+
+    Ok((files, symlinks))
+}
+
+// Sidecar written next to the bundle, in the `sha256sum` format. Returns the sha256.
+pub fn write_checksum_file(bundle: &Path) -> Result<String> {
+    let sha256 = utils::sha256_file(bundle)?;
+    let file_name = bundle.file_name().expect("bundle has no file name").to_string_lossy();
+    fs::write(checksum_path(bundle), format!("{}  {}\n", sha256, file_name)).context("writing bundle checksum")?;
+    Ok(sha256)
+}
+
+// The `sha256sum` sidecar of `bundle`
+pub fn checksum_path(bundle: &Path) -> std::path::PathBuf {
+    let mut path = bundle.as_os_str().to_owned();
+    path.push(".sha256");
+    path.into()
+}
+
+// Re-hash every file of a `.tar.gz` bundle against its embedded metadata.
+// A bundle that cannot be read to the end is reported as an error.
+pub fn verify_bundle(bundle: &Path) -> Result<(BundleMetadata, VerifyReport)> {
+    let checksum_file = checksum_path(bundle);
+    if checksum_file.exists() {
+        let expected = fs::read_to_string(&checksum_file)?;
+        let expected = expected.split_whitespace().next().unwrap_or_default();
+        let actual = utils::sha256_file(bundle)?;
+        if expected != actual {
+            bail!("bundle checksum mismatch: expected {}, found {}", expected, actual);
+        }
+    }
+
+    let file = File::open(bundle).with_context(|| format!("failed to open bundle: {}", bundle.display()))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+    let mut metadata: Option<BundleMetadata> = None;
+    let mut actual = BTreeMap::new();
+    let mut actual_symlinks = BTreeMap::new();
+    let entries = archive.entries().context("bundle is truncated or corrupted")?;
+    for entry in entries {
+        let mut entry = entry.context("bundle is truncated or corrupted")?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_symlink() {
+            let path = entry.path()?.to_string_lossy().trim_start_matches("./").to_string();
+            let target = entry.link_name()?.map(|target| target.to_string_lossy().into_owned()).unwrap_or_default();
+            actual_symlinks.insert(path, target);
+            continue;
+        }
+        if !entry_type.is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().trim_start_matches("./").to_string();
+
+        if path == BUNDLE_METADATA_FILENAME {
+            let mut content = String::new();
+            entry.read_to_string(&mut content).context("bundle is truncated or corrupted")?;
+            metadata = Some(serde_json::from_str(&content).context("invalid bundle metadata")?);
+        } else {
+            let mut hasher = Sha256::new();
+            io::copy(&mut entry, &mut hasher).context("bundle is truncated or corrupted")?;
+            actual.insert(path, utils::to_hex(&hasher.finalize()));
+        }
+    }
+
+    let Some(metadata) = metadata else {
+        bail!("bundle has no {}", BUNDLE_METADATA_FILENAME);
+    };
+
+    let mut report = VerifyReport::default();
+    for (path, sha256) in &metadata.files {
+        match actual.remove(path) {
+            Some(found) if &found == sha256 => report.verified += 1,
+            Some(_) => report.tampered.push(path.clone()),
+            None => report.missing.push(path.clone()),
+        }
+    }
+    for (path, target) in &metadata.symlinks {
+        match actual_symlinks.remove(path) {
+            Some(found) if &found == target => report.verified += 1,
+            Some(_) => report.tampered.push(path.clone()),
+            None => report.missing.push(path.clone()),
+        }
+    }
+    report.unexpected = actual.into_keys().chain(actual_symlinks.into_keys()).collect();
+    report.unexpected.sort();
+
+    Ok((metadata, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{bundle_metadata, linux_target, temp_dir, write_files};
+
+    fn write_bundle(dir: &Path, bundle: &Path) {
+        let file = File::create(bundle).unwrap();
+        let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        builder.follow_symlinks(false);
+        builder.append_dir_all(".", dir).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    fn stage(name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+        let root = temp_dir(name);
+        let dir = root.join("rust-nightly-x86_64-unknown-linux-gnu");
+        write_files(
+            &dir,
+            &[
+                ("components", "rustc\ncargo\ngrcov-0.8.18\n"),
+                ("grcov-0.8.18/bin/grcov", "binary"),
+                (
+                    "grcov-0.8.18/.crates2.json",
+                    r#"{"installs":{"grcov 0.8.18 (registry+https://github.com/rust-lang/crates.io-index)":{}}}"#,
+                ),
+            ],
+        );
+
+        let tool = ExtendTool { name: "grcov".to_string(), version: None };
+        let metadata = bundle_metadata(&dir, linux_target("nightly", None), &[(tool, "grcov-0.8.18".to_string())]);
+        assert_eq!(metadata.components, vec!["rustc", "cargo", "grcov-0.8.18"]);
+        assert_eq!(metadata.tools[0].version.as_deref(), Some("0.8.18"));
+
+        (root, dir)
+    }
+
+    #[test]
+    fn test_verify_bundle() {
+        let (root, dir) = stage("rustup-distribution-test-verify");
+        let bundle = root.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz");
+        write_bundle(&dir, &bundle);
+        write_checksum_file(&bundle).unwrap();
+
+        let (_, report) = verify_bundle(&bundle).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.verified, 3);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_verify_tampered_bundle() {
+        let (root, dir) = stage("rustup-distribution-test-tampered");
+        fs::write(dir.join("grcov-0.8.18/bin/grcov"), "patched").unwrap();
+        fs::remove_file(dir.join("components")).unwrap();
+        let bundle = root.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz");
+        write_bundle(&dir, &bundle);
+
+        let (_, report) = verify_bundle(&bundle).unwrap();
+        assert_eq!(report.tampered, vec!["grcov-0.8.18/bin/grcov"]);
+        assert_eq!(report.missing, vec!["components"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_verify_bundle_symlinks() {
+        let (root, dir) = stage("rustup-distribution-test-verify-symlinks");
+        std::os::unix::fs::symlink("grcov", dir.join("grcov-0.8.18/bin/grcov-link")).unwrap();
+        let metadata: BundleMetadata = serde_json::from_str(&fs::read_to_string(dir.join(BUNDLE_METADATA_FILENAME)).unwrap()).unwrap();
+        let metadata = metadata.write_to(&dir, &[], None).unwrap();
+        assert_eq!(metadata.symlinks["grcov-0.8.18/bin/grcov-link"], "grcov");
+        assert!(!metadata.files.contains_key("grcov-0.8.18/bin/grcov-link"));
+
+        let bundle = root.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz");
+        write_bundle(&dir, &bundle);
+        let (_, report) = verify_bundle(&bundle).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.verified, 4);
+
+        fs::remove_file(dir.join("grcov-0.8.18/bin/grcov-link")).unwrap();
+        std::os::unix::fs::symlink("/bin/sh", dir.join("grcov-0.8.18/bin/grcov-link")).unwrap();
+        write_bundle(&dir, &bundle);
+        let (_, report) = verify_bundle(&bundle).unwrap();
+        assert_eq!(report.tampered, vec!["grcov-0.8.18/bin/grcov-link"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_component_sources() {
+        let manifest = ChannelManifest::parse(
+            r#"
+date = "2024-01-01"
+[pkg.rustc]
+version = "1.77.0-nightly (abc 2024-01-01)"
+[pkg.rustc.target.x86_64-unknown-linux-gnu]
+available = true
+url = "https://static.rust-lang.org/dist/2024-01-01/rustc-nightly-x86_64-unknown-linux-gnu.tar.gz"
+hash = "11"
+"#,
+        )
+        .unwrap();
+        let components = vec!["rustc".to_string(), "grcov-0.8.18".to_string()];
+        let sources = component_sources(&components, "x86_64-unknown-linux-gnu", &manifest);
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources["rustc"].sha256, "11");
+        assert!(sources["rustc"].url.ends_with("/rustc-nightly-x86_64-unknown-linux-gnu.tar.gz"));
+    }
+
+    #[test]
+    fn test_verify_truncated_bundle() {
+        let (root, dir) = stage("rustup-distribution-test-truncated");
+        let bundle = root.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz");
+        write_bundle(&dir, &bundle);
+        let content = fs::read(&bundle).unwrap();
+        fs::write(&bundle, &content[..content.len() / 2]).unwrap();
+
+        assert!(verify_bundle(&bundle).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::CommandSpec;
+
+// One side effect `package`, `install` or `uninstall` would have
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum PlanStep {
+    Download {
+        url: String,
+        // `None` when the server does not report a size
+        size: Option<u64>,
+        // `None` when no lock file entry records the hash
+        sha256: Option<String>,
+        // `None` when the file is fetched by rustup itself
+        destination: Option<PathBuf>,
+    },
+    Command {
+        program: String,
+        args: Vec<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        env: Vec<(String, String)>,
+    },
+    WriteFile {
+        path: PathBuf,
+    },
+    Bundle {
+        path: PathBuf,
+    },
+    RemoveFile {
+        path: PathBuf,
+    },
+}
+
+// Everything a run would do, in order, computed by `--dry-run` without doing any of it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    pub fn push(&mut self, step: PlanStep) {
+        self.steps.push(step);
+    }
+
+    pub fn command(&mut self, command: CommandSpec) {
+        self.push(PlanStep::Command { program: command.program, args: command.args, env: command.env });
+    }
+
+    pub fn write_file(&mut self, path: PathBuf) {
+        self.push(PlanStep::WriteFile { path });
+    }
+
+    pub fn remove_file(&mut self, path: PathBuf) {
+        self.push(PlanStep::RemoveFile { path });
+    }
+
+    pub fn bundles(&self) -> impl Iterator<Item = &PathBuf> {
+        self.steps.iter().filter_map(|step| match step {
+            PlanStep::Bundle { path } => Some(path),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanStep::Download { url, size, sha256, destination } => {
+                write!(f, "download {}", url)?;
+                if let Some(destination) = destination {
+                    write!(f, " -> {}", destination.display())?;
+                }
+                match size {
+                    Some(size) => write!(f, " (size: {} bytes", size)?,
+                    None => write!(f, " (size: unknown")?,
+                }
+                match sha256 {
+                    Some(sha256) => write!(f, ", sha256: {})", sha256),
+                    None => write!(f, ", sha256: unknown)"),
+                }
+            }
+            PlanStep::Command { program, args, env } => {
+                write!(f, "run ")?;
+                for (key, value) in env {
+                    write!(f, "{}={} ", key, value)?;
+                }
+                write!(f, "{} {}", program, args.join(" "))
+            }
+            PlanStep::WriteFile { path } => write!(f, "write {}", path.display()),
+            PlanStep::Bundle { path } => write!(f, "bundle {}", path.display()),
+            PlanStep::RemoveFile { path } => write!(f, "remove {}", path.display()),
+        }
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, step) in self.steps.iter().enumerate() {
+            writeln!(f, "{:>3}. {}", index + 1, step)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_plan() {
+        let mut plan = Plan::default();
+        plan.push(PlanStep::Download {
+            url: "https://static.rust-lang.org/dist/2024-01-01/rust-nightly-x86_64-unknown-linux-gnu.tar.gz".to_string(),
+            size: Some(42),
+            sha256: None,
+            destination: Some(PathBuf::from("/out/rust-nightly-x86_64-unknown-linux-gnu.tar.gz")),
+        });
+        plan.command(CommandSpec::cargo(vec!["install".to_string(), "grcov".to_string()]));
+        plan.push(PlanStep::Bundle { path: PathBuf::from("/out/rust-nightly-x86_64-unknown-linux-gnu.tar.gz") });
+
+        assert_eq!(
+            plan.to_string(),
+            "  1. download https://static.rust-lang.org/dist/2024-01-01/rust-nightly-x86_64-unknown-linux-gnu.tar.gz \
+             -> /out/rust-nightly-x86_64-unknown-linux-gnu.tar.gz (size: 42 bytes, sha256: unknown)\n\
+             \x20 2. run cargo install grcov\n\
+             \x20 3. bundle /out/rust-nightly-x86_64-unknown-linux-gnu.tar.gz\n"
+        );
+        assert_eq!(plan.bundles().count(), 1);
+    }
+}
@@ -0,0 +1,218 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{utils, InstallStatus, PlanStep};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MessageFormat {
+    // progress for people, through the logs
+    #[default]
+    Human,
+    // one json message per line on stdout, logs stay on stderr
+    Json,
+}
+
+static MESSAGE_FORMAT: OnceLock<MessageFormat> = OnceLock::new();
+static RUN_REPORT: Mutex<Option<RunReport>> = Mutex::new(None);
+
+// An event of a `package` or `install` run, tagged like cargo's `--message-format json`
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Message {
+    DownloadStarted {
+        url: String,
+        destination: PathBuf,
+    },
+    DownloadProgress {
+        url: String,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    DownloadFinished {
+        url: String,
+        destination: PathBuf,
+        size: u64,
+        sha256: String,
+    },
+    CommandStarted {
+        program: String,
+        args: Vec<String>,
+    },
+    CommandFinished {
+        program: String,
+        args: Vec<String>,
+        success: bool,
+        // `None` when the process was killed by a signal
+        exit_status: Option<i32>,
+    },
+    BundleWritten {
+        path: PathBuf,
+        size: u64,
+        sha256: String,
+    },
+    // a toolchain or tool `install` dealt with
+    ItemInstalled {
+        item: String,
+        status: InstallStatus,
+        // the version an upgraded tool replaced
+        #[serde(skip_serializing_if = "Option::is_none")]
+        previous: Option<String>,
+    },
+    Plan {
+        steps: Vec<PlanStep>,
+    },
+    RunFinished(RunReport),
+}
+
+// Summary of a whole run, emitted last and optionally written to a file
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RunReport {
+    pub command: String,
+    pub success: bool,
+    pub error: Option<String>,
+    pub started_at: String,
+    pub finished_at: String,
+    pub downloads: Vec<DownloadReport>,
+    pub commands: Vec<CommandReport>,
+    pub bundles: Vec<BundleReport>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DownloadReport {
+    pub url: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandReport {
+    pub program: String,
+    pub args: Vec<String>,
+    pub success: bool,
+    pub exit_status: Option<i32>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BundleReport {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+}
+
+pub fn set_message_format(format: MessageFormat) {
+    let _ = MESSAGE_FORMAT.set(format);
+}
+
+pub fn message_format() -> MessageFormat {
+    MESSAGE_FORMAT.get().copied().unwrap_or_default()
+}
+
+// Record `message` in the run report and print it: every message in json mode, only the
+// outcome of each toolchain and tool for people, the rest being logged
+pub fn emit(message: Message) {
+    if let Some(report) = RUN_REPORT.lock().unwrap_or_else(|err| err.into_inner()).as_mut() {
+        report.record(&message);
+    }
+
+    match (message_format(), &message) {
+        (MessageFormat::Json, _) => match serde_json::to_string(&message) {
+            Ok(line) => println!("{}", line),
+            Err(err) => warn!("Failed to serialize message: {}", err),
+        },
+        (MessageFormat::Human, Message::ItemInstalled { item, status, previous: Some(previous) }) => {
+            println!("{:>16} {} (was {})", status, item, previous)
+        }
+        (MessageFormat::Human, Message::ItemInstalled { item, status, previous: None }) => println!("{:>16} {}", status, item),
+        (MessageFormat::Human, _) => {}
+    }
+}
+
+// Start collecting the report of a `command` run
+pub fn start_run(command: &str) {
+    let report = RunReport {
+        command: command.to_string(),
+        started_at: utils::format_rfc3339(now()),
+        ..RunReport::default()
+    };
+    *RUN_REPORT.lock().unwrap_or_else(|err| err.into_inner()) = Some(report);
+}
+
+// Close the run report with its outcome, emit it and write it to `report_path`
+pub fn finish_run(error: Option<String>, report_path: Option<&Path>) -> Result<()> {
+    let Some(mut report) = RUN_REPORT.lock().unwrap_or_else(|err| err.into_inner()).take() else {
+        return Ok(());
+    };
+    report.success = error.is_none();
+    report.error = error;
+    report.finished_at = utils::format_rfc3339(now());
+
+    if let Some(path) = report_path {
+        let content = serde_json::to_string_pretty(&report)?;
+        fs::write(path, content).with_context(|| format!("failed to write run report {}", path.display()))?;
+    }
+    emit(Message::RunFinished(report));
+
+    Ok(())
+}
+
+impl RunReport {
+    fn record(&mut self, message: &Message) {
+        match message {
+            Message::DownloadFinished { url, size, sha256, .. } => self.downloads.push(DownloadReport {
+                url: url.clone(),
+                size: *size,
+                sha256: sha256.clone(),
+            }),
+            Message::CommandFinished { program, args, success, exit_status } => self.commands.push(CommandReport {
+                program: program.clone(),
+                args: args.clone(),
+                success: *success,
+                exit_status: *exit_status,
+            }),
+            Message::BundleWritten { path, size, sha256 } => self.bundles.push(BundleReport {
+                path: path.clone(),
+                size: *size,
+                sha256: sha256.clone(),
+            }),
+            _ => {}
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_json() {
+        let message = Message::CommandFinished {
+            program: "cargo".to_string(),
+            args: vec!["install".to_string(), "grcov".to_string()],
+            success: false,
+            exit_status: Some(101),
+        };
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"reason":"command-finished","program":"cargo","args":["install","grcov"],"success":false,"exit_status":101}"#
+        );
+
+        let mut report = RunReport::default();
+        report.record(&message);
+        report.record(&Message::BundleWritten { path: PathBuf::from("/out/rust.tar.gz"), size: 3, sha256: "00".to_string() });
+        let value = serde_json::to_value(Message::RunFinished(report)).unwrap();
+        assert_eq!(value["reason"], "run-finished");
+        assert_eq!(value["commands"][0]["exit_status"], 101);
+        assert_eq!(value["bundles"][0]["size"], 3);
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::{archive, installer, utils, BundleMetadata};
+
+const LEAD_MAGIC: [u8; 4] = [0xed, 0xab, 0xee, 0xdb];
+const HEADER_MAGIC: [u8; 8] = [0x8e, 0xad, 0xe8, 0x01, 0, 0, 0, 0];
+// signature headers follow the lead
+const RPMSIGTYPE_HEADERSIG: u16 = 5;
+
+// region tags marking the whole header as immutable
+const RPMTAG_HEADERSIGNATURES: u32 = 62;
+const RPMTAG_HEADERIMMUTABLE: u32 = 63;
+
+const RPMSIGTAG_SHA256: u32 = 273;
+const RPMSIGTAG_SIZE: u32 = 1000;
+const RPMSIGTAG_MD5: u32 = 1004;
+const RPMSIGTAG_PAYLOADSIZE: u32 = 1007;
+
+const RPMTAG_HEADERI18NTABLE: u32 = 100;
+const RPMTAG_NAME: u32 = 1000;
+const RPMTAG_VERSION: u32 = 1001;
+const RPMTAG_RELEASE: u32 = 1002;
+const RPMTAG_SUMMARY: u32 = 1004;
+const RPMTAG_DESCRIPTION: u32 = 1005;
+const RPMTAG_BUILDTIME: u32 = 1006;
+const RPMTAG_SIZE: u32 = 1009;
+const RPMTAG_LICENSE: u32 = 1014;
+const RPMTAG_GROUP: u32 = 1016;
+const RPMTAG_OS: u32 = 1021;
+const RPMTAG_ARCH: u32 = 1022;
+const RPMTAG_POSTIN: u32 = 1024;
+const RPMTAG_PREUN: u32 = 1025;
+const RPMTAG_FILESIZES: u32 = 1028;
+const RPMTAG_FILEMODES: u32 = 1030;
+const RPMTAG_FILERDEVS: u32 = 1033;
+const RPMTAG_FILEMTIMES: u32 = 1034;
+const RPMTAG_FILEDIGESTS: u32 = 1035;
+const RPMTAG_FILELINKTOS: u32 = 1036;
+const RPMTAG_FILEFLAGS: u32 = 1037;
+const RPMTAG_FILEUSERNAME: u32 = 1039;
+const RPMTAG_FILEGROUPNAME: u32 = 1040;
+const RPMTAG_SOURCERPM: u32 = 1044;
+const RPMTAG_PROVIDENAME: u32 = 1047;
+const RPMTAG_REQUIREFLAGS: u32 = 1048;
+const RPMTAG_REQUIRENAME: u32 = 1049;
+const RPMTAG_REQUIREVERSION: u32 = 1050;
+const RPMTAG_POSTINPROG: u32 = 1086;
+const RPMTAG_PREUNPROG: u32 = 1087;
+const RPMTAG_FILEDEVICES: u32 = 1095;
+const RPMTAG_FILEINODES: u32 = 1096;
+const RPMTAG_PROVIDEFLAGS: u32 = 1112;
+const RPMTAG_PROVIDEVERSION: u32 = 1113;
+const RPMTAG_DIRINDEXES: u32 = 1116;
+const RPMTAG_BASENAMES: u32 = 1117;
+const RPMTAG_DIRNAMES: u32 = 1118;
+const RPMTAG_PAYLOADFORMAT: u32 = 1124;
+const RPMTAG_PAYLOADCOMPRESSOR: u32 = 1125;
+const RPMTAG_PAYLOADFLAGS: u32 = 1126;
+const RPMTAG_FILEDIGESTALGO: u32 = 5011;
+const RPMTAG_PAYLOADDIGEST: u32 = 5092;
+const RPMTAG_PAYLOADDIGESTALGO: u32 = 5093;
+
+// file type bits of a mode
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+const PGPHASHALGO_SHA256: u32 = 8;
+const RPMSENSE_EQUAL: u32 = 1 << 3;
+const RPMSENSE_LESS: u32 = 1 << 1;
+const RPMSENSE_RPMLIB: u32 = 1 << 24;
+
+// rpm features the package relies on, with the rpm version that introduced them
+const RPMLIB_REQUIRES: [(&str, &str); 3] = [
+    ("rpmlib(CompressedFileNames)", "3.0.4-1"),
+    ("rpmlib(FileDigests)", "4.6.0-1"),
+    ("rpmlib(PayloadFilesHavePrefix)", "4.0-1"),
+];
+
+// RPM architecture of a target triple, eg: armv7-unknown-linux-gnueabihf -> armv7hl
+pub fn rpm_architecture(target: &str) -> Option<&'static str> {
+    installer::package_architectures(target).map(|(_, rpm)| rpm)
+}
+
+// Write the staged bundle `bundle_dir` as an RPM installing it into /opt/rust/<toolchain>,
+// without rpmbuild. Only the files listed by the `manifest.in` of each component are packaged.
+pub fn write_rpm(bundle_dir: &Path, dest: &Path, metadata: &BundleMetadata) -> Result<()> {
+    let target = &metadata.config.target;
+    let Some(architecture) = rpm_architecture(&target.target) else {
+        bail!("no RPM architecture for target {}", target.target);
+    };
+
+    let payload = dest.with_extension("cpio.gz");
+    let prefix = installer::package_prefix(metadata);
+    let root = installer::stage_package_root(bundle_dir, dest, metadata)?;
+    let files = package_files(&root, &prefix)?;
+
+    let file = File::create(&payload).with_context(|| format!("failed to create {}", payload.display()))?;
+    let mut cpio = CountingWriter { inner: archive::reproducible_gzip(file), count: 0 };
+    write_cpio(&mut cpio, &files)?;
+    let payload_size = cpio.count;
+    cpio.inner.finish()?.sync_all()?;
+    fs::remove_dir_all(&root)?;
+
+    let header = main_header(metadata, architecture, &files, &utils::sha256_file(&payload)?)?.to_bytes(RPMTAG_HEADERIMMUTABLE);
+    let mut md5 = Md5::new();
+    md5.update(&header);
+    io::copy(&mut File::open(&payload)?, &mut md5)?;
+
+    let mut signature = RpmHeader::default();
+    signature.insert(RPMSIGTAG_SHA256, Value::String(utils::to_hex(&Sha256::digest(&header))));
+    signature.insert(RPMSIGTAG_SIZE, Value::Int32(vec![to_u32(header.len() as u64 + fs::metadata(&payload)?.len())?]));
+    signature.insert(RPMSIGTAG_MD5, Value::Bin(md5.finalize().to_vec()));
+    signature.insert(RPMSIGTAG_PAYLOADSIZE, Value::Int32(vec![to_u32(payload_size)?]));
+    let mut signature = signature.to_bytes(RPMTAG_HEADERSIGNATURES);
+    // the main header starts on an 8 byte boundary
+    signature.resize(signature.len().next_multiple_of(8), 0);
+
+    let nevra = format!("{}-{}-1", target.package_name(), target.package_version());
+    let mut file = File::create(dest).with_context(|| format!("failed to create {}", dest.display()))?;
+    file.write_all(&lead(&nevra))?;
+    file.write_all(&signature)?;
+    file.write_all(&header)?;
+    io::copy(&mut File::open(&payload)?, &mut file)?;
+    file.sync_all()?;
+    fs::remove_file(&payload)?;
+
+    Ok(())
+}
+
+// One file, directory or symlink of the package
+struct PackageFile {
+    // absolute installed path, eg: /opt/rust/stable-x86_64-unknown-linux-gnu/bin/rustc
+    path: String,
+    source: PathBuf,
+    mode: u32,
+    size: u64,
+    // sha256 of regular files, empty otherwise
+    digest: String,
+    link_to: String,
+}
+
+// Everything below `prefix` in `root`, the prefix itself included, sorted by path as rpm expects
+fn package_files(root: &Path, prefix: &Path) -> Result<Vec<PackageFile>> {
+    let prefix_dir = root.join(prefix.strip_prefix("/").unwrap_or(prefix));
+    let mut files = vec![PackageFile {
+        path: prefix.display().to_string(),
+        source: prefix_dir.clone(),
+        mode: S_IFDIR | 0o755,
+        size: 0,
+        digest: String::new(),
+        link_to: String::new(),
+    }];
+    for rel_path in archive::sorted_entries(&prefix_dir)? {
+        let source = prefix_dir.join(&rel_path);
+        let metadata = fs::symlink_metadata(&source)?;
+        let path = prefix.join(&rel_path).display().to_string();
+        let file = if metadata.file_type().is_symlink() {
+            let link_to = fs::read_link(&source)?.display().to_string();
+            PackageFile { path, source, mode: S_IFLNK | 0o777, size: link_to.len() as u64, digest: String::new(), link_to }
+        } else if metadata.is_dir() {
+            PackageFile { path, source, mode: S_IFDIR | 0o755, size: 0, digest: String::new(), link_to: String::new() }
+        } else {
+            let mode = S_IFREG | if archive::is_executable(&metadata) { 0o755 } else { 0o644 };
+            let digest = utils::sha256_file(&source)?;
+            PackageFile { path, source, mode, size: metadata.len(), digest, link_to: String::new() }
+        };
+        files.push(file);
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn main_header(metadata: &BundleMetadata, architecture: &str, files: &[PackageFile], payload_sha256: &str) -> Result<RpmHeader> {
+    let target = &metadata.config.target;
+    let name = target.package_name();
+    let version = target.package_version();
+    let mtime = to_u32(archive::archive_mtime())?;
+
+    let mut dirnames: Vec<String> = Vec::new();
+    let mut dirindexes = Vec::new();
+    let mut basenames = Vec::new();
+    for file in files {
+        let (dir, base) = file.path.rsplit_once('/').unwrap_or(("", &file.path));
+        let dir = format!("{}/", dir);
+        let index = match dirnames.iter().position(|dirname| *dirname == dir) {
+            Some(index) => index,
+            None => {
+                dirnames.push(dir);
+                dirnames.len() - 1
+            }
+        };
+        dirindexes.push(index as u32);
+        basenames.push(base.to_string());
+    }
+
+    let mut requires: Vec<(&str, &str, u32)> = vec![("/bin/sh", "", 0)];
+    requires.extend(RPMLIB_REQUIRES.iter().map(|(name, version)| (*name, *version, RPMSENSE_RPMLIB | RPMSENSE_LESS | RPMSENSE_EQUAL)));
+
+    let mut header = RpmHeader::default();
+    header.insert(RPMTAG_HEADERI18NTABLE, Value::StringArray(vec!["C".to_string()]));
+    header.insert(RPMTAG_NAME, Value::String(name.clone()));
+    header.insert(RPMTAG_VERSION, Value::String(version.clone()));
+    header.insert(RPMTAG_RELEASE, Value::String("1".to_string()));
+    header.insert(RPMTAG_SUMMARY, Value::I18nString(format!("Rust {} toolchain", metadata.toolchain)));
+    header.insert(
+        RPMTAG_DESCRIPTION,
+        Value::I18nString(format!(
+            "Installs {} into {} and registers it with rustup when available. Packaged by {}.",
+            metadata.components.join(", "),
+            installer::package_prefix(metadata).display(),
+            metadata.generator
+        )),
+    );
+    header.insert(RPMTAG_BUILDTIME, Value::Int32(vec![mtime]));
+    header.insert(RPMTAG_SIZE, Value::Int32(vec![to_u32(files.iter().map(|file| file.size).sum())?]));
+    header.insert(RPMTAG_LICENSE, Value::String("MIT or Apache-2.0".to_string()));
+    header.insert(RPMTAG_GROUP, Value::I18nString("Development/Languages".to_string()));
+    header.insert(RPMTAG_OS, Value::String("linux".to_string()));
+    header.insert(RPMTAG_ARCH, Value::String(architecture.to_string()));
+    header.insert(RPMTAG_POSTIN, Value::String(installer::rustup_link_script(metadata)));
+    header.insert(RPMTAG_PREUN, Value::String(installer::rustup_unlink_script(metadata)));
+    header.insert(RPMTAG_FILESIZES, Value::Int32(files.iter().map(|file| to_u32(file.size)).collect::<Result<_>>()?));
+    header.insert(RPMTAG_FILEMODES, Value::Int16(files.iter().map(|file| file.mode as u16).collect()));
+    header.insert(RPMTAG_FILERDEVS, Value::Int16(vec![0; files.len()]));
+    header.insert(RPMTAG_FILEMTIMES, Value::Int32(vec![mtime; files.len()]));
+    header.insert(RPMTAG_FILEDIGESTS, Value::StringArray(files.iter().map(|file| file.digest.clone()).collect()));
+    header.insert(RPMTAG_FILELINKTOS, Value::StringArray(files.iter().map(|file| file.link_to.clone()).collect()));
+    header.insert(RPMTAG_FILEFLAGS, Value::Int32(vec![0; files.len()]));
+    header.insert(RPMTAG_FILEUSERNAME, Value::StringArray(vec!["root".to_string(); files.len()]));
+    header.insert(RPMTAG_FILEGROUPNAME, Value::StringArray(vec!["root".to_string(); files.len()]));
+    // rpm tells binary packages from source ones by this tag
+    header.insert(RPMTAG_SOURCERPM, Value::String(format!("{}-{}-1.src.rpm", name, version)));
+    header.insert(RPMTAG_PROVIDENAME, Value::StringArray(vec![name]));
+    header.insert(RPMTAG_REQUIREFLAGS, Value::Int32(requires.iter().map(|(_, _, flags)| *flags).collect()));
+    header.insert(RPMTAG_REQUIRENAME, Value::StringArray(requires.iter().map(|(name, _, _)| name.to_string()).collect()));
+    header.insert(RPMTAG_REQUIREVERSION, Value::StringArray(requires.iter().map(|(_, version, _)| version.to_string()).collect()));
+    header.insert(RPMTAG_POSTINPROG, Value::String("/bin/sh".to_string()));
+    header.insert(RPMTAG_PREUNPROG, Value::String("/bin/sh".to_string()));
+    header.insert(RPMTAG_FILEDEVICES, Value::Int32(vec![1; files.len()]));
+    header.insert(RPMTAG_FILEINODES, Value::Int32((1..=files.len() as u32).collect()));
+    header.insert(RPMTAG_PROVIDEFLAGS, Value::Int32(vec![RPMSENSE_EQUAL]));
+    header.insert(RPMTAG_PROVIDEVERSION, Value::StringArray(vec![format!("{}-1", version)]));
+    header.insert(RPMTAG_DIRINDEXES, Value::Int32(dirindexes));
+    header.insert(RPMTAG_BASENAMES, Value::StringArray(basenames));
+    header.insert(RPMTAG_DIRNAMES, Value::StringArray(dirnames));
+    header.insert(RPMTAG_PAYLOADFORMAT, Value::String("cpio".to_string()));
+    header.insert(RPMTAG_PAYLOADCOMPRESSOR, Value::String("gzip".to_string()));
+    header.insert(RPMTAG_PAYLOADFLAGS, Value::String(archive::COMPRESSION_LEVEL.to_string()));
+    header.insert(RPMTAG_FILEDIGESTALGO, Value::Int32(vec![PGPHASHALGO_SHA256]));
+    header.insert(RPMTAG_PAYLOADDIGEST, Value::StringArray(vec![payload_sha256.to_string()]));
+    header.insert(RPMTAG_PAYLOADDIGESTALGO, Value::Int32(vec![PGPHASHALGO_SHA256]));
+    Ok(header)
+}
+
+// The legacy lead rpm still expects in front of the signature header
+fn lead(nevra: &str) -> Vec<u8> {
+    let mut lead = Vec::with_capacity(96);
+    lead.extend_from_slice(&LEAD_MAGIC);
+    lead.extend_from_slice(&[3, 0]);
+    // binary package, architecture number unused by rpm
+    lead.extend_from_slice(&0u16.to_be_bytes());
+    lead.extend_from_slice(&0u16.to_be_bytes());
+    let mut name = [0u8; 66];
+    let len = nevra.len().min(65);
+    name[..len].copy_from_slice(&nevra.as_bytes()[..len]);
+    lead.extend_from_slice(&name);
+    // linux
+    lead.extend_from_slice(&1u16.to_be_bytes());
+    lead.extend_from_slice(&RPMSIGTYPE_HEADERSIG.to_be_bytes());
+    lead.extend_from_slice(&[0; 16]);
+    lead
+}
+
+// The `newc` cpio archive rpm payloads are, with paths relative to the root
+fn write_cpio<W: Write>(writer: &mut W, files: &[PackageFile]) -> Result<()> {
+    let mtime = archive::archive_mtime();
+    for (index, file) in files.iter().enumerate() {
+        write_cpio_header(writer, &format!(".{}", file.path), index as u64 + 1, file.mode, mtime, file.size)?;
+        match file.mode & S_IFMT {
+            S_IFLNK => writer.write_all(file.link_to.as_bytes())?,
+            S_IFREG => {
+                io::copy(&mut File::open(&file.source)?, writer)?;
+            }
+            _ => {}
+        }
+        write_padding(writer, file.size as usize)?;
+    }
+    write_cpio_header(writer, "TRAILER!!!", 0, 0, 0, 0)?;
+    Ok(())
+}
+
+fn write_cpio_header<W: Write>(writer: &mut W, name: &str, inode: u64, mode: u32, mtime: u64, size: u64) -> Result<()> {
+    let nlink = if mode & S_IFMT == S_IFDIR { 2 } else { 1 };
+    let fields = [inode, mode as u64, 0, 0, nlink, mtime, size, 0, 0, 0, 0, name.len() as u64 + 1, 0];
+    let mut header = String::from("070701");
+    for field in fields {
+        header.push_str(&format!("{:08x}", to_u32(field)?));
+    }
+    writer.write_all(header.as_bytes())?;
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(&[0])?;
+    write_padding(writer, header.len() + name.len() + 1)?;
+    Ok(())
+}
+
+// Pad what follows `len` bytes to a 4 byte boundary
+fn write_padding<W: Write>(writer: &mut W, len: usize) -> io::Result<()> {
+    writer.write_all(&[0; 3][..len.next_multiple_of(4) - len])
+}
+
+fn to_u32(value: u64) -> Result<u32> {
+    u32::try_from(value).context("too large for an RPM package")
+}
+
+enum Value {
+    Int16(Vec<u16>),
+    Int32(Vec<u32>),
+    String(String),
+    Bin(Vec<u8>),
+    StringArray(Vec<String>),
+    I18nString(String),
+}
+
+impl Value {
+    // rpm type number, byte alignment and count
+    fn kind(&self) -> (u32, usize, usize) {
+        match self {
+            Value::Int16(values) => (3, 2, values.len()),
+            Value::Int32(values) => (4, 4, values.len()),
+            Value::String(_) => (6, 1, 1),
+            Value::Bin(bytes) => (7, 1, bytes.len()),
+            Value::StringArray(values) => (8, 1, values.len()),
+            Value::I18nString(_) => (9, 1, 1),
+        }
+    }
+
+    fn write(&self, data: &mut Vec<u8>) {
+        match self {
+            Value::Int16(values) => values.iter().for_each(|value| data.extend_from_slice(&value.to_be_bytes())),
+            Value::Int32(values) => values.iter().for_each(|value| data.extend_from_slice(&value.to_be_bytes())),
+            Value::String(value) | Value::I18nString(value) => {
+                data.extend_from_slice(value.as_bytes());
+                data.push(0);
+            }
+            Value::Bin(bytes) => data.extend_from_slice(bytes),
+            Value::StringArray(values) => {
+                for value in values {
+                    data.extend_from_slice(value.as_bytes());
+                    data.push(0);
+                }
+            }
+        }
+    }
+}
+
+// An rpm header: tags sorted by number, each with its typed value
+#[derive(Default)]
+struct RpmHeader {
+    entries: BTreeMap<u32, Value>,
+}
+
+impl RpmHeader {
+    fn insert(&mut self, tag: u32, value: Value) {
+        self.entries.insert(tag, value);
+    }
+
+    // The header with a leading `region_tag` entry covering every tag, as rpmbuild writes it
+    fn to_bytes(&self, region_tag: u32) -> Vec<u8> {
+        let mut index = Vec::new();
+        let mut data = Vec::new();
+        for (tag, value) in &self.entries {
+            let (kind, alignment, count) = value.kind();
+            data.resize(data.len().next_multiple_of(alignment), 0);
+            index.push((*tag, kind, data.len() as u32, count as u32));
+            value.write(&mut data);
+        }
+
+        let entries = index.len() as i32 + 1;
+        let region_offset = data.len() as u32;
+        data.extend_from_slice(&region_tag.to_be_bytes());
+        data.extend_from_slice(&7u32.to_be_bytes());
+        data.extend_from_slice(&(-entries * 16).to_be_bytes());
+        data.extend_from_slice(&16u32.to_be_bytes());
+        index.insert(0, (region_tag, 7, region_offset, 16));
+
+        let mut header = HEADER_MAGIC.to_vec();
+        header.extend_from_slice(&(entries as u32).to_be_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        for (tag, kind, offset, count) in index {
+            for field in [tag, kind, offset, count] {
+                header.extend_from_slice(&field.to_be_bytes());
+            }
+        }
+        header.extend_from_slice(&data);
+        header
+    }
+}
+
+// Counts the uncompressed payload size the signature records
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{bundle_metadata, linux_target, temp_dir, write_files};
+
+    // Tag -> raw value bytes of the header starting at `bytes`, and the header length
+    fn read_header(bytes: &[u8]) -> (BTreeMap<u32, Vec<u8>>, usize) {
+        let be = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(bytes[..8], HEADER_MAGIC);
+        let (entries, data_len) = (be(8) as usize, be(12) as usize);
+        let data = &bytes[16 + entries * 16..16 + entries * 16 + data_len];
+        let mut offsets: Vec<(u32, usize)> = (0..entries).map(|i| (be(16 + i * 16), be(16 + i * 16 + 8) as usize)).collect();
+        offsets.sort_by_key(|(_, offset)| *offset);
+        let mut tags = BTreeMap::new();
+        for (i, (tag, offset)) in offsets.iter().enumerate() {
+            let end = offsets.get(i + 1).map_or(data.len(), |(_, next)| *next);
+            tags.insert(*tag, data[*offset..end].to_vec());
+        }
+        (tags, 16 + entries * 16 + data_len)
+    }
+
+    #[test]
+    fn test_write_rpm() {
+        let root = temp_dir("rustup-distribution-test-rpm");
+        let dir = root.join("rust-1.75.0-x86_64-unknown-linux-gnu");
+        write_files(
+            &dir,
+            &[
+                ("components", "rustc\ngrcov-0.8.18\n"),
+                ("rustc/manifest.in", "file:bin/rustc\ndir:share/doc/rust\n"),
+                ("rustc/bin/rustc", "rustc"),
+                ("rustc/share/doc/rust/README.md", "readme"),
+                ("rustc/share/doc/unlisted", "not in manifest.in"),
+                ("grcov-0.8.18/manifest.in", "file:bin/grcov\n"),
+                ("grcov-0.8.18/bin/grcov", "grcov"),
+            ],
+        );
+        let metadata = bundle_metadata(&dir, linux_target("1.75.0", None), &[]);
+
+        let rpm = root.join("rust-1.75.0-x86_64-unknown-linux-gnu.rpm");
+        write_rpm(&dir, &rpm, &metadata).unwrap();
+        let content = fs::read(&rpm).unwrap();
+        assert_eq!(content[..4], LEAD_MAGIC);
+        assert!(content[10..].starts_with(b"rust-1.75.0-x86-64-unknown-linux-gnu-1.75.0-1\0"));
+
+        let (signature, signature_len) = read_header(&content[96..]);
+        let (header, header_len) = read_header(&content[96 + signature_len.next_multiple_of(8)..]);
+        let main = &content[96 + signature_len.next_multiple_of(8)..];
+        // values run up to the next one, alignment padding included
+        assert!(signature[&RPMSIGTAG_SHA256].starts_with(format!("{}\0", utils::to_hex(&Sha256::digest(&main[..header_len]))).as_bytes()));
+        assert_eq!(header[&RPMTAG_NAME], b"rust-1.75.0-x86-64-unknown-linux-gnu\0");
+        assert_eq!(header[&RPMTAG_VERSION], b"1.75.0\0");
+        assert_eq!(header[&RPMTAG_ARCH], b"x86_64\0");
+        let basenames = String::from_utf8(header[&RPMTAG_BASENAMES].clone()).unwrap();
+        let basenames: Vec<&str> = basenames.trim_end_matches('\0').split('\0').collect();
+        assert_eq!(
+            basenames,
+            [
+                "1.75.0-x86_64-unknown-linux-gnu",
+                "bin",
+                "grcov",
+                "rustc",
+                "lib",
+                "rustlib",
+                "components",
+                "manifest-grcov-0.8.18",
+                "manifest-rustc",
+                "rust-installer-version",
+                "share",
+                "doc",
+                "rust",
+                "README.md"
+            ]
+        );
+
+        // and libarchive finds the same files in the payload, when bsdtar is installed
+        if utils::find_program("bsdtar").is_some() {
+            let output = std::process::Command::new("bsdtar").arg("-tf").arg(&rpm).output().unwrap();
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            let listed = String::from_utf8(output.stdout).unwrap();
+            assert_eq!(listed.lines().count(), basenames.len());
+            assert!(listed.contains("./opt/rust/1.75.0-x86_64-unknown-linux-gnu/bin/rustc\n"));
+            let extracted = root.join("extracted");
+            fs::create_dir_all(&extracted).unwrap();
+            assert!(std::process::Command::new("bsdtar").arg("-xf").arg(&rpm).arg("-C").arg(&extracted).status().unwrap().success());
+            let prefix = extracted.join("opt/rust/1.75.0-x86_64-unknown-linux-gnu");
+            assert_eq!(fs::read_to_string(prefix.join("bin/grcov")).unwrap(), "grcov");
+            assert_eq!(fs::read_to_string(prefix.join("share/doc/rust/README.md")).unwrap(), "readme");
+            assert!(!prefix.join("share/doc/unlisted").exists());
+        }
+
+        assert_eq!(rpm_architecture("i586-unknown-linux-gnu"), Some("i586"));
+        assert_eq!(rpm_architecture("i686-unknown-linux-musl"), Some("i686"));
+        assert_eq!(rpm_architecture("arm-unknown-linux-gnueabi"), Some("armv6l"));
+        assert_eq!(rpm_architecture("arm-unknown-linux-gnueabihf"), Some("armv6hl"));
+        assert_eq!(rpm_architecture("armv5te-unknown-linux-gnueabi"), Some("armv5tel"));
+        assert_eq!(rpm_architecture("armv7-unknown-linux-gnueabihf"), Some("armv7hl"));
+        assert_eq!(rpm_architecture("x86_64-apple-darwin"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
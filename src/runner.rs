@@ -0,0 +1,402 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, info};
+
+use crate::{report, Message, COMMAND_OUTPUT_TARGET};
+
+// Lines of stdout and stderr kept in the message of a failed command
+const OUTPUT_TAIL_LINES: usize = 40;
+
+// How often a command with a timeout is checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// How long the rest of the output of a killed command is waited for
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+// A program to run, with the environment, working directory and timeout to run it with
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandSpec {
+    pub program: String,
+    pub args: Vec<String>,
+    // set on top of the inherited environment
+    pub env: Vec<(String, String)>,
+    pub current_dir: Option<PathBuf>,
+    // `None` uses the default of the runner
+    pub timeout: Option<Duration>,
+}
+
+impl CommandSpec {
+    pub fn new(program: &str, args: Vec<String>) -> Self {
+        CommandSpec { program: program.to_string(), args, ..CommandSpec::default() }
+    }
+
+    pub fn rustup(args: Vec<String>) -> Self {
+        Self::new("rustup", args)
+    }
+
+    pub fn cargo(args: Vec<String>) -> Self {
+        Self::new("cargo", args)
+    }
+
+    pub fn tar(args: Vec<String>) -> Self {
+        Self::new("tar", args)
+    }
+
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn current_dir(mut self, dir: &Path) -> Self {
+        self.current_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl fmt::Display for CommandSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    Spawn {
+        command: String,
+        source: io::Error,
+    },
+    Failed {
+        command: String,
+        // `None` when the process was killed by a signal
+        exit_status: Option<i32>,
+        stdout: String,
+        stderr: String,
+    },
+    TimedOut {
+        command: String,
+        timeout: Duration,
+        stdout: String,
+        stderr: String,
+    },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Spawn { command, source } => write!(f, "failed to run `{}`: {}", command, source),
+            CommandError::Failed { command, exit_status, stdout, stderr } => {
+                match exit_status {
+                    Some(code) => write!(f, "`{}` failed with exit status {}", command, code)?,
+                    None => write!(f, "`{}` was terminated by a signal", command)?,
+                }
+                write_output_tail(f, "stdout", stdout)?;
+                write_output_tail(f, "stderr", stderr)
+            }
+            CommandError::TimedOut { command, timeout, stdout, stderr } => {
+                write!(f, "`{}` timed out after {}s", command, timeout.as_secs())?;
+                write_output_tail(f, "stdout", stdout)?;
+                write_output_tail(f, "stderr", stderr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Spawn { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn write_output_tail(f: &mut fmt::Formatter<'_>, name: &str, output: &str) -> fmt::Result {
+    let lines: Vec<&str> = output.lines().collect();
+    if lines.is_empty() {
+        return Ok(());
+    }
+    write!(f, "\n--- {}", name)?;
+    if lines.len() > OUTPUT_TAIL_LINES {
+        write!(f, " (last {} of {} lines)", OUTPUT_TAIL_LINES, lines.len())?;
+    }
+    for line in &lines[lines.len().saturating_sub(OUTPUT_TAIL_LINES)..] {
+        write!(f, "\n{}", line)?;
+    }
+    Ok(())
+}
+
+pub trait Runner {
+    fn run(&self, command: &CommandSpec) -> Result<CommandOutput, CommandError>;
+}
+
+// Runs commands as child processes. Their output is logged line by line and captured.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessRunner {
+    // applied to commands that do not set their own
+    pub timeout: Option<Duration>,
+}
+
+impl Runner for ProcessRunner {
+    fn run(&self, command: &CommandSpec) -> Result<CommandOutput, CommandError> {
+        let command_line = command.to_string();
+        report::emit(Message::CommandStarted { program: command.program.clone(), args: command.args.clone() });
+        debug!("Running {}", command_line);
+
+        let mut process = Command::new(&command.program);
+        process.args(&command.args).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        process.envs(command.env.iter().map(|(key, value)| (key, value)));
+        if let Some(dir) = &command.current_dir {
+            process.current_dir(dir);
+        }
+        let mut child = process
+            .spawn()
+            .map_err(|source| CommandError::Spawn { command: command_line.clone(), source })?;
+
+        let stdout = Arc::new(Mutex::new(String::new()));
+        let stderr = Arc::new(Mutex::new(String::new()));
+        let forwarders: Vec<_> = [
+            child.stdout.take().map(|out| forward_output(&command.program, "stdout", out, stdout.clone())),
+            child.stderr.take().map(|err| forward_output(&command.program, "stderr", err, stderr.clone())),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        let status = match command.timeout.or(self.timeout) {
+            None => Some(child.wait()),
+            Some(timeout) => wait_timeout(&mut child, timeout),
+        };
+        let status = match status {
+            Some(status) => status.map_err(|source| CommandError::Spawn { command: command_line.clone(), source })?,
+            None => {
+                // Processes spawned by the killed one may still hold the pipes open, only wait
+                // for the rest of the output for a moment and keep what was read so far
+                join_forwarders(forwarders, Some(Instant::now() + KILL_GRACE_PERIOD));
+                let timeout = command.timeout.or(self.timeout).unwrap_or_default();
+                error!("{} timed out after {}s", command_line, timeout.as_secs());
+                replay_output(&command.program, &stdout, &stderr);
+                report::emit(Message::CommandFinished {
+                    program: command.program.clone(),
+                    args: command.args.clone(),
+                    success: false,
+                    exit_status: None,
+                });
+                return Err(CommandError::TimedOut {
+                    command: command_line,
+                    timeout,
+                    stdout: take_output(&stdout),
+                    stderr: take_output(&stderr),
+                });
+            }
+        };
+        join_forwarders(forwarders, None);
+
+        report::emit(Message::CommandFinished {
+            program: command.program.clone(),
+            args: command.args.clone(),
+            success: status.success(),
+            exit_status: status.code(),
+        });
+
+        if status.success() {
+            info!("Execute {} command succeeded", command.program);
+            Ok(CommandOutput { stdout: take_output(&stdout), stderr: take_output(&stderr) })
+        } else {
+            error!(exit_status = ?status.code(), "Execute {} command failed", command.program);
+            replay_output(&command.program, &stdout, &stderr);
+            Err(CommandError::Failed {
+                command: command_line,
+                exit_status: status.code(),
+                stdout: take_output(&stdout),
+                stderr: take_output(&stderr),
+            })
+        }
+    }
+}
+
+// `None` when the child was killed after `timeout`
+fn wait_timeout(child: &mut std::process::Child, timeout: Duration) -> Option<io::Result<std::process::ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(Ok(status)),
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(err) => return Some(Err(err)),
+        }
+    }
+}
+
+// Wait for the threads forwarding the output of a finished command, until `deadline` if any
+fn join_forwarders(forwarders: Vec<thread::JoinHandle<()>>, deadline: Option<Instant>) {
+    for forwarder in forwarders {
+        if let Some(deadline) = deadline {
+            while !forwarder.is_finished() && Instant::now() < deadline {
+                thread::sleep(POLL_INTERVAL);
+            }
+            if !forwarder.is_finished() {
+                continue;
+            }
+        }
+        let _ = forwarder.join();
+    }
+}
+
+// The output of a command is logged at info, hidden by `-q`. Log the tail of it again
+// at error when the command fails, so the reason is shown whatever the verbosity.
+fn replay_output(program: &str, stdout: &Mutex<String>, stderr: &Mutex<String>) {
+    for (stream, output) in [("stdout", stdout), ("stderr", stderr)] {
+        let output = output.lock().unwrap_or_else(|err| err.into_inner());
+        let lines: Vec<&str> = output.lines().collect();
+        for line in &lines[lines.len().saturating_sub(OUTPUT_TAIL_LINES)..] {
+            error!(target: COMMAND_OUTPUT_TARGET, program = %program, stream, "{}", line);
+        }
+    }
+}
+
+fn take_output(output: &Mutex<String>) -> String {
+    std::mem::take(&mut *output.lock().unwrap_or_else(|err| err.into_inner()))
+}
+
+fn forward_output(
+    program: &str,
+    stream: &'static str,
+    output: impl Read + Send + 'static,
+    captured: Arc<Mutex<String>>,
+) -> thread::JoinHandle<()> {
+    // Spans are per thread, carry the caller's target or tool span over
+    let span = tracing::Span::current();
+    let program = program.to_string();
+    thread::spawn(move || {
+        let _entered = span.enter();
+        let mut reader = BufReader::new(output);
+        let mut line = Vec::new();
+        while matches!(reader.read_until(b'\n', &mut line), Ok(read) if read > 0) {
+            let text = String::from_utf8_lossy(&line);
+            info!(target: COMMAND_OUTPUT_TARGET, program = %program, stream, "{}", text.trim_end());
+            captured.lock().unwrap_or_else(|err| err.into_inner()).push_str(&text);
+            line.clear();
+        }
+    })
+}
+
+type Handler = Box<dyn Fn(&CommandSpec) -> Result<CommandOutput, CommandError> + Send + Sync>;
+
+// Records commands instead of running them, for tests. The handler decides the outcome
+// and can create the files the real command would have written.
+#[derive(Default)]
+pub struct RecordingRunner {
+    commands: Mutex<Vec<CommandSpec>>,
+    handler: Option<Handler>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_handler(
+        handler: impl Fn(&CommandSpec) -> Result<CommandOutput, CommandError> + Send + Sync + 'static,
+    ) -> Self {
+        RecordingRunner { commands: Mutex::new(Vec::new()), handler: Some(Box::new(handler)) }
+    }
+
+    // Every command run so far, in order
+    pub fn commands(&self) -> Vec<CommandSpec> {
+        self.commands.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+}
+
+impl Runner for RecordingRunner {
+    fn run(&self, command: &CommandSpec) -> Result<CommandOutput, CommandError> {
+        self.commands.lock().unwrap_or_else(|err| err.into_inner()).push(command.clone());
+        match &self.handler {
+            Some(handler) => handler(command),
+            None => Ok(CommandOutput::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_runner_captures_output() {
+        let runner = ProcessRunner::default();
+        let command = CommandSpec::new("sh", vec!["-c".to_string(), "echo \"$GREETING\"; pwd".to_string()])
+            .env("GREETING", "hello")
+            .current_dir(&std::env::temp_dir());
+        let output = runner.run(&command).unwrap();
+        let mut lines = output.stdout.lines();
+        assert_eq!(lines.next(), Some("hello"));
+        assert_eq!(
+            lines.next().map(|dir| std::fs::canonicalize(dir).unwrap()),
+            Some(std::fs::canonicalize(std::env::temp_dir()).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_process_runner_failure() {
+        let runner = ProcessRunner::default();
+        let command = CommandSpec::new("sh", vec!["-c".to_string(), "echo out; echo broken >&2; exit 3".to_string()]);
+        let err = runner.run(&command).unwrap_err();
+        assert!(matches!(&err, CommandError::Failed { exit_status: Some(3), stderr, .. } if stderr == "broken\n"));
+        assert!(err.to_string().contains("--- stderr\nbroken"));
+
+        let missing = runner.run(&CommandSpec::new("rustup-distribution-no-such-program", Vec::new()));
+        assert!(matches!(missing, Err(CommandError::Spawn { .. })));
+    }
+
+    #[test]
+    fn test_process_runner_timeout() {
+        let runner = ProcessRunner { timeout: Some(Duration::from_millis(200)) };
+        let command = CommandSpec::new("sh", vec!["-c".to_string(), "echo started; exec sleep 10".to_string()]);
+        let started = Instant::now();
+        let err = runner.run(&command).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(err, CommandError::TimedOut { stdout, .. } if stdout == "started\n"));
+    }
+
+    #[test]
+    fn test_recording_runner() {
+        let runner = RecordingRunner::with_handler(|command| match command.program.as_str() {
+            "cargo" => Err(CommandError::Failed {
+                command: command.to_string(),
+                exit_status: Some(101),
+                stdout: String::new(),
+                stderr: "error: could not compile".to_string(),
+            }),
+            _ => Ok(CommandOutput::default()),
+        });
+        assert!(runner.run(&CommandSpec::tar(vec!["zxvf".to_string()])).is_ok());
+        assert!(runner.run(&CommandSpec::cargo(vec!["install".to_string()])).is_err());
+        let programs: Vec<String> = runner.commands().into_iter().map(|command| command.program).collect();
+        assert_eq!(programs, vec!["tar", "cargo"]);
+    }
+}
@@ -0,0 +1,460 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{utils, BundleMetadata, ChannelManifest};
+
+// License of every component shipped in the official Rust toolchain
+const RUST_TOOLCHAIN_LICENSE: &str = "MIT OR Apache-2.0";
+const CRATES_IO_SOURCE: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SbomFormat {
+    Cyclonedx,
+    Spdx,
+}
+
+impl SbomFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SbomFormat::Cyclonedx => "cdx.json",
+            SbomFormat::Spdx => "spdx.json",
+        }
+    }
+
+    // The document written next to `bundle`
+    pub fn path_for(&self, bundle: &Path) -> PathBuf {
+        let mut path = bundle.as_os_str().to_owned();
+        path.push(format!(".{}", self.extension()));
+        PathBuf::from(path)
+    }
+}
+
+// A package described by the SBOM, either a toolchain component or a crate
+#[derive(Clone, Debug)]
+pub struct SbomPackage {
+    pub name: String,
+    pub version: String,
+    pub purl: String,
+    pub license: Option<String>,
+    pub sha256: Option<String>,
+    pub download_url: Option<String>,
+    // `application` for toolchain components and installed tools, `library` for their dependencies
+    pub kind: &'static str,
+    // purls of the direct dependencies
+    pub depends_on: Vec<String>,
+}
+
+// Bundle level information, the root of the dependency graph
+#[derive(Clone, Debug)]
+pub struct SbomBundle {
+    pub name: String,
+    pub file_name: String,
+    pub sha256: String,
+    pub created: String,
+    pub packages: Vec<SbomPackage>,
+}
+
+#[derive(Deserialize)]
+struct CargoLock {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+    checksum: Option<String>,
+    #[serde(default)]
+    dependencies: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CrateManifest {
+    package: CrateManifestPackage,
+}
+
+#[derive(Deserialize)]
+struct CrateManifestPackage {
+    license: Option<String>,
+}
+
+impl SbomBundle {
+    // Describe the toolchain components found in the bundle, with versions and hashes from the
+    // channel manifest, and every extra tool with the dependency tree of its `Cargo.lock`.
+    pub fn new(bundle: &Path, metadata: &BundleMetadata, manifest: &ChannelManifest) -> Result<Self> {
+        let target = &metadata.config.target.target;
+        let mut packages = Vec::new();
+
+        for component in &metadata.components {
+            let Some((name, pkg)) = manifest.package_for_component(component, target) else {
+                continue;
+            };
+            if pkg.short_version().is_empty() {
+                bail!("component {} has no version in the channel manifest", component);
+            }
+            let manifest_target = pkg.for_target(target);
+            packages.push(SbomPackage {
+                name: name.clone(),
+                version: pkg.short_version().to_string(),
+                purl: format!("pkg:generic/rust-lang/{}@{}", name, pkg.short_version()),
+                license: Some(RUST_TOOLCHAIN_LICENSE.to_string()),
+                sha256: manifest_target.and_then(|t| t.hash.clone()),
+                download_url: manifest_target.and_then(|t| t.url.clone()),
+                kind: "application",
+                depends_on: Vec::new(),
+            });
+        }
+
+        // `cargo install --locked` built each tool from the `Cargo.lock` it was published with
+        for tool in &metadata.tools {
+            match tool.version.as_deref().filter(|version| !version.is_empty()) {
+                Some(version) => packages.extend(crate_packages(&tool.name, version)),
+                None => bail!("tool {} has no resolved version to describe", tool.name),
+            }
+        }
+
+        Ok(SbomBundle {
+            name: metadata.toolchain.clone(),
+            file_name: bundle.file_name().unwrap_or_default().to_string_lossy().to_string(),
+            sha256: utils::sha256_file(bundle).context("hashing bundle")?,
+            created: utils::format_rfc3339(utils::source_date_epoch()),
+            packages,
+        })
+    }
+
+    // Write one document per format next to the bundle, returning their paths
+    pub fn write(&self, bundle: &Path, formats: &[SbomFormat]) -> Result<Vec<PathBuf>> {
+        let mut written = Vec::new();
+        for format in formats {
+            let document = match format {
+                SbomFormat::Cyclonedx => self.cyclonedx(),
+                SbomFormat::Spdx => self.spdx(),
+            };
+            let path = format.path_for(bundle);
+            fs::write(&path, serde_json::to_string_pretty(&document)?)
+                .with_context(|| format!("writing sbom {}", path.display()))?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    fn roots(&self) -> Vec<&SbomPackage> {
+        let dependencies: Vec<&String> = self.packages.iter().flat_map(|p| &p.depends_on).collect();
+        self.packages.iter().filter(|p| !dependencies.contains(&&p.purl)).collect()
+    }
+
+    pub fn cyclonedx(&self) -> Value {
+        let bundle_ref = format!("bundle:{}", self.file_name);
+        let components: Vec<Value> = self
+            .packages
+            .iter()
+            .map(|package| {
+                let mut component = json!({
+                    "type": package.kind,
+                    "bom-ref": package.purl,
+                    "name": package.name,
+                    "version": package.version,
+                    "purl": package.purl,
+                });
+                if let Some(license) = &package.license {
+                    component["licenses"] = json!([{ "expression": license }]);
+                }
+                if let Some(sha256) = &package.sha256 {
+                    component["hashes"] = json!([{ "alg": "SHA-256", "content": sha256 }]);
+                }
+                if let Some(url) = &package.download_url {
+                    component["externalReferences"] = json!([{ "type": "distribution", "url": url }]);
+                }
+                component
+            })
+            .collect();
+
+        let mut dependencies = vec![json!({
+            "ref": bundle_ref,
+            "dependsOn": self.roots().iter().map(|p| &p.purl).collect::<Vec<_>>(),
+        })];
+        dependencies.extend(
+            self.packages
+                .iter()
+                .map(|package| json!({ "ref": package.purl, "dependsOn": package.depends_on })),
+        );
+
+        json!({
+            "bomFormat": "CycloneDX",
+            "specVersion": "1.5",
+            "version": 1,
+            "metadata": {
+                "timestamp": self.created,
+                "tools": [{ "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") }],
+                "component": {
+                    "type": "application",
+                    "bom-ref": bundle_ref,
+                    "name": self.name,
+                    "hashes": [{ "alg": "SHA-256", "content": self.sha256 }],
+                },
+            },
+            "components": components,
+            "dependencies": dependencies,
+        })
+    }
+
+    pub fn spdx(&self) -> Value {
+        let spdx_ids: BTreeMap<&String, String> = self
+            .packages
+            .iter()
+            .enumerate()
+            .map(|(index, package)| (&package.purl, format!("SPDXRef-Package-{}", index + 1)))
+            .collect();
+
+        let mut packages = vec![json!({
+            "SPDXID": "SPDXRef-Bundle",
+            "name": self.name,
+            "packageFileName": self.file_name,
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": false,
+            "licenseConcluded": "NOASSERTION",
+            "licenseDeclared": "NOASSERTION",
+            "copyrightText": "NOASSERTION",
+            "checksums": [{ "algorithm": "SHA256", "checksumValue": self.sha256 }],
+        })];
+        packages.extend(self.packages.iter().map(|package| {
+            let mut spdx_package = json!({
+                "SPDXID": spdx_ids[&package.purl],
+                "name": package.name,
+                "versionInfo": package.version,
+                "downloadLocation": package.download_url.as_deref().unwrap_or("NOASSERTION"),
+                "filesAnalyzed": false,
+                "licenseConcluded": "NOASSERTION",
+                "licenseDeclared": package.license.as_deref().unwrap_or("NOASSERTION"),
+                "copyrightText": "NOASSERTION",
+                "externalRefs": [{
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": package.purl,
+                }],
+            });
+            if let Some(sha256) = &package.sha256 {
+                spdx_package["checksums"] = json!([{ "algorithm": "SHA256", "checksumValue": sha256 }]);
+            }
+            spdx_package
+        }));
+
+        let mut relationships = vec![json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": "SPDXRef-Bundle",
+        })];
+        relationships.extend(self.roots().iter().map(|package| {
+            json!({
+                "spdxElementId": "SPDXRef-Bundle",
+                "relationshipType": "CONTAINS",
+                "relatedSpdxElement": spdx_ids[&package.purl],
+            })
+        }));
+        for package in &self.packages {
+            relationships.extend(package.depends_on.iter().filter_map(|dependency| {
+                Some(json!({
+                    "spdxElementId": spdx_ids[&package.purl],
+                    "relationshipType": "DEPENDS_ON",
+                    "relatedSpdxElement": spdx_ids.get(dependency)?,
+                }))
+            }));
+        }
+
+        json!({
+            "spdxVersion": "SPDX-2.3",
+            "dataLicense": "CC0-1.0",
+            "SPDXID": "SPDXRef-DOCUMENT",
+            "name": self.name,
+            "documentNamespace": format!("https://spdx.org/spdxdocs/{}-{}", self.name, self.sha256),
+            "creationInfo": {
+                "created": self.created,
+                "creators": [format!("Tool: {}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))],
+            },
+            "packages": packages,
+            "relationships": relationships,
+        })
+    }
+}
+
+fn crate_package(
+    name: &str,
+    version: &str,
+    license: Option<String>,
+    sha256: Option<String>,
+    depends_on: Vec<String>,
+    kind: &'static str,
+) -> SbomPackage {
+    SbomPackage {
+        name: name.to_string(),
+        version: version.to_string(),
+        purl: format!("pkg:cargo/{}@{}", name, version),
+        license,
+        download_url: sha256
+            .as_ref()
+            .map(|_| format!("https://static.crates.io/crates/{}/{}-{}.crate", name, name, version)),
+        sha256,
+        kind,
+        depends_on,
+    }
+}
+
+// The tool and its dependency tree, read from the sources `cargo install` left in the registry
+fn crate_packages(name: &str, version: &str) -> Vec<SbomPackage> {
+    let crate_dir = registry_source_dir(name, version);
+    let license = crate_dir.as_deref().and_then(crate_license);
+    let sha256 = registry_crate_file(name, version).and_then(|path| utils::sha256_file(&path).ok());
+
+    let lock = crate_dir
+        .map(|dir| dir.join("Cargo.lock"))
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| toml::from_str::<CargoLock>(&content).ok());
+    let Some(lock) = lock else {
+        return vec![crate_package(name, version, license, sha256, Vec::new(), "application")];
+    };
+
+    lock.package
+        .iter()
+        .map(|package| {
+            let depends_on = package
+                .dependencies
+                .iter()
+                .filter_map(|dependency| resolve_lock_dependency(&lock, dependency))
+                .collect();
+            let is_root = package.name == name && package.version == version;
+            let registry = package.source.as_deref() == Some(CRATES_IO_SOURCE);
+            let license = if is_root {
+                license.clone()
+            } else {
+                registry_source_dir(&package.name, &package.version).as_deref().and_then(crate_license)
+            };
+            let sha256 = if is_root { sha256.clone() } else { package.checksum.clone().filter(|_| registry) };
+            let kind = if is_root { "application" } else { "library" };
+            crate_package(&package.name, &package.version, license, sha256, depends_on, kind)
+        })
+        .collect()
+}
+
+// Lock file dependencies are written as `name`, `name version` or `name version (source)`
+fn resolve_lock_dependency(lock: &CargoLock, dependency: &str) -> Option<String> {
+    let mut parts = dependency.split(' ');
+    let name = parts.next()?;
+    let version = parts.next();
+    lock.package
+        .iter()
+        .find(|package| package.name == name && version.is_none_or(|version| package.version == version))
+        .map(|package| format!("pkg:cargo/{}@{}", package.name, package.version))
+}
+
+fn crate_license(crate_dir: &Path) -> Option<String> {
+    let content = fs::read_to_string(crate_dir.join("Cargo.toml")).ok()?;
+    toml::from_str::<CrateManifest>(&content).ok()?.package.license
+}
+
+// Look up `name-version` under every registry index of `$CARGO_HOME/registry/<kind>`
+fn find_in_registry(kind: &str, entry: &str) -> Option<PathBuf> {
+    let registry = utils::cargo_home()?.join("registry").join(kind);
+    fs::read_dir(registry)
+        .ok()?
+        .filter_map(|index| index.ok())
+        .map(|index| index.path().join(entry))
+        .find(|path| path.exists())
+}
+
+fn registry_source_dir(name: &str, version: &str) -> Option<PathBuf> {
+    find_in_registry("src", &format!("{}-{}", name, version))
+}
+
+fn registry_crate_file(name: &str, version: &str) -> Option<PathBuf> {
+    find_in_registry("cache", &format!("{}-{}.crate", name, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{bundle_metadata, linux_target, temp_dir, write_files};
+
+    fn bundle() -> SbomBundle {
+        let tool = crate_package("grcov", "0.8.18", Some("MPL-2.0".to_string()), None, vec!["pkg:cargo/log@0.4.19".to_string()], "application");
+        let log = crate_package("log", "0.4.19", Some("MIT OR Apache-2.0".to_string()), Some("cccc".to_string()), Vec::new(), "library");
+        let rustc = SbomPackage {
+            name: "rustc".to_string(),
+            version: "1.72.0-nightly".to_string(),
+            purl: "pkg:generic/rust-lang/rustc@1.72.0-nightly".to_string(),
+            license: Some(RUST_TOOLCHAIN_LICENSE.to_string()),
+            sha256: Some("aaaa".to_string()),
+            download_url: None,
+            kind: "application",
+            depends_on: Vec::new(),
+        };
+        SbomBundle {
+            name: "nightly-x86_64-unknown-linux-gnu".to_string(),
+            file_name: "rust-nightly-x86_64-unknown-linux-gnu.tar.gz".to_string(),
+            sha256: "ffff".to_string(),
+            created: "2023-06-25T00:00:00Z".to_string(),
+            packages: vec![rustc, tool, log],
+        }
+    }
+
+    #[test]
+    fn test_cyclonedx() {
+        let document = bundle().cyclonedx();
+        assert_eq!(document["components"].as_array().unwrap().len(), 3);
+        assert_eq!(document["components"][2]["hashes"][0]["content"], "cccc");
+        // only the toolchain component and the tool hang off the bundle
+        assert_eq!(document["dependencies"][0]["dependsOn"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_spdx() {
+        let document = bundle().spdx();
+        assert_eq!(document["packages"].as_array().unwrap().len(), 4);
+        let depends_on: Vec<&Value> = document["relationships"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|relationship| relationship["relationshipType"] == "DEPENDS_ON")
+            .collect();
+        assert_eq!(depends_on.len(), 1);
+        assert_eq!(depends_on[0]["relatedSpdxElement"], "SPDXRef-Package-3");
+    }
+
+    #[test]
+    fn test_reject_unresolved_tool_version() {
+        let dir = temp_dir("rustup-distribution-test-unresolved-tool");
+        write_files(&dir, &[("components", "rustc\n")]);
+        let mut metadata = bundle_metadata(&dir, linux_target("nightly", None), &[]);
+        fs::remove_dir_all(&dir).unwrap();
+        metadata.tools.push(crate::ToolMetadata { name: "grcov".to_string(), version: Some(String::new()) });
+        let manifest = ChannelManifest::parse("date = \"2024-01-01\"\n").unwrap();
+
+        let err = SbomBundle::new(Path::new("bundle.tar.gz"), &metadata, &manifest).err().unwrap();
+        assert!(err.to_string().contains("grcov has no resolved version"));
+    }
+
+    #[test]
+    fn test_resolve_lock_dependency() {
+        let lock: CargoLock = toml::from_str(
+            r#"
+[[package]]
+name = "log"
+version = "0.4.19"
+
+[[package]]
+name = "log"
+version = "0.3.9"
+"#,
+        )
+        .unwrap();
+        assert_eq!(resolve_lock_dependency(&lock, "log 0.3.9").as_deref(), Some("pkg:cargo/log@0.3.9"));
+        assert_eq!(resolve_lock_dependency(&lock, "log").as_deref(), Some("pkg:cargo/log@0.4.19"));
+    }
+}
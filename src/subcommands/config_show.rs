@@ -0,0 +1,108 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::{override_key, target_key, tool_key, utils, Config, ValueSource};
+
+// Print every value of the resolved configuration, with the file, environment variable
+// or default it comes from.
+pub fn run_config_show(config: &Config) -> Result<()> {
+    print!("{}", show_config(config));
+    Ok(())
+}
+
+pub fn show_config(config: &Config) -> String {
+    let source = |key: &str| config.sources.get(key).cloned().unwrap_or(ValueSource::Default);
+    let mut out = String::new();
+    let mut line = |key: &str, value: Option<String>| {
+        let value = value.unwrap_or_else(|| "~".to_string());
+        // proxies are the exception to the precedence of the other values
+        let note = if key.ends_with("_PROXY") { ", the configuration file wins over the environment" } else { "" };
+        let _ = writeln!(out, "{}: {}  # {}{}", key, value, source(key), note);
+    };
+
+    line("RUSTUP_DIST_SERVER", Some(config.rustup_dist_server.clone()));
+    line("RUSTUP_UPDATE_ROOT", Some(config.rustup_update_root.clone()));
+    // proxies may carry credentials in their url
+    line("HTTP_PROXY", config.network.http_proxy.as_deref().map(utils::redact_url_str));
+    line("HTTPS_PROXY", config.network.https_proxy.as_deref().map(utils::redact_url_str));
+    line("NO_PROXY", config.network.no_proxy.clone());
+    let ca_bundles: Vec<String> = config.network.ca_bundles.iter().map(|path| path.display().to_string()).collect();
+    line("CA_BUNDLES", Some(format!("[{}]", ca_bundles.join(", "))));
+    line("CLIENT_CERT", config.network.client_cert.as_ref().map(|path| path.display().to_string()));
+    line("CLIENT_KEY", config.network.client_key.as_ref().map(|path| path.display().to_string()));
+    line("CREDENTIALS_FILE", config.network.credentials_file.as_ref().map(|path| path.display().to_string()));
+    line("RUSTUP_HOME", config.homes.rustup_home.as_ref().map(|path| path.display().to_string()));
+    line("CARGO_HOME", config.homes.cargo_home.as_ref().map(|path| path.display().to_string()));
+
+    let files: Vec<String> = config.rust_toolchain_files.iter().map(|path| path.display().to_string()).collect();
+    line("RUST_TOOLCHAIN_FILES", Some(format!("[{}]", files.join(", "))));
+    line("HOSTS", Some(format!("[{}]", config.hosts.join(", "))));
+
+    let _ = writeln!(out, "TARGETS:");
+    for selection in &config.target_selections {
+        let mut details = vec![format!("channel: {}", selection.channel)];
+        if let Some(date) = &selection.date {
+            details.push(format!("date: {}", date));
+        }
+        if let Some(profile) = &selection.profile {
+            details.push(format!("profile: {}", profile));
+        }
+        if !selection.components.is_empty() {
+            details.push(format!("components: [{}]", selection.components.join(", ")));
+        }
+        if !selection.targets.is_empty() {
+            details.push(format!("targets: [{}]", selection.targets.join(", ")));
+        }
+        if selection.staging {
+            details.push("staging: true".to_string());
+        }
+        if selection.default {
+            details.push("default: true".to_string());
+        }
+        let _ = writeln!(
+            out,
+            "  - {{ target: {}, {} }}  # {}",
+            selection.target,
+            details.join(", "),
+            source(&target_key(selection))
+        );
+    }
+
+    let _ = writeln!(out, "EXTEND_TOOLS:");
+    for tool in &config.extra_tools {
+        let version = tool.version.as_deref().unwrap_or("~");
+        let _ = writeln!(out, "  - {{ name: {}, version: {} }}  # {}", tool.name, version, source(&tool_key(tool)));
+    }
+
+    let _ = writeln!(out, "OVERRIDES:");
+    for (directory, toolchain) in &config.directory_overrides {
+        let _ = writeln!(out, "  {}: {}  # {}", directory.display(), toolchain, source(&override_key(directory)));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_show_config() {
+        let mut config = Config {
+            rust_toolchain_files: vec![PathBuf::from("/repo/rust-toolchain.toml")],
+            hosts: vec!["x86_64-unknown-linux-gnu".to_string(), "aarch64-unknown-linux-gnu".to_string()],
+            ..Default::default()
+        };
+        config.network.credentials_file = Some(PathBuf::from("/etc/credentials.yaml"));
+        config.sources.insert("HOSTS".to_string(), ValueSource::File(PathBuf::from("/toolchain.yaml")));
+
+        let out = show_config(&config);
+        assert!(out.contains("RUST_TOOLCHAIN_FILES: [/repo/rust-toolchain.toml]  # default\n"));
+        assert!(out.contains("HOSTS: [x86_64-unknown-linux-gnu, aarch64-unknown-linux-gnu]  # /toolchain.yaml\n"));
+        assert!(out.contains("CREDENTIALS_FILE: /etc/credentials.yaml  # default\n"));
+        assert!(out.contains("NO_PROXY: ~  # default, the configuration file wins over the environment\n"));
+    }
+}
@@ -1,24 +1,204 @@
-use std::{io::{Error}, env};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use crate::{Config, TargetSelection, ExtendTool, CommandRunner, Runner};
+use anyhow::{bail, Context, Result};
+use tracing::{info, info_span, warn};
 
-pub fn run_install(config: &Config) -> Result<(), Error>{
-    env::set_var("RUSTUP_DIST_SERVER", &config.rustup_dist_server);
-    env::set_var("RUSTUP_UPDATE_ROOT", &config.rustup_update_root);
+use crate::{local_file_size, report, utils, CommandSpec, Config, TargetSelection, ExtendTool, InstallHomes, InstallStatus, InstalledState, Lockfile, Message, Plan, PlanStep, Runner};
+
+// Version of rustup's settings.toml format written into a fresh RUSTUP_HOME
+const RUSTUP_SETTINGS_VERSION: &str = "12";
+
+// What rustup-init installs into `<CARGO_HOME>/bin`, the proxies and rustup itself
+const RUSTUP_PROXIES: &[&str] = &[
+    "rustup",
+    "cargo",
+    "cargo-clippy",
+    "cargo-fmt",
+    "cargo-miri",
+    "clippy-driver",
+    "rls",
+    "rust-analyzer",
+    "rust-gdb",
+    "rust-gdbgui",
+    "rust-lldb",
+    "rustc",
+    "rustdoc",
+    "rustfmt",
+];
+
+// What happened to one toolchain or tool: its name, status, and the version it replaced
+pub type InstallOutcome = (String, InstallStatus, Option<String>);
+
+pub fn run_install(config: &Config, runner: &dyn Runner) -> Result<()> {
+    // Checked before rustup creates it while installing the first toolchain
+    let fresh_settings = fresh_rustup_settings(&config.homes);
+    let installed = InstalledState::read(runner, &config.homes, !config.target_selections.is_empty())?;
+    install_missing(config, runner, &installed, fresh_settings.as_deref())?;
+
+    Ok(())
+}
+
+// Install what `config` lists and `installed` lacks, then apply the rustup settings
+pub fn install_missing(
+    config: &Config,
+    runner: &dyn Runner,
+    installed: &InstalledState,
+    fresh_settings: Option<&Path>,
+) -> Result<Vec<InstallOutcome>> {
+    check_rustup_names(config)?;
+    let mut outcomes = Vec::new();
 
     for target_selection in config.target_selections.iter() {
-        install_toolchain(&target_selection);
+        let _target_span = info_span!("target", target = %target_selection.toolchain_name()).entered();
+        let (commands, status) = toolchain_commands(config, target_selection, installed);
+        for command in commands {
+            runner.run(&command)?;
+        }
+        outcomes.push(record_outcome(target_selection.rustup_toolchain_name().to_lowercase(), status, None));
     }
+    apply_rustup_settings(config, runner, fresh_settings)?;
 
     for extra_tool in config.extra_tools.iter() {
-        install_extra_tools(&extra_tool);
+        let _tool_span = info_span!("tool", tool = %extra_tool.crate_name()).entered();
+        let (command, status, previous) = tool_commands(config, extra_tool, installed, fresh_settings.is_some());
+        if let Some(command) = command {
+            runner.run(&command)?;
+        }
+        outcomes.push(record_outcome(extra_tool.crate_name(), status, previous));
     }
+    write_activation_scripts(&config.homes)?;
+    link_rustup_proxies(&config.homes)?;
 
-    Ok(())
+    Ok(outcomes)
+}
+
+// Everything `run_install` would download and run for `config` on top of what is already
+// `installed`, without doing it. Downloads are only known when `lockfile` has an entry
+// for the toolchain.
+pub fn plan_install(config: &Config, lockfile: Option<&Lockfile>, installed: &InstalledState) -> Result<Plan> {
+    check_rustup_names(config)?;
+    let mut plan = Plan::default();
+
+    for target_selection in config.target_selections.iter() {
+        let (commands, status) = toolchain_commands(config, target_selection, installed);
+        // rustup fetches the manifest and components itself, the lock file records which ones
+        if let Some(entry) = lockfile.and_then(|lockfile| lockfile.toolchain(target_selection)) {
+            let artifacts: Vec<_> = match (status, installed.missing(target_selection)) {
+                (InstallStatus::AlreadyPresent, _) => Vec::new(),
+                (InstallStatus::Upgraded, Some((components, targets))) => entry
+                    .artifacts
+                    .iter()
+                    .filter(|artifact| {
+                        components.contains(&artifact.name)
+                            || targets.iter().any(|target| artifact.name == format!("rust-std-{}", target))
+                    })
+                    .collect(),
+                _ => {
+                    plan.push(PlanStep::Download {
+                        url: utils::redact_url_str(&entry.manifest_url),
+                        size: local_file_size(&entry.manifest_url),
+                        sha256: Some(entry.manifest_sha256.clone()),
+                        destination: None,
+                    });
+                    entry.artifacts.iter().filter(|artifact| artifact.name != "rust").collect()
+                }
+            };
+            for artifact in artifacts {
+                plan.push(PlanStep::Download {
+                    url: utils::redact_url_str(&artifact.url),
+                    size: local_file_size(&artifact.url),
+                    sha256: Some(artifact.sha256.clone()),
+                    destination: None,
+                });
+            }
+        }
+        commands.into_iter().for_each(|command| plan.command(command));
+    }
+    if has_rustup_settings(config)? {
+        match fresh_rustup_settings(&config.homes) {
+            Some(path) => plan.write_file(path),
+            None => rustup_settings_commands(config)?.into_iter().for_each(|command| plan.command(command)),
+        }
+    }
+
+    let fresh = fresh_rustup_settings(&config.homes).is_some();
+    for extra_tool in config.extra_tools.iter() {
+        if let (Some(command), _, _) = tool_commands(config, extra_tool, installed, fresh) {
+            plan.command(command);
+        }
+    }
+    for (path, _) in activation_scripts(&config.homes) {
+        plan.write_file(path);
+    }
+    for path in rustup_proxies(&config.homes).into_iter().filter(|path| fs::symlink_metadata(path).is_err()) {
+        plan.write_file(path);
+    }
+
+    Ok(plan)
+}
+
+// Install a missing toolchain, or add the components and targets it lacks
+fn toolchain_commands(config: &Config, target_selection: &TargetSelection, installed: &InstalledState) -> (Vec<CommandSpec>, InstallStatus) {
+    let Some((components, targets)) = installed.missing(target_selection) else {
+        return (vec![toolchain_install_command(config, target_selection)], InstallStatus::Installed);
+    };
+    if components.is_empty() && targets.is_empty() {
+        return (Vec::new(), InstallStatus::AlreadyPresent);
+    }
+
+    let toolchain = target_selection.rustup_toolchain_name().to_lowercase();
+    let mut commands = Vec::new();
+    for (kind, names) in [("component", components), ("target", targets)] {
+        if names.is_empty() {
+            continue;
+        }
+        let mut args = vec![kind.to_string(), "add".to_string(), "--toolchain".to_string(), toolchain.clone()];
+        args.extend(names);
+        commands.push(rustup_with_servers(config, target_selection, args));
+    }
+    (commands, InstallStatus::Upgraded)
+}
+
+// Install a missing tool or another version of it, with the version it replaces. `fresh` is
+// whether the rustup home had no settings, and so no default toolchain, before installing.
+fn tool_commands(
+    config: &Config,
+    tool: &ExtendTool,
+    installed: &InstalledState,
+    fresh: bool,
+) -> (Option<CommandSpec>, InstallStatus, Option<String>) {
+    match installed.tool(tool) {
+        None => (Some(tool_install_command(config, tool, fresh)), InstallStatus::Installed, None),
+        Some((_, true)) => (None, InstallStatus::AlreadyPresent, None),
+        Some((version, false)) => {
+            (Some(tool_install_command(config, tool, fresh)), InstallStatus::Upgraded, Some(version.to_string()))
+        }
+    }
 }
 
-fn install_toolchain(target_selection: &TargetSelection) {
-    let toolchain = target_selection.toolchain_name();
+// Report what happened to `item`, once for people or json consumers
+pub fn record_outcome(item: String, status: InstallStatus, previous: Option<String>) -> InstallOutcome {
+    report::emit(Message::ItemInstalled { item: item.clone(), status, previous: previous.clone() });
+    (item, status, previous)
+}
+
+fn toolchain_install_command(config: &Config, target_selection: &TargetSelection) -> CommandSpec {
+    rustup_with_servers(config, target_selection, toolchain_install_args(target_selection))
+}
+
+// rustup downloads from the configured servers rather than its own defaults
+fn rustup_with_servers(config: &Config, target_selection: &TargetSelection, args: Vec<String>) -> CommandSpec {
+    config
+        .homes
+        .apply(CommandSpec::rustup(args))
+        .env("RUSTUP_DIST_SERVER", &target_selection.dist_server(&config.rustup_dist_server))
+        .env("RUSTUP_UPDATE_ROOT", &config.rustup_update_root)
+}
+
+fn toolchain_install_args(target_selection: &TargetSelection) -> Vec<String> {
+    let toolchain = target_selection.rustup_toolchain_name();
 
     let mut args: Vec<String> = Vec::new();
     args.push("toolchain".to_string());
@@ -29,20 +209,408 @@ fn install_toolchain(target_selection: &TargetSelection) {
         args.push("--profile".to_string());
         args.push(profile.to_string());
     }
+    for component in &target_selection.components {
+        args.push("--component".to_string());
+        args.push(component.to_string());
+    }
+    for target in &target_selection.targets {
+        args.push("--target".to_string());
+        args.push(target.to_string());
+    }
+    args
+}
+
+// The toolchain marked `default: true`, if any
+fn default_toolchain(config: &Config) -> Result<Option<&TargetSelection>> {
+    let mut defaults = config.target_selections.iter().filter(|target_selection| target_selection.default);
+    let default = defaults.next();
+    if let Some(other) = defaults.next() {
+        bail!(
+            "both {} and {} are marked `default: true`",
+            default.map(TargetSelection::toolchain_name).unwrap_or_default(),
+            other.toolchain_name()
+        );
+    }
+    Ok(default)
+}
+
+// rustup installs a staged toolchain under the name of the released one, so only one of
+// them can be installed
+fn check_rustup_names(config: &Config) -> Result<()> {
+    for (index, target_selection) in config.target_selections.iter().enumerate() {
+        let name = target_selection.rustup_toolchain_name().to_lowercase();
+        if let Some(other) = config.target_selections[index + 1..]
+            .iter()
+            .find(|other| other.rustup_toolchain_name().to_lowercase() == name)
+        {
+            bail!(
+                "{} and {} would both be installed as {}",
+                target_selection.toolchain_name(),
+                other.toolchain_name(),
+                name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn has_rustup_settings(config: &Config) -> Result<bool> {
+    Ok(default_toolchain(config)?.is_some() || !config.directory_overrides.is_empty())
+}
+
+// rustup's settings.toml, when the rustup home of `homes` does not have one yet
+pub fn fresh_rustup_settings(homes: &InstallHomes) -> Option<PathBuf> {
+    let settings = homes.rustup_home()?.join("settings.toml");
+    (!settings.exists()).then_some(settings)
+}
+
+// Make the default toolchain and directory overrides effective, by writing them into a
+// fresh `settings` file or else through rustup
+fn apply_rustup_settings(config: &Config, runner: &dyn Runner, fresh_settings: Option<&Path>) -> Result<()> {
+    if !has_rustup_settings(config)? {
+        return Ok(());
+    }
+    match fresh_settings {
+        Some(path) => write_rustup_settings(config, path),
+        None => {
+            for command in rustup_settings_commands(config)? {
+                runner.run(&command)?;
+            }
+            Ok(())
+        }
+    }
+}
 
-    if let Err(e) = CommandRunner::Rustup.run_command(&args) {
-        panic!("CommandRunner failed {}", e);
+fn rustup_settings_commands(config: &Config) -> Result<Vec<CommandSpec>> {
+    let mut commands = Vec::new();
+    if let Some(default) = default_toolchain(config)? {
+        commands.push(config.homes.apply(CommandSpec::rustup(vec!["default".to_string(), default.rustup_toolchain_name().to_lowercase()])));
+    }
+    for (directory, toolchain) in &config.directory_overrides {
+        commands.push(config.homes.apply(CommandSpec::rustup(vec![
+            "override".to_string(),
+            "set".to_string(),
+            toolchain.clone(),
+            "--path".to_string(),
+            directory.to_string_lossy().into_owned(),
+        ])));
+    }
+    Ok(commands)
+}
+
+// Set `default_toolchain` and `[overrides]` in rustup's settings file, keeping whatever
+// installing the toolchains already wrote there
+fn write_rustup_settings(config: &Config, path: &Path) -> Result<()> {
+    let mut settings: toml::Table = match fs::read_to_string(path) {
+        Ok(content) => toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))?,
+        Err(_) => toml::Table::new(),
     };
+    settings.entry("version").or_insert_with(|| RUSTUP_SETTINGS_VERSION.into());
+    if let Some(default) = default_toolchain(config)? {
+        settings.insert("default_toolchain".to_string(), default.rustup_toolchain_name().to_lowercase().into());
+    }
+    if !config.directory_overrides.is_empty() {
+        let overrides = settings
+            .entry("overrides")
+            .or_insert_with(|| toml::Table::new().into())
+            .as_table_mut()
+            .with_context(|| format!("`overrides` is not a table in {}", path.display()))?;
+        for (directory, toolchain) in &config.directory_overrides {
+            overrides.insert(directory.to_string_lossy().into_owned(), toolchain.clone().into());
+        }
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    fs::write(path, toml::to_string(&settings)?).with_context(|| format!("failed to write {}", path.display()))?;
+    info!(path = %path.display(), "Wrote rustup settings");
+    Ok(())
 }
 
-fn install_extra_tools(tool: &ExtendTool) {
+fn tool_install_command(config: &Config, tool: &ExtendTool, fresh: bool) -> CommandSpec {
     let tool = tool.crate_name();
-    
-    let mut args: Vec<String> = Vec::new();
-    args.push("install".to_string());
-    args.push(tool.to_lowercase());
 
-    if let Err(e) = CommandRunner::Cargo.run_command(&args) {
-        panic!("CommandRunner failed {}", e);
+    let command = config.homes.apply(CommandSpec::cargo(vec!["install".to_string(), tool.to_lowercase()]));
+    // A fresh rustup home has no default toolchain for the cargo proxy to pick, unless one
+    // is marked `default: true`. Any other home keeps the default it has.
+    let has_default = default_toolchain(config).ok().flatten().is_some();
+    match config.target_selections.first() {
+        Some(toolchain) if fresh && !has_default => {
+            command.env("RUSTUP_TOOLCHAIN", &toolchain.rustup_toolchain_name().to_lowercase())
+        }
+        _ => command,
+    }
+}
+
+// `env.sh` and `env.fish` pointing PATH, RUSTUP_HOME and CARGO_HOME at an isolated install,
+// written into its cargo home, or its rustup home when only that one is isolated
+pub fn activation_scripts(homes: &InstallHomes) -> Vec<(PathBuf, String)> {
+    let Some(dir) = homes.cargo_home.as_ref().or(homes.rustup_home.as_ref()) else {
+        return Vec::new();
+    };
+    let rustup_home = homes.rustup_home();
+    let cargo_home = homes.cargo_home();
+
+    let header = "# Source this file to use the toolchains and tools installed here\n";
+    let (mut sh, mut fish) = (header.to_string(), header.to_string());
+    for (name, home) in [("RUSTUP_HOME", &rustup_home), ("CARGO_HOME", &cargo_home)] {
+        if let Some(home) = home {
+            let _ = writeln!(sh, "export {}={}", name, sh_quote(home));
+            let _ = writeln!(fish, "set -gx {} {}", name, fish_quote(home));
+        }
+    }
+    if let Some(cargo_home) = &cargo_home {
+        let bin = cargo_home.join("bin");
+        let (sh_bin, fish_bin) = (sh_quote(&bin), fish_quote(&bin));
+        let _ = write!(sh, "case \":${{PATH}}:\" in\n    *:{}:*) ;;\n    *) export PATH={}:\"$PATH\" ;;\nesac\n", sh_bin, sh_bin);
+        let _ = write!(fish, "if not contains {} $PATH\n    set -gx PATH {} $PATH\nend\n", fish_bin, fish_bin);
+    }
+
+    vec![(dir.join("env.sh"), sh), (dir.join("env.fish"), fish)]
+}
+
+fn write_activation_scripts(homes: &InstallHomes) -> Result<()> {
+    for (path, script) in activation_scripts(homes) {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        fs::write(&path, script).with_context(|| format!("failed to write {}", path.display()))?;
+        info!(path = %path.display(), "Wrote activation script");
+    }
+    Ok(())
+}
+
+// The rustup proxies, eg: cargo or rustc, in the `bin` directory of an isolated cargo home,
+// so `env.sh` and `env.fish` put the installed toolchains on `PATH`
+pub fn rustup_proxies(homes: &InstallHomes) -> Vec<PathBuf> {
+    let Some(cargo_home) = &homes.cargo_home else {
+        return Vec::new();
+    };
+    RUSTUP_PROXIES
+        .iter()
+        .map(|name| cargo_home.join("bin").join(format!("{}{}", name, std::env::consts::EXE_SUFFIX)))
+        .collect()
+}
+
+// rustup acts as the tool it is invoked as, the proxies are links to the rustup on `PATH`.
+// Existing files are left as they are.
+fn link_rustup_proxies(homes: &InstallHomes) -> Result<()> {
+    let proxies: Vec<PathBuf> = rustup_proxies(homes).into_iter().filter(|path| fs::symlink_metadata(path).is_err()).collect();
+    if proxies.is_empty() {
+        return Ok(());
+    }
+    let Some(rustup) = utils::find_program("rustup") else {
+        warn!("rustup is not on PATH, the isolated cargo home gets no rustup proxies");
+        return Ok(());
+    };
+    for proxy in proxies {
+        if let Some(parent) = proxy.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&rustup, &proxy).with_context(|| format!("failed to link {}", proxy.display()))?;
+        #[cfg(not(unix))]
+        fs::hard_link(&rustup, &proxy).with_context(|| format!("failed to link {}", proxy.display()))?;
+    }
+    info!(rustup = %rustup.display(), "Linked rustup proxies");
+    Ok(())
+}
+
+fn sh_quote(path: &Path) -> String {
+    utils::shell_quote(&path.to_string_lossy())
+}
+
+fn fish_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::linux_target;
+    use crate::{CommandError, CommandOutput, RecordingRunner};
+
+    fn config() -> Config {
+        Config {
+            rustup_dist_server: "https://mirror.example.com".to_string(),
+            rustup_update_root: "https://mirror.example.com/rustup".to_string(),
+            target_selections: vec![TargetSelection {
+                profile: Some("minimal".to_string()),
+                components: vec!["clippy".to_string()],
+                targets: vec!["wasm32-unknown-unknown".to_string()],
+                ..linux_target("nightly", Some("2024-01-01"))
+            }],
+            extra_tools: vec![ExtendTool { name: "grcov".to_string(), version: Some("0.8.18".to_string()) }],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_plan_install() {
+        let plan = plan_install(&config(), None, &InstalledState::default()).unwrap();
+        let commands: Vec<String> = plan.steps.iter().map(|step| step.to_string()).collect();
+        assert_eq!(
+            commands,
+            vec![
+                "run RUSTUP_DIST_SERVER=https://mirror.example.com RUSTUP_UPDATE_ROOT=https://mirror.example.com/rustup \
+                 rustup toolchain install nightly-2024-01-01-x86_64-unknown-linux-gnu --profile minimal \
+                 --component clippy --target wasm32-unknown-unknown",
+                "run cargo install grcov@0.8.18",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_install_staging() {
+        let mut config = config();
+        config.target_selections[0].staging = true;
+        let plan = plan_install(&config, None, &InstalledState::default()).unwrap();
+        assert!(plan.steps[0].to_string().starts_with(
+            "run RUSTUP_DIST_SERVER=https://mirror.example.com/dist/staging RUSTUP_UPDATE_ROOT=https://mirror.example.com/rustup \
+             rustup toolchain install nightly-2024-01-01-x86_64-unknown-linux-gnu "
+        ));
+
+        // the staged and the released toolchain would share a name in rustup
+        config.target_selections.push(TargetSelection { staging: false, ..config.target_selections[0].clone() });
+        let err = plan_install(&config, None, &InstalledState::default()).unwrap_err();
+        assert!(err.to_string().contains("nightly-staging-2024-01-01-x86_64-unknown-linux-gnu and nightly-2024-01-01-x86_64-unknown-linux-gnu"));
+    }
+
+    #[test]
+    fn test_run_install() {
+        let runner = RecordingRunner::new();
+        run_install(&config(), &runner).unwrap();
+
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[0].to_string(), "rustup toolchain list");
+        assert_eq!(commands[1].program, "rustup");
+        assert!(commands[1].env.contains(&("RUSTUP_DIST_SERVER".to_string(), "https://mirror.example.com".to_string())));
+        assert_eq!(commands[2].to_string(), "cargo install grcov@0.8.18");
+    }
+
+    #[test]
+    fn test_run_install_failure() {
+        let runner = RecordingRunner::with_handler(|command| match command.args.get(1).map(String::as_str) {
+            Some("install") if command.program == "rustup" => Err(CommandError::Failed {
+                command: command.to_string(),
+                exit_status: Some(1),
+                stdout: String::new(),
+                stderr: "error: toolchain 'nightly-2024-01-01' is not installable".to_string(),
+            }),
+            _ => Ok(CommandOutput::default()),
+        });
+
+        let err = run_install(&config(), &runner).unwrap_err();
+        assert!(err.to_string().contains("is not installable"));
+        // tools are not installed once a toolchain failed
+        assert_eq!(runner.commands().len(), 2);
+    }
+
+    #[test]
+    fn test_apply_rustup_settings() {
+        let mut config = config();
+        config.target_selections[0].default = true;
+        config.directory_overrides.insert(PathBuf::from("/work/service"), "stable".to_string());
+
+        let runner = RecordingRunner::new();
+        apply_rustup_settings(&config, &runner, None).unwrap();
+        let commands: Vec<String> = runner.commands().iter().map(|command| command.to_string()).collect();
+        assert_eq!(
+            commands,
+            vec![
+                "rustup default nightly-2024-01-01-x86_64-unknown-linux-gnu",
+                "rustup override set stable --path /work/service",
+            ]
+        );
+
+        // a fresh RUSTUP_HOME gets its settings written directly, next to what rustup already wrote
+        let rustup_home = std::env::temp_dir().join("rustup-distribution-test-rustup-settings");
+        let _ = fs::remove_dir_all(&rustup_home);
+        fs::create_dir_all(&rustup_home).unwrap();
+        let settings = rustup_home.join("settings.toml");
+        fs::write(&settings, "version = \"12\"\nprofile = \"minimal\"\n").unwrap();
+        let runner = RecordingRunner::new();
+        apply_rustup_settings(&config, &runner, Some(&settings)).unwrap();
+        assert!(runner.commands().is_empty());
+        let written: toml::Table = toml::from_str(&fs::read_to_string(&settings).unwrap()).unwrap();
+        assert_eq!(written["profile"].as_str(), Some("minimal"));
+        assert_eq!(written["default_toolchain"].as_str(), Some("nightly-2024-01-01-x86_64-unknown-linux-gnu"));
+        assert_eq!(written["overrides"]["/work/service"].as_str(), Some("stable"));
+        fs::remove_dir_all(&rustup_home).unwrap();
+
+        config.target_selections.push(TargetSelection { default: true, ..config.target_selections[0].clone() });
+        assert!(apply_rustup_settings(&config, &RecordingRunner::new(), None).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_isolated_homes() {
+        let prefix = std::env::temp_dir().join("rustup-distribution-test-isolated-homes");
+        let _ = fs::remove_dir_all(&prefix);
+        let mut config = config();
+        config.homes = InstallHomes { rustup_home: Some(prefix.join("rustup")), cargo_home: Some(prefix.join("cargo")) };
+
+        let plan = plan_install(&config, None, &InstalledState::default()).unwrap();
+        let steps: Vec<String> = plan.steps.iter().map(|step| step.to_string()).collect();
+        let homes = format!("RUSTUP_HOME={} CARGO_HOME={}", prefix.join("rustup").display(), prefix.join("cargo").display());
+        assert!(steps[0].starts_with(&format!("run {} RUSTUP_DIST_SERVER=", homes)));
+        assert_eq!(
+            steps[1],
+            format!("run {} RUSTUP_TOOLCHAIN=nightly-2024-01-01-x86_64-unknown-linux-gnu cargo install grcov@0.8.18", homes)
+        );
+        assert_eq!(steps[2..4], [format!("write {}", prefix.join("cargo/env.sh").display()), format!("write {}", prefix.join("cargo/env.fish").display())]);
+        assert_eq!(steps[4..].len(), RUSTUP_PROXIES.len());
+        assert!(steps.contains(&format!("write {}", prefix.join("cargo/bin/rustc").display())));
+
+        install_missing(&config, &RecordingRunner::new(), &InstalledState::default(), None).unwrap();
+        if utils::find_program("rustup").is_some() {
+            assert!(fs::symlink_metadata(prefix.join("cargo/bin/cargo")).unwrap().file_type().is_symlink());
+        }
+        let env_sh = fs::read_to_string(prefix.join("cargo/env.sh")).unwrap();
+        assert!(env_sh.contains(&format!("export RUSTUP_HOME='{}'\n", prefix.join("rustup").display())));
+        assert!(env_sh.contains(&format!("*) export PATH='{}':\"$PATH\" ;;", prefix.join("cargo/bin").display())));
+        let env_fish = fs::read_to_string(prefix.join("cargo/env.fish")).unwrap();
+        assert!(env_fish.contains(&format!("set -gx CARGO_HOME '{}'\n", prefix.join("cargo").display())));
+
+        // a rustup home with settings, or about to get a default toolchain, keeps its default
+        assert!(tool_install_command(&config, &config.extra_tools[0], false).env.iter().all(|(key, _)| key != "RUSTUP_TOOLCHAIN"));
+        config.target_selections[0].default = true;
+        assert!(tool_install_command(&config, &config.extra_tools[0], true).env.iter().all(|(key, _)| key != "RUSTUP_TOOLCHAIN"));
+
+        assert_eq!(sh_quote(Path::new("/it's")), "'/it'\\''s'");
+        assert_eq!(fish_quote(Path::new("/it's")), "'/it\\'s'");
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn test_install_differences() {
+        let mut config = config();
+        config.extra_tools.push(ExtendTool { name: "cargo-nextest".to_string(), version: Some("0.9.61".to_string()) });
+        let mut installed = InstalledState::default();
+        installed.toolchains.insert(
+            "nightly-2024-01-01-x86_64-unknown-linux-gnu".to_string(),
+            ["rustc-x86_64-unknown-linux-gnu".to_string(), "clippy-x86_64-unknown-linux-gnu".to_string()].into(),
+        );
+        installed.crates.insert("grcov".to_string(), "0.8.18".to_string());
+        installed.crates.insert("cargo-nextest".to_string(), "0.9.60".to_string());
+
+        // clippy and grcov are there, only the wasm target and the newer cargo-nextest are missing
+        let plan = plan_install(&config, None, &installed).unwrap();
+        let commands: Vec<String> = plan.steps.iter().map(|step| step.to_string()).collect();
+        assert_eq!(
+            commands,
+            vec![
+                "run RUSTUP_DIST_SERVER=https://mirror.example.com RUSTUP_UPDATE_ROOT=https://mirror.example.com/rustup \
+                 rustup target add --toolchain nightly-2024-01-01-x86_64-unknown-linux-gnu wasm32-unknown-unknown",
+                "run cargo install cargo-nextest@0.9.61",
+            ]
+        );
+
+        let (_, status, previous) = tool_commands(&config, &config.extra_tools[1], &installed, false);
+        assert_eq!((status, previous.as_deref()), (InstallStatus::Upgraded, Some("0.9.60")));
+        let (command, status, _) = tool_commands(&config, &config.extra_tools[0], &installed, false);
+        assert!(command.is_none());
+        assert_eq!(status, InstallStatus::AlreadyPresent);
+    }
+}
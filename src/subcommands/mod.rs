@@ -1,3 +1,12 @@
 pub mod install;
 
-pub mod package;
\ No newline at end of file
+pub mod package;
+
+pub mod resolve;
+
+pub mod verify;
+
+pub mod config_show;
+
+pub mod sync;
+pub mod uninstall;
@@ -1,79 +1,263 @@
-use std::error::Error;
 use std::path::{PathBuf, Path};
 use std::io::{Write, self};
 use std::collections::VecDeque;
-use std::env;
 use std::fs::{self, File};
 
-use anyhow::{Result};
+use anyhow::{Context, Result};
+use tracing::{info, info_span};
+
+use crate::{
+    archive, deb, extract_args, installer, local_file_size, metadata, report, rpm, utils, BundleConfig, BundleMetadata, ChannelManifest, CommandSpec, Config,
+    DownloadCfg, ExtendTool, Lockfile, Message, NetworkConfig, Plan, PlanStep, Runner, SbomBundle, SbomFormat, SourceArchive, TargetSelection,
+    BUNDLE_METADATA_FILENAME,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct PackageOptions {
+    // software bill of materials formats written next to each bundle
+    pub sbom: Vec<SbomFormat>,
+    // what each toolchain is packaged as, a `.tar.gz` bundle when empty
+    pub formats: Vec<PackageFormat>,
+}
 
-use crate::{Config, DownloadCfg, ExtendTool, CommandRunner, Runner, utils, TargetSelection};
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PackageFormat {
+    // the bundle itself, with its install.sh and a standalone installer next to it
+    TarGz,
+    // a single executable shell script carrying the bundle
+    Sfx,
+    // a Debian package installing into /opt/rust/<toolchain>
+    Deb,
+    // an RPM installing into /opt/rust/<toolchain>
+    Rpm,
+}
 
-pub fn run_package(config: &Config, output_dir: &PathBuf) -> Result<(), Box<dyn Error>>{
-    env::set_var("RUSTUP_USE_CURL", "true");
+impl PackageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PackageFormat::TarGz => "tar.gz",
+            PackageFormat::Sfx => "run",
+            PackageFormat::Deb => "deb",
+            PackageFormat::Rpm => "rpm",
+        }
+    }
+
+    // The file written for `bundle`, eg: rust-nightly-x86_64-unknown-linux-gnu.run
+    pub fn path_for(&self, bundle: &Path) -> PathBuf {
+        let mut path = utils::strip_extensions(&utils::strip_extensions(bundle)).into_os_string();
+        path.push(format!(".{}", self.extension()));
+        PathBuf::from(path)
+    }
+}
+
+impl PackageOptions {
+    pub fn formats(&self) -> Vec<PackageFormat> {
+        let mut formats = Vec::new();
+        for format in &self.formats {
+            if !formats.contains(format) {
+                formats.push(*format);
+            }
+        }
+        if formats.is_empty() {
+            formats.push(PackageFormat::TarGz);
+        }
+        formats
+    }
+}
+
+// Package every toolchain of `config`, checking the downloaded archive and channel manifest
+// against `lockfile` when it has an entry for the toolchain
+pub fn run_package(
+    config: &Config,
+    lockfile: Option<&Lockfile>,
+    output_dir: &Path,
+    options: &PackageOptions,
+    runner: &dyn Runner,
+) -> Result<()> {
+    let network = NetworkConfig { use_curl: true, ..config.network.clone() };
 
     // FIXME: this only support one target, but we want to support multiple targets
     // ADDITIONAL: CARGO only supports one target to install: cargo install --root XXX
     for target_selection in &config.target_selections {
+        let _target_span = info_span!("target", target = %target_selection.toolchain_name()).entered();
         // parse to tagert package download path
         let root_url = target_selection.package_dir(&config.rustup_dist_server);
 
-        let target_file_name = get_package_full_name(&target_selection);
+        let target_file_name = get_package_full_name(target_selection);
 
         // TODO: Figure out how to handle different dist urls
         let dist_root = format!("{}/{}", root_url, target_file_name);
 
         let download_cfg = DownloadCfg {
-            dist_root: dist_root,
+            dist_root: dist_root.clone(),
             download_dir: output_dir.to_path_buf(),
+            network: network.clone(),
         };
 
-        if let Ok(file) = download_cfg.extract_after_download(&target_file_name) {
+        let locked_entry = lockfile.and_then(|lockfile| lockfile.toolchain(target_selection));
+        let expected_sha256 = locked_entry.and_then(|entry| entry.artifact_sha256("rust"));
+
+        {
+            let file = download_cfg.extract_after_download(&target_file_name, expected_sha256, runner)?;
             // move file to destination
+            let mut tool_dirs = Vec::new();
             for extra_tool in &config.extra_tools {
-                let tool_path =  package_extra_tools(extra_tool, output_dir);
-                if let Err(err) = move_folder(&tool_path, &file.path) {
-                    panic!("Couldn't move file: {}", err);
-                };
+                let _tool_span = info_span!("tool", tool = %extra_tool.crate_name()).entered();
+                let tool_path = package_extra_tools(extra_tool, output_dir, runner)?;
+                move_folder(&tool_path, &file.path).context("Couldn't move tool into the bundle")?;
                 update_components(&file, &get_tool_folder_name(extra_tool))?;
+                tool_dirs.push((extra_tool.clone(), get_tool_folder_name(extra_tool)));
+            }
+
+            installer::write_install_script(&file)?;
+
+            // record what went into the bundle before it is sealed
+            let source = SourceArchive {
+                url: utils::redact_url_str(&dist_root),
+                sha256: file.sha256.clone(),
+            };
+            let bundle_config = BundleConfig {
+                rustup_dist_server: utils::redact_url_str(&config.rustup_dist_server),
+                rustup_update_root: utils::redact_url_str(&config.rustup_update_root),
+                target: target_selection.clone(),
+                extra_tools: config.extra_tools.clone(),
+            };
+            let (manifest, manifest_sha256) =
+                ChannelManifest::fetch(target_selection, &config.rustup_dist_server, &network, output_dir)?;
+            if let Some(entry) = locked_entry {
+                entry.verify_manifest(&manifest_sha256)?;
             }
+            let bundle_metadata = BundleMetadata::new(target_selection.toolchain_name(), source, bundle_config)
+                .write_to(&file, &tool_dirs, Some(&manifest))?;
+
+            let bundle = output_dir.join(bundle_file_name(target_selection));
+            let artifacts = try_package(&bundle, &file, &options.formats(), &bundle_metadata)?;
+
+            for (format, artifact) in artifacts {
+                let sha256 = metadata::write_checksum_file(&artifact)?;
+                if format == PackageFormat::TarGz && installer::has_wrapper(&target_selection.target) {
+                    let wrapper = installer::write_wrapper(&artifact, &sha256, &bundle_metadata)?;
+                    info!(path = %wrapper.display(), "Wrote standalone installer");
+                }
+                report::emit(Message::BundleWritten { path: artifact.clone(), size: fs::metadata(&artifact)?.len(), sha256 });
 
-            try_package(&target_file_name, &output_dir, &file).unwrap();
+                if !options.sbom.is_empty() {
+                    for path in SbomBundle::new(&artifact, &bundle_metadata, &manifest)?.write(&artifact, &options.sbom)? {
+                        info!(path = %path.display(), "Wrote software bill of materials");
+                    }
+                }
+            }
         }
     }
 
     Ok(())
 }
 
-fn try_package(target_file_name: &String, output_dir: &PathBuf, file: &Path) -> Result<()> {
-    let targe_file_path = output_dir.join(target_file_name);
-    
-    println!("{}", targe_file_path.to_owned().to_string_lossy().to_string());
-    println!("{}", file.to_owned().to_string_lossy().to_string());
+// Everything `run_package` would download, run and write for `config`, without doing it or
+// going to the network. Expected hashes come from `lockfile`, sizes from local mirrors.
+pub fn plan_package(config: &Config, lockfile: Option<&Lockfile>, output_dir: &Path, options: &PackageOptions) -> Result<Plan> {
+    let mut plan = Plan::default();
+    for target_selection in &config.target_selections {
+        let root_url = target_selection.package_dir(&config.rustup_dist_server);
+        let target_file_name = get_package_full_name(target_selection);
+        let dist_root = format!("{}/{}", root_url, target_file_name);
+        let locked_entry = lockfile.and_then(|lockfile| lockfile.toolchain(target_selection));
+
+        let download_cfg = DownloadCfg {
+            dist_root: dist_root.clone(),
+            download_dir: output_dir.to_path_buf(),
+            network: config.network.clone(),
+        };
+        let archive_path = download_cfg.target_path(&target_file_name);
+        let staged_dir = utils::strip_extensions(&utils::strip_extensions(&archive_path));
+
+        plan.push(PlanStep::Download {
+            url: utils::redact_url_str(&dist_root),
+            size: local_file_size(&dist_root),
+            sha256: locked_entry.and_then(|entry| entry.artifact_sha256("rust")).map(String::from),
+            destination: Some(archive_path.clone()),
+        });
+        plan.command(CommandSpec::tar(extract_args(&archive_path)));
+
+        for extra_tool in &config.extra_tools {
+            let tool_dir = get_tool_folder_name(extra_tool);
+            plan.command(tool_install_command(extra_tool, &output_dir.join(&tool_dir)));
+            plan.write_file(staged_dir.join(&tool_dir).join(MANIFEST_FILENAME));
+        }
+        if !config.extra_tools.is_empty() {
+            plan.write_file(staged_dir.join("components"));
+        }
+        plan.write_file(staged_dir.join(installer::INSTALL_SCRIPT_FILENAME));
+        let manifest_url = ChannelManifest::url(target_selection, &config.rustup_dist_server);
+        plan.push(PlanStep::Download {
+            url: utils::redact_url_str(&manifest_url),
+            size: local_file_size(&manifest_url),
+            sha256: locked_entry.map(|entry| entry.manifest_sha256.clone()),
+            destination: Some(output_dir.join(ChannelManifest::file_name(target_selection))),
+        });
+        plan.write_file(staged_dir.join(BUNDLE_METADATA_FILENAME));
+
+        let bundle = output_dir.join(bundle_file_name(target_selection));
+        let formats = options.formats();
+        for format in &formats {
+            plan.push(PlanStep::Bundle { path: format.path_for(&bundle) });
+        }
 
-    let mut args = Vec::new();
-    args.push("-czvf".to_string());
-    args.push(targe_file_path.to_owned().to_string_lossy().to_string());
-    args.push("-C".to_string());
-    args.push(file.to_owned().to_string_lossy().to_string());
-    args.push(".".to_string());
+        for format in &formats {
+            let artifact = format.path_for(&bundle);
+            plan.write_file(metadata::checksum_path(&artifact));
+            if *format == PackageFormat::TarGz && installer::has_wrapper(&target_selection.target) {
+                plan.write_file(installer::wrapper_path(&artifact));
+            }
+            for sbom in &options.sbom {
+                plan.write_file(sbom.path_for(&artifact));
+            }
+        }
+    }
 
-    CommandRunner::Tar.run_command(&args).unwrap();
-    fs::remove_dir_all(file).expect("Failed to remove directory after tar command successfully");
+    Ok(plan)
+}
 
-    Ok(())
+// Package the staged `file` directory in every format, returning what was written for each
+fn try_package(
+    bundle: &Path,
+    file: &Path,
+    formats: &[PackageFormat],
+    bundle_metadata: &BundleMetadata,
+) -> Result<Vec<(PackageFormat, PathBuf)>> {
+    info!(bundle = %bundle.display(), source = %file.display(), "Packaging bundle");
+
+    // Sorted entries and normalized ownership and mtimes, so identical inputs give identical bundles.
+    // The other formats are built from it.
+    archive::write_reproducible_tar_gz(file, bundle)?;
+    let mut artifacts = Vec::new();
+    for format in formats {
+        let path = format.path_for(bundle);
+        match format {
+            PackageFormat::TarGz => {}
+            PackageFormat::Sfx => installer::write_self_extracting(bundle, &path, bundle_metadata)?,
+            PackageFormat::Deb => deb::write_deb(file, &path, bundle_metadata)?,
+            PackageFormat::Rpm => rpm::write_rpm(file, &path, bundle_metadata)?,
+        }
+        artifacts.push((*format, path));
+    }
+    if !formats.contains(&PackageFormat::TarGz) {
+        fs::remove_file(bundle).context("Failed to remove the intermediate bundle")?;
+    }
+    fs::remove_dir_all(file).context("Failed to remove directory after packaging successfully")?;
+
+    Ok(artifacts)
 }
 
+// The combined installer upstream publishes for every channel, version pins included,
+// eg: rust-1.75.0-x86_64-unknown-linux-gnu.tar.gz
 fn get_package_full_name(target_selection: &TargetSelection) -> String {
-    let mut target_file_name = String::new();
-    // FIXME: this don't cover all possible channel names
-    if ["nightly", "beta", "stable"].contains(&&*target_selection.channel) {
-        target_file_name = format!(
-            "rust-{}-{}.tar.gz",
-            target_selection.channel, target_selection.target
-        );
-    }
-    target_file_name
+    format!("rust-{}-{}.tar.gz", target_selection.channel, target_selection.target)
+}
+
+// Bundles are named after the toolchain, so dated and staged toolchains get bundles of their own
+fn bundle_file_name(target_selection: &TargetSelection) -> String {
+    format!("rust-{}.tar.gz", target_selection.toolchain_name())
 }
 
 fn get_tool_folder_name(tool: &ExtendTool) -> String {
@@ -81,45 +265,47 @@ fn get_tool_folder_name(tool: &ExtendTool) -> String {
     if let Some(tool_version) = &tool.version {
         tool_dir = format!("{}-{}", tool.name, tool_version);
     } else {
-        tool_dir = format!("{}", tool.name);
+        tool_dir = tool.name.to_string();
     }
     tool_dir
 }
 
-fn package_extra_tools(tool: &ExtendTool, output_dir: &PathBuf) -> PathBuf {
+fn package_extra_tools(tool: &ExtendTool, output_dir: &Path, runner: &dyn Runner) -> Result<PathBuf> {
     // init tool download dir
-    let package_dir = init_package_dir(tool, output_dir);
+    let package_dir = init_package_dir(tool, output_dir)?;
     // install tool locally
-    install_tool_local_directory(tool, &PathBuf::from(&package_dir));
+    install_tool_local_directory(tool, &package_dir, runner)?;
     // init manifest.in file
-    if let Err(err) = init_manifest_for_tool(&PathBuf::from(&package_dir)) {
-        panic!("Couldn't init manifest for tool '{}'", err);
-    };
+    init_manifest_for_tool(&package_dir).context("Couldn't init manifest for tool")?;
 
-    PathBuf::from(package_dir)
+    Ok(package_dir)
 }
 
-fn init_package_dir(tool: &ExtendTool, output_dir: &PathBuf) -> String {
+fn init_package_dir(tool: &ExtendTool, output_dir: &Path) -> Result<PathBuf> {
     let tool_dir = get_tool_folder_name(tool);
     let download_path = output_dir.join(&tool_dir);
-    utils::ensure_dir_exists(&"Tools download path".to_string(), &download_path).unwrap();
+    utils::ensure_dir_exists(&"Tools download path".to_string(), &download_path)?;
+
+    Ok(download_path)
+}
 
-    download_path.to_owned().to_string_lossy().to_string()
+fn install_tool_local_directory(tool: &ExtendTool, package_dir: &Path, runner: &dyn Runner) -> Result<()> {
+    runner.run(&tool_install_command(tool, package_dir))?;
+    Ok(())
 }
 
-fn install_tool_local_directory(tool: &ExtendTool, package_dir: &PathBuf) {
-    let mut args = Vec::new();
-    args.push("install".to_string());
-    args.push(tool.crate_name());
-    args.push("--root".to_string());
-    args.push(package_dir.to_string_lossy().into_owned());
-    
-    if let Err(e) = CommandRunner::Cargo.run_command(&args){
-        panic!("CommandRunner failed {}", e);
-    };
+fn tool_install_command(tool: &ExtendTool, package_dir: &Path) -> CommandSpec {
+    CommandSpec::cargo(vec![
+        "install".to_string(),
+        tool.crate_name(),
+        // build from the published `Cargo.lock`, the one the bill of materials describes
+        "--locked".to_string(),
+        "--root".to_string(),
+        package_dir.to_string_lossy().into_owned(),
+    ])
 }
 
-fn init_manifest_for_tool(package_dir: &PathBuf) -> io::Result<()> {
+fn init_manifest_for_tool(package_dir: &Path) -> io::Result<()> {
     let manifest_path = package_dir.join("manifest.in");
     let mut manifest_file = File::create(manifest_path)?;
 
@@ -136,11 +322,13 @@ fn write_directory_entries(file: &mut File, dir_path: &Path) -> io::Result<()> {
     queue.push_back(dir_path.to_path_buf());
 
     while let Some(path) = queue.pop_front() {
-        let entries = fs::read_dir(&path)?;
-        for entry in entries {
-            let entry = entry?;
-            let entry_path = entry.path();
+        let mut entries = fs::read_dir(&path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        // read_dir order depends on the filesystem, sort it so manifest.in is reproducible
+        entries.sort();
 
+        for entry_path in entries {
             if entry_path.is_file() {
                 let rel_path = entry_path.strip_prefix(dir_path).expect("Invalid directory path");
                 let format_path = rel_path.display().to_string().replace("\\", "/");
@@ -157,7 +345,7 @@ fn write_directory_entries(file: &mut File, dir_path: &Path) -> io::Result<()> {
     Ok(())
 }
 
-fn move_folder(from: &PathBuf, to: &PathBuf) -> io::Result<()> {
+fn move_folder(from: &Path, to: &Path) -> io::Result<()> {
     let destination_path = to.join(from.file_name().unwrap());
 
     fs::rename(from, destination_path)?;
@@ -178,63 +366,301 @@ fn update_components(components_folder: &Path, target_file_name: &String) -> Res
 
 #[cfg(test)]
 mod tests {
+    use std::env;
+
     use super::*;
+    use crate::test_utils::{bundle_metadata, linux_target, temp_dir};
+    use crate::{CommandError, CommandOutput, RecordingRunner};
 
     #[test]
-    #[ignore]
-    pub fn test_init_package_dir() {
-        let tool = ExtendTool{
+    pub fn test_plan_package() {
+        let root = env::temp_dir().join("rustup-distribution-test-plan-package");
+        let _ = fs::remove_dir_all(&root);
+        let dated = root.join("mirror/dist/2024-01-01");
+        fs::create_dir_all(&dated).unwrap();
+        fs::write(dated.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz"), "archive").unwrap();
+        let output_dir = root.join("out");
+
+        let config = Config {
+            rustup_dist_server: url::Url::from_directory_path(root.join("mirror")).unwrap().to_string().trim_end_matches('/').to_string(),
+            target_selections: vec![linux_target("nightly", Some("2024-01-01"))],
+            extra_tools: vec![ExtendTool { name: "grcov".to_string(), version: Some("0.8.18".to_string()) }],
+            ..Config::default()
+        };
+        let options = PackageOptions { sbom: vec![SbomFormat::Spdx], ..Default::default() };
+
+        let plan = plan_package(&config, None, &output_dir, &options).unwrap();
+        let archive = output_dir.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz");
+        let staged_dir = output_dir.join("rust-nightly-x86_64-unknown-linux-gnu");
+        let bundle = output_dir.join("rust-nightly-2024-01-01-x86_64-unknown-linux-gnu.tar.gz");
+        assert!(matches!(&plan.steps[0], PlanStep::Download { size: Some(7), destination: Some(path), .. } if path == &archive));
+        assert_eq!(plan.steps[1].to_string(), format!("run {}", CommandSpec::tar(extract_args(&archive))));
+        assert_eq!(
+            plan.steps[2].to_string(),
+            format!("run {}", tool_install_command(&config.extra_tools[0], &output_dir.join("grcov-0.8.18")))
+        );
+        assert_eq!(plan.steps[3], PlanStep::WriteFile { path: staged_dir.join("grcov-0.8.18/manifest.in") });
+        assert_eq!(plan.bundles().collect::<Vec<_>>(), vec![&bundle]);
+        assert_eq!(plan.steps.last(), Some(&PlanStep::WriteFile { path: SbomFormat::Spdx.path_for(&bundle) }));
+        // nothing was downloaded or written
+        assert!(!output_dir.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn grcov() -> ExtendTool {
+        ExtendTool{
             name: "grcov".to_string(),
             version: Some("0.8.18".to_string()),
-        };
-        let path = PathBuf::from(r"D:\Normal\projects\rustup-plus-plus\");
+        }
+    }
 
-        init_package_dir(&tool, &path);
+    // Stands in for `tar` and `cargo install`, writing what they would have
+    fn fake_runner() -> RecordingRunner {
+        RecordingRunner::with_handler(|command| {
+            match command.program.as_str() {
+                "tar" => {
+                    let archive = PathBuf::from(&command.args[1]);
+                    let dir = utils::strip_extensions(&utils::strip_extensions(&archive));
+                    fs::create_dir_all(&dir).unwrap();
+                    fs::write(dir.join("components"), "rustc\ncargo\n").unwrap();
+                }
+                "cargo" => {
+                    let root = command.args.iter().position(|arg| arg == "--root").map(|i| PathBuf::from(&command.args[i + 1])).unwrap();
+                    // never write outside the test directories
+                    assert!(root.starts_with(env::temp_dir()), "{}", root.display());
+                    fs::create_dir_all(root.join("bin")).unwrap();
+                    fs::write(root.join("bin/grcov"), "binary").unwrap();
+                    fs::write(root.join(CRATE_TOML_FILENAME), "[v1]").unwrap();
+                }
+                _ => {}
+            }
+            Ok(CommandOutput::default())
+        })
+    }
+
+    #[test]
+    pub fn test_init_package_dir() {
+        let path = temp_dir("rustup-distribution-test-init-package-dir");
+
+        let package_dir = init_package_dir(&grcov(), &path).unwrap();
+        assert_eq!(package_dir, path.join("grcov-0.8.18"));
+        assert!(package_dir.is_dir());
+
+        fs::remove_dir_all(&path).unwrap();
     }
 
     #[test]
-    #[ignore]
     pub fn test_install_tool_local_directory() {
-        let tool = ExtendTool{
-            name: "grcov".to_string(),
-            version: Some("0.8.18".to_string()),
-        };
-        let path = PathBuf::from(r"D:\Normal\projects\rustup-plus-plus\grcov_0.8.18");
+        let path = PathBuf::from("/out/grcov-0.8.18");
+        let runner = RecordingRunner::new();
 
-        install_tool_local_directory(&tool, &path);
+        install_tool_local_directory(&grcov(), &path, &runner).unwrap();
+        assert_eq!(runner.commands()[0].to_string(), "cargo install grcov@0.8.18 --locked --root /out/grcov-0.8.18");
     }
 
     #[test]
-    #[ignore]
     pub fn test_init_manifest_file() {
-        let path = PathBuf::from(r"D:\Normal\projects\rustup-plus-plus\grcov_0.8.18");
-        if let Err(err) = init_manifest_for_tool(&path) {
-            panic!("Couldn't init manifest for tool '{}'", err);
-        };
+        let path = temp_dir("rustup-distribution-test-init-manifest");
+        fs::create_dir_all(path.join("bin")).unwrap();
+        fs::write(path.join("bin/grcov"), "binary").unwrap();
+        fs::write(path.join(CRATE_TOML_FILENAME), "[v1]").unwrap();
+
+        init_manifest_for_tool(&path).unwrap();
+        assert_eq!(fs::read_to_string(path.join(MANIFEST_FILENAME)).unwrap(), "file:bin/grcov\n");
+
+        fs::remove_dir_all(&path).unwrap();
     }
 
     #[test]
-    #[ignore]
     pub fn test_move_file() {
-        let from = PathBuf::from(r"D:\Normal\projects\rustup-plus-plus\grcov_0.8.18");
-        let to = PathBuf::from(r"D:\Normal\projects\rustup-plus-plus\tmp");
-        let _ = move_folder(&from, &to);
+        let root = temp_dir("rustup-distribution-test-move");
+        let from = root.join("grcov-0.8.18");
+        let to = root.join("tmp");
+        fs::create_dir_all(&from).unwrap();
+        fs::create_dir_all(&to).unwrap();
+
+        move_folder(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert!(to.join("grcov-0.8.18").is_dir());
+
+        fs::remove_dir_all(&root).unwrap();
     }
 
     #[test]
-    #[ignore]
     pub fn test_modify_components() {
-        let path = PathBuf::from(r"D:\Normal\projects\rustup-plus-plus\rust-nightly-x86_64-pc-windows-msvc");
-        let target_file_name = "grcov-0.8.18".to_string();
-        update_components(&path, &target_file_name).unwrap();
+        let path = temp_dir("rustup-distribution-test-components");
+        fs::write(path.join("components"), "rustc\n").unwrap();
+
+        update_components(&path, &"grcov-0.8.18".to_string()).unwrap();
+        assert_eq!(fs::read_to_string(path.join("components")).unwrap(), "rustc\ngrcov-0.8.18\n");
+
+        fs::remove_dir_all(&path).unwrap();
     }
-    
+
     #[test]
-    #[ignore]
     pub fn test_try_package() {
-        let target_file_name = "rust-nightly-x86_64-pc-windows-msvc.tar.gz";
-        let output_dir = PathBuf::from(r"D:\Normal\projects\rustup-plus-plus");
-        let file = PathBuf::from(r"D:\Normal\projects\rustup-plus-plus\rust-nightly-x86_64-pc-windows-msvc");
-        try_package(&target_file_name.to_string(), &output_dir, &file).unwrap();
+        let target_file_name = "rust-nightly-x86_64-unknown-linux-gnu.tar.gz";
+        let output_dir = temp_dir("rustup-distribution-test-try-package");
+        let file = output_dir.join("rust-nightly-x86_64-unknown-linux-gnu");
+        fs::create_dir_all(&file).unwrap();
+        fs::write(file.join("components"), "rustc\n").unwrap();
+        let bundle = output_dir.join(target_file_name);
+
+        let artifacts = try_package(&bundle, &file, &[PackageFormat::Sfx], &bundle_metadata(&file, linux_target("nightly", None), &[])).unwrap();
+        assert_eq!(artifacts, vec![(PackageFormat::Sfx, output_dir.join("rust-nightly-x86_64-unknown-linux-gnu.run"))]);
+        assert!(artifacts[0].1.is_file());
+        // the bundle the self-extracting installer carries is not kept
+        assert!(!bundle.exists());
+        assert!(!file.exists());
+
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_run_package() {
+        let root = temp_dir("rustup-distribution-test-run-package");
+        let dated = root.join("mirror/dist/2024-01-01");
+        fs::create_dir_all(&dated).unwrap();
+        fs::write(dated.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz"), "archive").unwrap();
+        fs::write(
+            dated.join("channel-rust-nightly.toml"),
+            "date = \"2024-01-01\"\n[pkg.rustc]\nversion = \"1.77.0-nightly\"\n[pkg.rustc.target.x86_64-unknown-linux-gnu]\navailable = true\nurl = \"https://static.rust-lang.org/dist/2024-01-01/rustc-nightly-x86_64-unknown-linux-gnu.tar.gz\"\nhash = \"11\"\n",
+        )
+        .unwrap();
+        let output_dir = root.join("out");
+        fs::create_dir_all(&output_dir).unwrap();
+
+        let config = Config {
+            rustup_dist_server: url::Url::from_directory_path(root.join("mirror")).unwrap().to_string().trim_end_matches('/').to_string(),
+            target_selections: vec![linux_target("nightly", Some("2024-01-01"))],
+            extra_tools: vec![grcov()],
+            ..Config::default()
+        };
+        let runner = fake_runner();
+
+        let options = PackageOptions { formats: vec![PackageFormat::TarGz, PackageFormat::Sfx], ..Default::default() };
+        run_package(&config, None, &output_dir, &options, &runner).unwrap();
+        let programs: Vec<String> = runner.commands().into_iter().map(|command| command.program).collect();
+        assert_eq!(programs, vec!["tar", "cargo"]);
+        assert!(output_dir.join("rust-nightly-2024-01-01-x86_64-unknown-linux-gnu.run").is_file());
+        assert!(output_dir.join("rust-nightly-2024-01-01-x86_64-unknown-linux-gnu.run.sha256").is_file());
+        // the downloaded archive does not linger next to the bundle named after the toolchain
+        assert!(!output_dir.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz").exists());
+
+        let bundle = output_dir.join("rust-nightly-2024-01-01-x86_64-unknown-linux-gnu.tar.gz");
+        let (metadata, report) = metadata::verify_bundle(&bundle).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(metadata.components, vec!["rustc", "cargo", "grcov-0.8.18"]);
+        assert!(metadata.files.contains_key("grcov-0.8.18/bin/grcov"));
+        assert_eq!(metadata.component_sources.keys().collect::<Vec<_>>(), vec!["rustc"]);
+        assert_eq!(metadata.component_sources["rustc"].sha256, "11");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    pub fn test_run_package_imported_version() {
+        let root = temp_dir("rustup-distribution-test-run-package-version");
+        let toolchain_file = root.join("rust-toolchain.toml");
+        fs::write(&toolchain_file, "[toolchain]\nchannel = \"1.75.0\"\n").unwrap();
+        let config = Config::parse(&toolchain_file).unwrap();
+        let host = config.target_selections[0].target.clone();
+        assert_eq!(config.target_selections[0].channel, "1.75.0");
+
+        let dist = root.join("mirror/dist");
+        fs::create_dir_all(&dist).unwrap();
+        fs::write(dist.join(format!("rust-1.75.0-{}.tar.gz", host)), "archive").unwrap();
+        fs::write(dist.join("channel-rust-1.75.0.toml"), "date = \"2023-12-28\"\n").unwrap();
+        let output_dir = root.join("out");
+        fs::create_dir_all(&output_dir).unwrap();
+        let config = Config {
+            rustup_dist_server: url::Url::from_directory_path(root.join("mirror")).unwrap().to_string().trim_end_matches('/').to_string(),
+            ..config
+        };
+
+        let runner = fake_runner();
+        run_package(&config, None, &output_dir, &PackageOptions::default(), &runner).unwrap();
+        assert_eq!(runner.commands()[0].args[1], output_dir.join(format!("rust-1.75.0-{}.tar.gz", host)).to_string_lossy());
+        let bundle = output_dir.join(format!("rust-1.75.0-{}.tar.gz", host));
+        let (metadata, report) = metadata::verify_bundle(&bundle).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(metadata.toolchain, format!("1.75.0-{}", host));
+        assert!(metadata.source.url.ends_with(&format!("/mirror/dist/rust-1.75.0-{}.tar.gz", host)));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    pub fn test_run_package_tool_failure() {
+        let root = temp_dir("rustup-distribution-test-run-package-failure");
+        let dated = root.join("mirror/dist/2024-01-01");
+        fs::create_dir_all(&dated).unwrap();
+        fs::write(dated.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz"), "archive").unwrap();
+        let output_dir = root.join("out");
+
+        let config = Config {
+            rustup_dist_server: url::Url::from_directory_path(root.join("mirror")).unwrap().to_string().trim_end_matches('/').to_string(),
+            target_selections: vec![linux_target("nightly", Some("2024-01-01"))],
+            extra_tools: vec![grcov()],
+            ..Config::default()
+        };
+        let runner = RecordingRunner::with_handler(|command| match command.program.as_str() {
+            "cargo" => Err(CommandError::Failed {
+                command: command.to_string(),
+                exit_status: Some(101),
+                stdout: String::new(),
+                stderr: "error: failed to compile `grcov v0.8.18`".to_string(),
+            }),
+            _ => fake_runner().run(command),
+        });
+
+        let err = run_package(&config, None, &output_dir, &PackageOptions::default(), &runner).unwrap_err();
+        assert!(format!("{:?}", err).contains("failed to compile `grcov v0.8.18`"));
+        assert!(!output_dir.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    pub fn test_run_package_checksum_mismatch() {
+        let root = temp_dir("rustup-distribution-test-run-package-checksum");
+        let dated = root.join("mirror/dist/2024-01-01");
+        fs::create_dir_all(&dated).unwrap();
+        fs::write(dated.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz"), "archive").unwrap();
+        let output_dir = root.join("out");
+
+        let target_selection = linux_target("nightly", Some("2024-01-01"));
+        let config = Config {
+            rustup_dist_server: url::Url::from_directory_path(root.join("mirror")).unwrap().to_string().trim_end_matches('/').to_string(),
+            target_selections: vec![target_selection.clone()],
+            ..Config::default()
+        };
+        let lockfile = Lockfile {
+            version: crate::LOCKFILE_VERSION,
+            toolchains: vec![crate::LockedToolchain {
+                target: target_selection.target.clone(),
+                channel: target_selection.channel.clone(),
+                requested_date: target_selection.date.clone(),
+                profile: None,
+                components: Vec::new(),
+                targets: Vec::new(),
+                staging: false,
+                date: "2024-01-01".to_string(),
+                manifest_url: String::new(),
+                manifest_sha256: String::new(),
+                artifacts: vec![crate::LockedArtifact { name: "rust".to_string(), url: String::new(), sha256: "00".to_string() }],
+            }],
+            tools: Vec::new(),
+        };
+        let runner = fake_runner();
+
+        let err = run_package(&config, Some(&lockfile), &output_dir, &PackageOptions::default(), &runner).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"));
+        // nothing was extracted from the rejected archive
+        assert!(runner.commands().is_empty());
+        assert!(!output_dir.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz").exists());
+
+        fs::remove_dir_all(&root).unwrap();
     }
 }
\ No newline at end of file
@@ -0,0 +1,29 @@
+use std::env;
+
+use anyhow::{bail, Result};
+
+use crate::{find_latest_available, ChannelManifest, Config};
+
+// Print the date each target selection resolves to, searching back through dated
+// manifests for floating channels and `latest-available`.
+pub fn run_resolve(config: &Config, max_days: usize) -> Result<()> {
+    let download_dir = env::temp_dir().join(format!("{}-resolve", env!("CARGO_PKG_NAME")));
+
+    for target_selection in &config.target_selections {
+        if target_selection.date.is_none() || target_selection.is_latest_available() {
+            let date = find_latest_available(target_selection, config, &download_dir, max_days)?;
+            println!("{} -> {}-{}-{}", target_selection.toolchain_name(), target_selection.channel, date, target_selection.target);
+            continue;
+        }
+
+        let (manifest, _) =
+            ChannelManifest::fetch(target_selection, &config.rustup_dist_server, &config.network, &download_dir)?;
+        let missing = manifest.missing_components(target_selection);
+        if !missing.is_empty() {
+            bail!("{} is missing {}", target_selection.toolchain_name(), missing.join(", "));
+        }
+        println!("{} -> {}", target_selection.toolchain_name(), target_selection.toolchain_name());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,193 @@
+use std::fs;
+
+use anyhow::{bail, Result};
+use tracing::{info, info_span};
+
+use crate::install::{fresh_rustup_settings, install_missing, plan_install, record_outcome};
+use crate::{CommandSpec, Config, InstallHomes, InstallStatus, InstalledState, Lockfile, Plan, Runner};
+
+#[derive(Clone, Debug, Default)]
+pub struct SyncOptions {
+    // also remove toolchains and tools the configuration does not list
+    pub prune: bool,
+}
+
+// Make the machine match `config`: install what is missing like `install` does and, with
+// `prune`, remove what is not listed. `confirm` is shown every change before any is made.
+pub fn run_sync(config: &Config, runner: &dyn Runner, options: &SyncOptions, confirm: &dyn Fn(&Plan) -> bool) -> Result<()> {
+    let fresh_settings = fresh_rustup_settings(&config.homes);
+    let installed = InstalledState::read(runner, &config.homes, reads_toolchains(config, options))?;
+
+    let plan = plan_sync(config, None, &installed, options)?;
+    if plan.steps.is_empty() {
+        info!("Already in sync");
+        return Ok(());
+    }
+    if !confirm(&plan) {
+        bail!("sync cancelled, nothing was changed");
+    }
+
+    install_missing(config, runner, &installed, fresh_settings.as_deref())?;
+    for (item, command) in removals(config, &installed, options) {
+        let _item_span = info_span!("remove", item = %item).entered();
+        runner.run(&command)?;
+        record_outcome(item, InstallStatus::Removed, None);
+    }
+
+    Ok(())
+}
+
+// Everything `run_sync` would download, run and remove, without doing it
+pub fn plan_sync(config: &Config, lockfile: Option<&Lockfile>, installed: &InstalledState, options: &SyncOptions) -> Result<Plan> {
+    let mut plan = plan_install(config, lockfile, installed)?;
+    for (_, command) in removals(config, installed, options) {
+        plan.command(command);
+    }
+    Ok(plan)
+}
+
+// Whether sync needs the installed toolchains: to install the configured ones, or to prune
+// those it does not list
+pub fn reads_toolchains(config: &Config, options: &SyncOptions) -> bool {
+    !config.target_selections.is_empty() || options.prune
+}
+
+// Installed toolchains and tools `config` does not list, with the command removing each.
+// This tool never removes itself, nor toolchains set up with `rustup toolchain link`.
+fn removals(config: &Config, installed: &InstalledState, options: &SyncOptions) -> Vec<(String, CommandSpec)> {
+    if !options.prune {
+        return Vec::new();
+    }
+
+    let listed: Vec<String> =
+        config.target_selections.iter().map(|target_selection| target_selection.rustup_toolchain_name().to_lowercase()).collect();
+    let toolchains = installed
+        .toolchains
+        .keys()
+        .filter(|toolchain| !listed.contains(toolchain))
+        .filter(|toolchain| is_channel_toolchain(toolchain) && !is_linked(&config.homes, toolchain))
+        .map(|toolchain| {
+            let command = config.homes.apply(CommandSpec::rustup(vec!["toolchain".to_string(), "uninstall".to_string(), toolchain.clone()]));
+            (toolchain.clone(), command)
+        });
+    let tools = installed
+        .crates
+        .iter()
+        .filter(|(name, _)| name.as_str() != env!("CARGO_PKG_NAME"))
+        .filter(|(name, _)| !config.extra_tools.iter().any(|tool| &tool.name == *name))
+        .map(|(name, version)| {
+            let command = config.homes.apply(CommandSpec::cargo(vec!["uninstall".to_string(), name.clone()]));
+            (format!("{}@{}", name, version), command)
+        });
+
+    toolchains.chain(tools).collect()
+}
+
+// Whether rustup installed `toolchain` from a channel, eg: nightly-2024-01-01-x86_64-unknown-linux-gnu
+// or 1.75.0-x86_64-pc-windows-msvc, rather than it being linked under a name of its own
+fn is_channel_toolchain(toolchain: &str) -> bool {
+    let Some((channel, rest)) = toolchain.split_once('-') else {
+        return false;
+    };
+    let is_version = |channel: &str| {
+        let parts: Vec<&str> = channel.split('.').collect();
+        (2..=3).contains(&parts.len()) && parts.iter().all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+    };
+    if !["stable", "beta", "nightly"].contains(&channel) && !is_version(channel) {
+        return false;
+    }
+    // an optional date, then the host triple
+    let is_date = rest.len() > 11
+        && rest[..10].chars().enumerate().all(|(index, c)| if index == 4 || index == 7 { c == '-' } else { c.is_ascii_digit() })
+        && rest.as_bytes()[10] == b'-';
+    let host = if is_date { &rest[11..] } else { rest };
+    host.split('-').count() >= 2 && host.split('-').all(|part| !part.is_empty())
+}
+
+// Linked toolchains are symbolic links under `<RUSTUP_HOME>/toolchains`
+fn is_linked(homes: &InstallHomes, toolchain: &str) -> bool {
+    homes
+        .rustup_home()
+        .and_then(|rustup_home| fs::symlink_metadata(rustup_home.join("toolchains").join(toolchain)).ok())
+        .is_some_and(|metadata| metadata.file_type().is_symlink())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ExtendTool, TargetSelection};
+
+    fn config() -> Config {
+        Config {
+            target_selections: vec![TargetSelection {
+                target: "x86_64-unknown-linux-gnu".to_string(),
+                channel: "stable".to_string(),
+                ..Default::default()
+            }],
+            extra_tools: vec![ExtendTool { name: "grcov".to_string(), version: Some("0.8.18".to_string()) }],
+            ..Config::default()
+        }
+    }
+
+    fn installed() -> InstalledState {
+        let mut installed = InstalledState::default();
+        installed.toolchains.insert("stable-x86_64-unknown-linux-gnu".to_string(), Default::default());
+        installed.toolchains.insert("nightly-x86_64-unknown-linux-gnu".to_string(), Default::default());
+        installed.crates.insert("grcov".to_string(), "0.8.17".to_string());
+        installed.crates.insert("cargo-nextest".to_string(), "0.9.60".to_string());
+        installed.crates.insert(env!("CARGO_PKG_NAME").to_string(), "0.1.0".to_string());
+        installed
+    }
+
+    #[test]
+    fn test_plan_sync() {
+        let plan = plan_sync(&config(), None, &installed(), &SyncOptions { prune: false }).unwrap();
+        let steps: Vec<String> = plan.steps.iter().map(|step| step.to_string()).collect();
+        assert_eq!(steps, vec!["run cargo install grcov@0.8.18"]);
+
+        let plan = plan_sync(&config(), None, &installed(), &SyncOptions { prune: true }).unwrap();
+        let steps: Vec<String> = plan.steps.iter().map(|step| step.to_string()).collect();
+        assert_eq!(
+            steps,
+            vec![
+                "run cargo install grcov@0.8.18",
+                "run rustup toolchain uninstall nightly-x86_64-unknown-linux-gnu",
+                "run cargo uninstall cargo-nextest",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_removals_need_prune() {
+        assert!(removals(&config(), &installed(), &SyncOptions::default()).is_empty());
+        let removed: Vec<String> =
+            removals(&config(), &installed(), &SyncOptions { prune: true }).into_iter().map(|(item, _)| item).collect();
+        assert_eq!(removed, vec!["nightly-x86_64-unknown-linux-gnu", "cargo-nextest@0.9.60"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_removals_keep_linked_toolchains() {
+        let rustup_home = std::env::temp_dir().join("rustup-distribution-test-sync-linked");
+        let _ = fs::remove_dir_all(&rustup_home);
+        fs::create_dir_all(rustup_home.join("toolchains/nightly-x86_64-unknown-linux-gnu")).unwrap();
+        fs::create_dir_all(rustup_home.join("custom")).unwrap();
+        std::os::unix::fs::symlink(rustup_home.join("custom"), rustup_home.join("toolchains/beta-x86_64-unknown-linux-gnu")).unwrap();
+
+        let mut config = config();
+        config.homes.rustup_home = Some(rustup_home.clone());
+        let mut installed = installed();
+        installed.toolchains.insert("beta-x86_64-unknown-linux-gnu".to_string(), Default::default());
+        installed.toolchains.insert("dev".to_string(), Default::default());
+        installed.toolchains.insert("stage1-x86_64".to_string(), Default::default());
+        installed.toolchains.insert("1.75.0-2024-01-01-x86_64-pc-windows-msvc".to_string(), Default::default());
+        let removed: Vec<String> =
+            removals(&config, &installed, &SyncOptions { prune: true }).into_iter().map(|(item, _)| item).collect();
+        assert_eq!(
+            removed,
+            vec!["1.75.0-2024-01-01-x86_64-pc-windows-msvc", "nightly-x86_64-unknown-linux-gnu", "cargo-nextest@0.9.60"]
+        );
+
+        fs::remove_dir_all(&rustup_home).unwrap();
+    }
+}
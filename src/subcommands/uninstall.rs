@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use tracing::{info, info_span};
+
+use crate::install::record_outcome;
+use crate::{installer, parse_component_manifest, BundleMetadata, CommandSpec, Config, InstallStatus, InstalledState, ManifestEntry, Plan, Runner, BUNDLE_METADATA_FILENAME};
+
+// Files rust-installer keeps in the prefix to track what it installed
+const RUSTLIB_DIR: &str = "lib/rustlib";
+const INSTALLED_COMPONENTS_FILENAME: &str = "components";
+const INSTALLER_FILENAMES: [&str; 2] = ["rust-installer-version", "uninstall.sh"];
+
+// What installing a bundle put into a prefix
+#[derive(Clone, Debug, Default)]
+pub struct BundleContents {
+    // components of the bundle, in the order of its `components` file
+    pub components: Vec<String>,
+    // every `manifest.in` entry of those components, paths relative to the prefix
+    pub entries: Vec<ManifestEntry>,
+    // from the bundle metadata, `None` for bundles without one
+    pub toolchain: Option<String>,
+}
+
+// Remove the toolchains and tools `config` lists, leaving everything else in place
+pub fn run_uninstall(config: &Config, runner: &dyn Runner) -> Result<()> {
+    let installed = InstalledState::read(runner, &config.homes, !config.target_selections.is_empty())?;
+
+    let uninstalls = uninstalls(config, &installed);
+    if uninstalls.is_empty() {
+        info!("Nothing to uninstall");
+    }
+    for (item, command) in uninstalls {
+        let _item_span = info_span!("uninstall", item = %item).entered();
+        runner.run(&command)?;
+        record_outcome(item, InstallStatus::Removed, None);
+    }
+
+    Ok(())
+}
+
+// Every command `run_uninstall` would run, without running it
+pub fn plan_uninstall(config: &Config, installed: &InstalledState) -> Plan {
+    let mut plan = Plan::default();
+    for (_, command) in uninstalls(config, installed) {
+        plan.command(command);
+    }
+    plan
+}
+
+// Listed toolchains and tools that are installed, with the command removing each
+fn uninstalls(config: &Config, installed: &InstalledState) -> Vec<(String, CommandSpec)> {
+    let mut uninstalls = Vec::new();
+    for target_selection in &config.target_selections {
+        let toolchain = target_selection.rustup_toolchain_name().to_lowercase();
+        if installed.toolchains.contains_key(&toolchain) {
+            let command = config.homes.apply(CommandSpec::rustup(vec!["toolchain".to_string(), "uninstall".to_string(), toolchain.clone()]));
+            uninstalls.push((toolchain, command));
+        } else {
+            info!(toolchain = %toolchain, "Not installed");
+        }
+    }
+    for tool in &config.extra_tools {
+        match installed.crates.get(&tool.name) {
+            Some(version) => {
+                let command = config.homes.apply(CommandSpec::cargo(vec!["uninstall".to_string(), tool.name.clone()]));
+                uninstalls.push((format!("{}@{}", tool.name, version), command));
+            }
+            None => info!(tool = %tool.name, "Not installed"),
+        }
+    }
+    uninstalls
+}
+
+// Remove exactly the files `bundle` installed into `prefix`, and forget its components
+pub fn run_uninstall_bundle(bundle: &Path, prefix: &Path) -> Result<()> {
+    let contents = BundleContents::read(bundle)?;
+    let (removals, components) = bundle_removals(&contents, prefix)?;
+
+    for path in &removals {
+        let metadata = fs::symlink_metadata(path)?;
+        if metadata.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }
+        .with_context(|| format!("failed to remove {}", path.display()))?;
+    }
+    if let Some(components) = components {
+        fs::write(installed_components_path(prefix), components).context("failed to update installed components")?;
+    }
+    for path in &removals {
+        remove_empty_parents(path, prefix);
+    }
+
+    for component in contents.components {
+        record_outcome(component, InstallStatus::Removed, None);
+    }
+
+    Ok(())
+}
+
+// Every file `run_uninstall_bundle` would remove or rewrite, without touching them
+pub fn plan_uninstall_bundle(bundle: &Path, prefix: &Path) -> Result<Plan> {
+    let contents = BundleContents::read(bundle)?;
+    let (removals, components) = bundle_removals(&contents, prefix)?;
+
+    let mut plan = Plan::default();
+    for path in removals {
+        plan.remove_file(path);
+    }
+    if components.is_some() {
+        plan.write_file(installed_components_path(prefix));
+    }
+    Ok(plan)
+}
+
+impl BundleContents {
+    // The `components` file of a `.tar.gz` bundle and the `manifest.in` of each component
+    pub fn read(bundle: &Path) -> Result<Self> {
+        let file = File::open(bundle).with_context(|| format!("failed to open bundle: {}", bundle.display()))?;
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+        let mut files = BTreeMap::new();
+        for entry in archive.entries().context("bundle is truncated or corrupted")? {
+            let mut entry = entry.context("bundle is truncated or corrupted")?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?.to_string_lossy().trim_start_matches("./").to_string();
+            if path == INSTALLED_COMPONENTS_FILENAME || path == BUNDLE_METADATA_FILENAME || path.ends_with("/manifest.in") {
+                let mut content = String::new();
+                entry.read_to_string(&mut content).context("bundle is truncated or corrupted")?;
+                files.insert(path, content);
+            }
+        }
+
+        let Some(components) = files.get(INSTALLED_COMPONENTS_FILENAME) else {
+            bail!("bundle has no {} file", INSTALLED_COMPONENTS_FILENAME);
+        };
+        let mut contents = BundleContents {
+            components: components.lines().map(str::trim).filter(|line| !line.is_empty()).map(String::from).collect(),
+            entries: Vec::new(),
+            toolchain: None,
+        };
+        if let Some(metadata) = files.get(BUNDLE_METADATA_FILENAME) {
+            let metadata: BundleMetadata = serde_json::from_str(metadata).context("invalid bundle metadata")?;
+            contents.toolchain = Some(metadata.toolchain);
+        }
+        for component in &contents.components {
+            let manifest = files
+                .get(&format!("{}/manifest.in", component))
+                .with_context(|| format!("bundle has no manifest.in for component {}", component))?;
+            contents.entries.extend(parse_component_manifest(manifest)?);
+        }
+
+        Ok(contents)
+    }
+}
+
+// Paths below `prefix` to remove, and the new content of the installed components file when
+// it has to be rewritten rather than removed. Files already gone are skipped.
+fn bundle_removals(contents: &BundleContents, prefix: &Path) -> Result<(Vec<PathBuf>, Option<String>)> {
+    let rustlib = prefix.join(RUSTLIB_DIR);
+    let mut removals: Vec<PathBuf> = contents
+        .entries
+        .iter()
+        .map(|entry| match entry {
+            ManifestEntry::File(path) | ManifestEntry::Dir(path) => prefix.join(path),
+        })
+        .chain(contents.components.iter().map(|component| rustlib.join(format!("manifest-{}", component))))
+        .chain(contents.toolchain.iter().map(|toolchain| installer::uninstall_record(prefix, toolchain)))
+        .collect();
+
+    let mut components = None;
+    let components_path = installed_components_path(prefix);
+    if components_path.exists() {
+        let installed = fs::read_to_string(&components_path)
+            .with_context(|| format!("failed to read {}", components_path.display()))?;
+        let remaining: Vec<&str> = installed
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !contents.components.iter().any(|component| component == line))
+            .collect();
+        if remaining.is_empty() {
+            removals.push(components_path);
+            removals.extend(INSTALLER_FILENAMES.iter().map(|name| rustlib.join(name)));
+        } else {
+            components = Some(remaining.iter().map(|line| format!("{}\n", line)).collect());
+        }
+    }
+
+    removals.retain(|path| fs::symlink_metadata(path).is_ok());
+    Ok((removals, components))
+}
+
+fn installed_components_path(prefix: &Path) -> PathBuf {
+    prefix.join(RUSTLIB_DIR).join(INSTALLED_COMPONENTS_FILENAME)
+}
+
+// Remove the directories left empty above `path`, stopping at `prefix`
+fn remove_empty_parents(path: &Path, prefix: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == prefix || !current.starts_with(prefix) || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{temp_dir, write_files};
+    use crate::{archive, ExtendTool, TargetSelection};
+
+    fn bundle(root: &Path, grcov_manifest: &str) -> PathBuf {
+        let staged = root.join("staged");
+        write_files(
+            &staged,
+            &[
+                ("components", "rustc\ngrcov-0.8.18\n"),
+                ("rustc/manifest.in", "file:bin/rustc\ndir:share/doc/rust\n"),
+                ("rustc/bin/rustc", "binary"),
+                ("grcov-0.8.18/manifest.in", grcov_manifest),
+                ("grcov-0.8.18/bin/grcov", "binary"),
+            ],
+        );
+        let bundle = root.join("rust-nightly-x86_64-unknown-linux-gnu.tar.gz");
+        archive::write_reproducible_tar_gz(&staged, &bundle).unwrap();
+        bundle
+    }
+
+    #[test]
+    fn test_plan_uninstall() {
+        let config = Config {
+            target_selections: vec![
+                TargetSelection {
+                    target: "x86_64-unknown-linux-gnu".to_string(),
+                    channel: "stable".to_string(),
+                    ..Default::default()
+                },
+                TargetSelection {
+                    target: "x86_64-unknown-linux-gnu".to_string(),
+                    channel: "beta".to_string(),
+                    ..Default::default()
+                },
+            ],
+            extra_tools: vec![
+                ExtendTool { name: "grcov".to_string(), version: Some("0.8.18".to_string()) },
+                ExtendTool { name: "cargo-nextest".to_string(), version: None },
+            ],
+            ..Config::default()
+        };
+        let mut installed = InstalledState::default();
+        installed.toolchains.insert("stable-x86_64-unknown-linux-gnu".to_string(), Default::default());
+        installed.toolchains.insert("nightly-x86_64-unknown-linux-gnu".to_string(), Default::default());
+        installed.crates.insert("grcov".to_string(), "0.8.17".to_string());
+
+        let steps: Vec<String> = plan_uninstall(&config, &installed).steps.iter().map(|step| step.to_string()).collect();
+        assert_eq!(steps, vec!["run rustup toolchain uninstall stable-x86_64-unknown-linux-gnu", "run cargo uninstall grcov"]);
+    }
+
+    #[test]
+    fn test_uninstall_bundle() {
+        let root = temp_dir("rustup-distribution-test-uninstall-bundle");
+        let bundle = bundle(&root, "file:bin/grcov\n");
+        let prefix = root.join("prefix");
+        write_files(
+            &prefix,
+            &[
+                ("bin/rustc", "binary"),
+                ("bin/grcov", "binary"),
+                ("bin/other", "binary"),
+                ("share/doc/rust/README.md", "readme"),
+                ("lib/rustlib/components", "rustc\ngrcov-0.8.18\ncargo\n"),
+                ("lib/rustlib/manifest-rustc", "file:bin/rustc\n"),
+                ("lib/rustlib/manifest-grcov-0.8.18", "file:bin/grcov\n"),
+                ("lib/rustlib/rust-installer-version", "3"),
+            ],
+        );
+
+        let plan = plan_uninstall_bundle(&bundle, &prefix).unwrap();
+        assert_eq!(plan.steps.len(), 6);
+        assert!(prefix.join("bin/rustc").exists());
+
+        run_uninstall_bundle(&bundle, &prefix).unwrap();
+        assert!(prefix.join("bin/other").exists());
+        assert!(!prefix.join("bin/rustc").exists());
+        assert!(!prefix.join("bin/grcov").exists());
+        assert!(!prefix.join("share").exists());
+        assert!(!prefix.join("lib/rustlib/manifest-rustc").exists());
+        assert!(prefix.join("lib/rustlib/rust-installer-version").exists());
+        assert_eq!(fs::read_to_string(prefix.join("lib/rustlib/components")).unwrap(), "cargo\n");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_uninstall_bundle_outside_prefix() {
+        let root = temp_dir("rustup-distribution-test-uninstall-escape");
+        let bundle = bundle(&root, "file:../escape\n");
+        assert!(BundleContents::read(&bundle).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::metadata;
+
+pub fn run_verify(bundle: &Path) -> Result<()> {
+    let (metadata, report) = metadata::verify_bundle(bundle)?;
+
+    println!("Bundle '{}' produced by {}", metadata.toolchain, metadata.generator);
+    println!("Source: {} (sha256 {})", metadata.source.url, metadata.source.sha256);
+    for (component, source) in &metadata.component_sources {
+        println!("Component: {} from {} (sha256 {})", component, source.url, source.sha256);
+    }
+    for tool in &metadata.tools {
+        println!("Tool: {} {}", tool.name, tool.version.as_deref().unwrap_or("latest"));
+    }
+
+    for path in &report.tampered {
+        eprintln!("Tampered file: {}", path);
+    }
+    for path in &report.missing {
+        eprintln!("Missing file: {}", path);
+    }
+    for path in &report.unexpected {
+        eprintln!("Unexpected file: {}", path);
+    }
+
+    if !report.is_ok() {
+        bail!(
+            "bundle verification failed: {} tampered, {} missing, {} unexpected",
+            report.tampered.len(),
+            report.missing.len(),
+            report.unexpected.len()
+        );
+    }
+
+    println!("Verified {} files", report.verified);
+    Ok(())
+}
@@ -0,0 +1,45 @@
+// Fixtures shared by the unit tests of the modules that stage, bundle and package toolchains
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{BundleConfig, BundleMetadata, ExtendTool, SourceArchive, TargetSelection};
+
+// An empty directory under the system temp dir, whatever a previous run left there removed
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Write each `(relative path, content)` below `root`, creating the directories on the way
+pub fn write_files(root: &Path, files: &[(&str, &str)]) {
+    for (path, content) in files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+}
+
+// The x86_64-unknown-linux-gnu toolchain of `channel`, on `date` if any
+pub fn linux_target(channel: &str, date: Option<&str>) -> TargetSelection {
+    TargetSelection {
+        target: "x86_64-unknown-linux-gnu".to_string(),
+        channel: channel.to_string(),
+        date: date.map(str::to_string),
+        ..Default::default()
+    }
+}
+
+// Write the metadata of the bundle staged in `dir`, as if it had been downloaded from the
+// official servers, `tools` being each extra tool with the directory it was installed in
+pub fn bundle_metadata(dir: &Path, target: TargetSelection, tools: &[(ExtendTool, String)]) -> BundleMetadata {
+    let config = BundleConfig {
+        rustup_dist_server: "https://static.rust-lang.org".to_string(),
+        rustup_update_root: "https://static.rust-lang.org/rustup".to_string(),
+        target: target.clone(),
+        extra_tools: tools.iter().map(|(tool, _)| tool.clone()).collect(),
+    };
+    let source = SourceArchive { url: "https://static.rust-lang.org/dist".to_string(), sha256: "00".to_string() };
+    BundleMetadata::new(target.toolchain_name(), source, config).write_to(dir, tools, None).unwrap()
+}